@@ -6,6 +6,9 @@ use tokio::time::sleep;
 
 #[tokio::main]
 async fn main() -> Result<(), Error> {
+    // RUST_LOG=rust_network=trace dumps every packet
+    tracing_subscriber::fmt::init();
+
     let (incoming_tx, mut incoming_rx) = mpsc::unbounded();
     let connections = Connections::new(incoming_tx);
 
@@ -21,15 +24,15 @@ async fn main() -> Result<(), Error> {
     let on_receive = async {
         while let Some(packet) = incoming_rx.next().await {
             match packet {
-                (c, Packet::PingRequest(pkg)) => {
+                (c, Packet::PingRequest(pkg), _) => {
                     if let Ok(res) = c.send_for_response(Packet::PingRequest(pkg)).await {
                         println!("rcv pkg conn  {:#?} {:#?}", c.client_id.read().await, res);
                     }
                 }
-                (_, Packet::PongResponse(pkg)) => {
+                (_, Packet::PongResponse(pkg), _) => {
                     println!("rcv pkg  {:#?}", pkg)
                 }
-                (c, Packet::ChatMessage(pkg)) => {
+                (c, Packet::ChatMessage(pkg), duplicate) => {
                     println!(
                         "收到到服务端端消息 to {} from {} content {}",
                         &pkg.to_user,
@@ -41,7 +44,8 @@ async fn main() -> Result<(), Error> {
                         }
                     );
 
-                    c.spawn_lua(Packet::ChatMessage(pkg.clone())).unwrap();
+                    c.spawn_lua(Packet::ChatMessage(pkg.clone()), duplicate)
+                        .unwrap();
                 }
             }
         }
@@ -62,7 +66,7 @@ async fn main() -> Result<(), Error> {
                 msg_id: 0,
                 to_user: "123".to_string(),
                 from_user: "789".to_string(),
-                content: Connections::gen_random_string(16).as_bytes().to_vec(),
+                content: Connections::gen_random_string(16).into(),
             };
 
             for (_id, c) in connections.clients.read().await.iter() {
@@ -74,7 +78,10 @@ async fn main() -> Result<(), Error> {
                     break;
                 }
                 //println!("res {:#?}", res.unwrap())
-                let pkg = pkg.unwrap();
+                let pkg = match pkg.unwrap() {
+                    Packet::ChatMessage(pkg) => pkg,
+                    _ => continue,
+                };
                 println!(
                     "同步收到服务端端消息  当前客户端ID {}to {} from {} content {}",
                     &c.client_id.read().await,
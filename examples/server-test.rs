@@ -4,6 +4,9 @@ use rust_network::stats::Stats;
 
 #[tokio::main]
 async fn main() -> Result<(), Error> {
+    // RUST_LOG=rust_network=trace dumps every packet
+    tracing_subscriber::fmt::init();
+
     let server = Server::new();
     let stats = Stats::new();
     tcp_run(&server, "0.0.0.0:8080".parse().unwrap(), stats, 100)
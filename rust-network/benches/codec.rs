@@ -0,0 +1,100 @@
+/*! Benchmarks of `Codec` against the copying path it replaced
+
+Before payloads were sliced out of the read buffer every decode dumped the
+whole buffer with `hex::encode`, copied the frame and copied `content` again.
+Every encode serialized the packet into a temporary `Vec` first.
+*/
+
+use bytes::{Buf, BufMut, BytesMut};
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion};
+use rust_network::{
+    chatmsg::ChatMessage,
+    codec::{Codec, FRAME_HEADER_SIZE},
+    stats::Stats,
+    FromBytes, Packet, ToBytes,
+};
+use tokio_util::codec::{Decoder, Encoder};
+
+const CONTENT_SIZES: &[usize] = &[64, 4 * 1024, 64 * 1024];
+
+fn chat_message(content_size: usize) -> Packet {
+    Packet::ChatMessage(ChatMessage {
+        msg_id: 42,
+        to_user: "123".to_string(),
+        from_user: "789".to_string(),
+        content: vec![0x2a; content_size].into(),
+    })
+}
+
+fn copying_decode(buf: &mut BytesMut) -> Packet {
+    black_box(hex::encode(&buf));
+    let mut header = [0; FRAME_HEADER_SIZE];
+    header.copy_from_slice(&buf[..FRAME_HEADER_SIZE]);
+    buf.advance(FRAME_HEADER_SIZE);
+    let frame = buf.split_to(u32::from_be_bytes(header) as usize).to_vec();
+    Packet::from_bytes(&frame).unwrap().1
+}
+
+fn copying_encode(packet: &Packet, buf: &mut BytesMut) {
+    let mut tmp = BytesMut::new();
+    packet.to_bytes(&mut tmp).unwrap();
+    let bufs = tmp.to_vec();
+    black_box(hex::encode(&bufs));
+    buf.reserve(FRAME_HEADER_SIZE + bufs.len());
+    buf.put_u32(bufs.len() as u32);
+    buf.extend_from_slice(&bufs);
+}
+
+fn decode(c: &mut Criterion) {
+    let mut group = c.benchmark_group("decode");
+    for &size in CONTENT_SIZES {
+        let mut encoded = BytesMut::new();
+        Codec::<Packet>::new(Stats::new())
+            .encode(chat_message(size), &mut encoded)
+            .unwrap();
+
+        group.bench_with_input(BenchmarkId::new("copying", size), &encoded, |b, encoded| {
+            b.iter_batched_ref(|| encoded.clone(), copying_decode, BatchSize::SmallInput)
+        });
+        group.bench_with_input(
+            BenchmarkId::new("zero_copy", size),
+            &encoded,
+            |b, encoded| {
+                let mut codec = Codec::<Packet>::new(Stats::new());
+                b.iter_batched_ref(
+                    || encoded.clone(),
+                    |buf| codec.decode(buf).unwrap(),
+                    BatchSize::SmallInput,
+                )
+            },
+        );
+    }
+    group.finish();
+}
+
+fn encode(c: &mut Criterion) {
+    let mut group = c.benchmark_group("encode");
+    for &size in CONTENT_SIZES {
+        let packet = chat_message(size);
+
+        group.bench_with_input(BenchmarkId::new("copying", size), &packet, |b, packet| {
+            let mut buf = BytesMut::new();
+            b.iter(|| {
+                copying_encode(packet, &mut buf);
+                buf.clear();
+            })
+        });
+        group.bench_with_input(BenchmarkId::new("zero_copy", size), &packet, |b, packet| {
+            let mut codec = Codec::<Packet>::new(Stats::new());
+            let mut buf = BytesMut::new();
+            b.iter(|| {
+                codec.encode(packet.clone(), &mut buf).unwrap();
+                buf.clear();
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, decode, encode);
+criterion_main!(benches);
@@ -2,67 +2,88 @@
 */
 
 use crate::errors::PacketError;
-use bytes::BufMut;
+use bytes::{BufMut, Bytes, BytesMut};
 use mlua::{MetaMethod, ToLua, UserData, UserDataMethods};
-use nom::{combinator::rest, do_parse, map_res, named, number::streaming::be_u64, tag, take};
+use nom::{combinator::rest, do_parse, map_res, named, number::streaming::be_u64, tag, take, IResult};
+use tracing::debug;
 
-use crate::{FromBytes, ToBytes};
-/** Sent by both client and server, both will respond.
-Ping packets are used to know if the other side of the connection is still
-live. TCP when established doesn't have any sane timeouts (1 week isn't sane)
-so we are obliged to have our own way to check if the other side is still live.
-Ping ids can be anything except 0, this is because of how toxcore sets the
-variable storing the `ping_id` that was sent to 0 when it receives a pong
-response which means 0 is invalid.
-The server should send ping packets every X seconds (toxcore `TCP_server` sends
-them every 30 seconds and times out the peer if it doesn't get a response in 10).
-The server should respond immediately to ping packets with pong packets.
+use crate::{packet_kind, FromBytes, ToBytes};
+/** Sent by both client and server.
+Chat message from one user to another. Responses to a message carry the same
+`msg_id` so that it can be used to match a request with its response.
 Serialized form:
-Length | Content
------- | ------
-`1`    | `0x04`
-`8`    | ping_id in BigEndian
+Length   | Content
+-------- | ------
+`1`      | `0x04`
+`8`      | msg_id in BigEndian
+`8`      | Length of `to_user` in BigEndian
+variable | to_user
+`8`      | Length of `from_user` in BigEndian
+variable | from_user
+variable | content
 */
 #[derive(Debug, PartialEq, Clone)]
 pub struct ChatMessage {
     pub msg_id: u64,
     pub to_user: String,
     pub from_user: String,
-    pub content: Vec<u8>,
+    /// Shares memory with the received frame when decoded by `Codec`
+    pub content: Bytes,
 }
 
+named!(
+    parse<(u64, &str, &str, &[u8])>,
+    do_parse!(
+        tag!(&[packet_kind::CHAT_MESSAGE][..])
+            >> msg_id: be_u64
+            >> len: be_u64
+            >> to_user: map_res!(take!(len as usize), std::str::from_utf8)
+            >> len: be_u64
+            >> from_user: map_res!(take!(len as usize), std::str::from_utf8)
+            >> content: rest
+            >> ((msg_id, to_user, from_user, content))
+    )
+);
+
 impl FromBytes for ChatMessage {
-    named!(
-        from_bytes<ChatMessage>,
-        do_parse!(
-            tag!("\x04")
-                >> msg_id: be_u64
-                >> len: be_u64
-                >> to_user: map_res!(take!(len as usize), std::str::from_utf8)
-                >> len: be_u64
-                >> from_user: map_res!(take!(len as usize), std::str::from_utf8)
-                >> content: rest
-                >> (ChatMessage {
-                    msg_id,
-                    to_user: to_user.to_string(),
-                    from_user: from_user.to_string(),
-                    content: content.to_vec()
-                })
-        )
-    );
+    fn from_bytes(i: &[u8]) -> IResult<&[u8], ChatMessage> {
+        let (i, (msg_id, to_user, from_user, content)) = parse(i)?;
+        Ok((
+            i,
+            ChatMessage {
+                msg_id,
+                to_user: to_user.to_string(),
+                from_user: from_user.to_string(),
+                content: Bytes::copy_from_slice(content),
+            },
+        ))
+    }
+
+    fn from_frame(frame: &Bytes) -> IResult<&[u8], ChatMessage> {
+        let (i, (msg_id, to_user, from_user, content)) = parse(frame)?;
+        Ok((
+            i,
+            ChatMessage {
+                msg_id,
+                to_user: to_user.to_string(),
+                from_user: from_user.to_string(),
+                content: frame.slice_ref(content),
+            },
+        ))
+    }
 }
 
 impl ToBytes for ChatMessage {
-    fn to_bytes(&self) -> Result<Vec<u8>, PacketError> {
-        let mut buf = Vec::<u8>::new();
-        buf.put_u8(0x4);
+    fn to_bytes(&self, buf: &mut BytesMut) -> Result<(), PacketError> {
+        buf.reserve(25 + self.to_user.len() + self.from_user.len() + self.content.len());
+        buf.put_u8(packet_kind::CHAT_MESSAGE);
         buf.put_u64(self.msg_id);
-        buf.put_u64(self.to_user.as_bytes().len() as u64);
+        buf.put_u64(self.to_user.len() as u64);
         buf.extend_from_slice(self.to_user.as_bytes());
-        buf.put_u64(self.from_user.as_bytes().len() as u64);
+        buf.put_u64(self.from_user.len() as u64);
         buf.extend_from_slice(self.from_user.as_bytes());
         buf.extend_from_slice(&self.content);
-        Ok(buf.to_vec())
+        Ok(())
     }
 }
 
@@ -74,7 +95,7 @@ impl UserData for ChatMessage {
                 "from_user" => this.from_user.as_str().to_lua(ctx).ok(),
                 "msg_id" => this.msg_id.to_lua(ctx).ok(),
                 _ => {
-                    println!("arg {}", arg);
+                    debug!(%arg, "unknown ChatMessage field");
                     None
                 }
             };
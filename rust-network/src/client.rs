@@ -1,51 +1,114 @@
 use crate::codec;
+use crate::codec::MAX_FRAME_SIZE;
+use crate::config::ClientConfig;
+use crate::dedup::{DedupWindow, DuplicateAction};
+use crate::endpoint::{Endpoint, Peer, Rotation, Target};
 use crate::errors::*;
-use crate::Packet;
-use crate::{chatmsg::ChatMessage, stats};
+use crate::handshake::{self, Capabilities, Negotiated};
+use crate::hello::{CAPABILITY_COMPRESSION, CAPABILITY_ENCRYPTION};
+use crate::journal::Journal;
+use crate::outbox::{Outbox, Push};
+use crate::secure::{self, Session};
+use crate::state::{self, ClientState, ErrorCause, StateEvent};
+use crate::stats;
+use crate::transport::{BoxedStream, RttSender, Transport};
+use crate::{Packet, Protocol};
 use codec::Codec;
 use failure::Fail;
 use futures::channel::mpsc;
 use futures::{FutureExt, SinkExt, StreamExt, TryFutureExt};
-use mlua::{Function, Lua, MetaMethod, Table, ToLua, UserData, UserDataMethods};
+use mlua::{Function, Lua, MetaMethod, Table, ToLua, UserData, UserDataMethods, Value};
 use stats::Stats;
+use std::io::{Error as IoError, ErrorKind};
 use std::{
-    collections::HashMap,
+    collections::{hash_map, HashMap},
     sync::atomic::{AtomicUsize, Ordering},
     time::Duration,
 };
-use std::{fs::File, io::Read, net::SocketAddr, sync::Arc, time::Instant};
+use std::{net::SocketAddr, sync::Arc, time::Instant};
 use tokio::{
-    net::TcpStream,
-    sync::{oneshot, Mutex, RwLock},
+    io::{AsyncRead, AsyncWrite},
+    sync::{broadcast, oneshot, Mutex, RwLock},
 };
 use tokio_util::codec::Framed;
+use tracing::{debug, info, info_span, trace, warn, Instrument};
 
-type ResponseMap = HashMap<usize, oneshot::Sender<Packet>>;
+/// Request sent by `send_for_response` waiting for a response.
+#[derive(Debug)]
+struct PendingRequest<P> {
+    request: P,
+    done: oneshot::Sender<P>,
+}
+
+type ResponseMap<P> = HashMap<u64, PendingRequest<P>>;
 /// Client connection to a TCP relay.
 #[derive(Clone, Debug)]
-pub struct Client {
-    /// IP address of the TCP relay.
-    pub addr: SocketAddr,
+pub struct Client<P = Packet> {
+    /// Endpoints of the TCP relay.
+    target: Target,
+    /// Endpoint of the target used by the next connect attempt.
+    rotation: Arc<Mutex<Rotation>>,
+    /// Address of the relay the client is connected to.
+    endpoint: Arc<RwLock<Option<Peer>>>,
     ///  client_id
     pub client_id: Arc<RwLock<String>>,
     /// Sink for packets that should be handled somewhere else.
     /// belongs to TCP relay.
-    incoming_tx: mpsc::UnboundedSender<(Client, Packet)>,
+    incoming_tx: mpsc::UnboundedSender<(Client<P>, P, bool)>,
     /// Status of the relay.
-    status: Arc<RwLock<ClientStatus>>,
+    status: Arc<RwLock<ClientStatus<P>>>,
     /// Time when a connection to the relay was established.
     connected_time: Arc<RwLock<Option<Instant>>>,
     /// Number of unsuccessful attempts to establish connection to the relay.
     /// This is used to decide what to do after the connection terminates.
     connection_attempts: Arc<RwLock<u32>>,
 
-    pending: Arc<Mutex<ResponseMap>>,
+    pending: Arc<Mutex<ResponseMap<P>>>,
 
     seq: Arc<AtomicUsize>,
+    /// Settings of the connection. Compression is used only if the relay
+    /// supports it too.
+    config: ClientConfig,
+    /// Counters of this client kept across reconnects
+    stats: Stats,
+    /// Round-trip time measured by the last answered ping.
+    rtt: Arc<RwLock<Option<Duration>>>,
+    /// Time of the next reconnect attempt after a failed one.
+    next_attempt: Arc<RwLock<Option<Instant>>>,
+    /// Handle of the running connection used to close it.
+    shutdown: Arc<Mutex<Option<Shutdown>>>,
+    /// Changes of the connection state.
+    events: broadcast::Sender<StateEvent>,
+    /// Changes of the connection state shared by several clients.
+    parent_events: Option<broadcast::Sender<StateEvent>>,
+    /// Packets sent while the client is disconnected. `send_packet` fails
+    /// instead of queuing when `None`.
+    outbox: Option<Arc<Mutex<Outbox<P>>>>,
+    /// Packets waiting for an acknowledgement persisted across restarts.
+    journal: Option<Arc<Mutex<Journal<P>>>>,
+    /// Keys of packets received recently. Repeats are delivered as usual
+    /// when `None`.
+    dedup: Option<Arc<Mutex<DedupWindow>>>,
+}
+
+/// Handle of a running connection.
+#[derive(Debug)]
+struct Shutdown {
+    /// Asks the connection to send queued packets and close the socket.
+    request: futures::channel::oneshot::Sender<()>,
+    /// Completes when the connection task has finished.
+    done: futures::channel::oneshot::Receiver<()>,
+}
+
+/// Ping sent to the relay that wasn't answered yet.
+#[derive(Debug, Clone, Copy)]
+struct PendingPing {
+    ping_id: u64,
+    sent_time: Instant,
 }
 /// TCP relay connection status.
 #[derive(Debug, Clone)]
-enum ClientStatus {
+enum ClientStatus<P> {
     /// In this status we are not connected to the relay. This is initial
     /// status. Also we can end up in this status if connection was lost due to
     /// errors.
@@ -57,23 +120,52 @@ enum ClientStatus {
     /// when the inner sender is dropped the connection to the relay will be
     /// closed. Also this means that the sender object should not be copied
     /// anywhere else unless you want to keep the connection.
-    Connected(mpsc::Sender<Packet>),
+    Connected(mpsc::Sender<P>),
     /// This status means that we are not connected to the relay but can
     /// reconnect later. Connection becomes sleeping when all friends that might
     /// use it are connected directly via UDP.
     Sleeping,
 }
 
-impl Client {
+impl<P: Protocol> Client<P> {
     /// Create new `Client` object.
     pub fn new(
         addr: SocketAddr,
         client_id: Arc<RwLock<String>>,
-        incoming_tx: mpsc::UnboundedSender<(Client, Packet)>,
+        incoming_tx: mpsc::UnboundedSender<(Client<P>, P, bool)>,
         //conn_mgr: Arc<Mutex<Option<Connections>>>,
-    ) -> Client {
+    ) -> Client<P> {
+        Client::from_config(addr, client_id, incoming_tx, ClientConfig::default())
+    }
+
+    /// Create new `Client` object with the given settings.
+    pub fn from_config(
+        addr: SocketAddr,
+        client_id: Arc<RwLock<String>>,
+        incoming_tx: mpsc::UnboundedSender<(Client<P>, P, bool)>,
+        config: ClientConfig,
+    ) -> Client<P> {
+        Client::from_target(Target::new(addr), client_id, incoming_tx, config)
+    }
+
+    /// Create new `Client` object connecting to one of the endpoints of the
+    /// given target.
+    pub fn from_target(
+        target: Target,
+        client_id: Arc<RwLock<String>>,
+        incoming_tx: mpsc::UnboundedSender<(Client<P>, P, bool)>,
+        config: ClientConfig,
+    ) -> Client<P> {
+        let outbox = config
+            .outbox
+            .map(|outbox| Arc::new(Mutex::new(Outbox::new(outbox))));
+        let dedup = config
+            .dedup
+            .map(|dedup| Arc::new(Mutex::new(DedupWindow::new(dedup))));
         Client {
-            addr,
+            rotation: Arc::new(Mutex::new(Rotation::new(&target))),
+            target,
+            endpoint: Arc::new(RwLock::new(None)),
             client_id,
             incoming_tx,
             status: Arc::new(RwLock::new(ClientStatus::Disconnected)),
@@ -83,41 +175,181 @@ impl Client {
             //conn_mgr: Arc::new(Mutex::new(None)),
             pending: Arc::new(Mutex::new(HashMap::new())),
             seq: Arc::new(AtomicUsize::new(1)),
+            config,
+            stats: Stats::new(),
+            rtt: Arc::new(RwLock::new(None)),
+            next_attempt: Arc::new(RwLock::new(None)),
+            shutdown: Arc::new(Mutex::new(None)),
+            events: state::channel(),
+            parent_events: None,
+            outbox,
+            journal: None,
+            dedup,
         }
     }
-    /// Handle packet received from TCP relay.
-    pub async fn handle_packet(&self, packet: Packet) -> Result<(), HandlePacketError> {
-        // match packet {
-        //     Packet::PingRequest(packet) => self.handle_response(Packet::PingRequest(packet)).await,
-        //     Packet::PongResponse(packet) => self.handle_response(Packet::PongResponse(packet)).await,
-        //     Packet::ChatMessage(packet) => self.handle_response(Packet::ChatMessage(packet)).await,
-        // }
 
-        let mut msg_seq = 0;
-        match packet {
-            Packet::PingRequest(ref packet) => msg_seq = packet.ping_id,
-            Packet::PongResponse(ref packet) => msg_seq = packet.ping_id,
-            Packet::ChatMessage(ref packet) => msg_seq = packet.msg_id,
+    /// Count packets of this client with the given stats, usually a child of
+    /// stats shared by several clients
+    pub fn with_stats(mut self, stats: Stats) -> Client<P> {
+        self.stats = stats;
+        self
+    }
+
+    /// Report changes of the connection state to the given sender too,
+    /// usually shared by several clients
+    pub fn with_events(mut self, events: broadcast::Sender<StateEvent>) -> Client<P> {
+        self.parent_events = Some(events);
+        self
+    }
+
+    /// Record packets that need an acknowledgement in the given journal and
+    /// send them again on every connect until they are acknowledged.
+    pub fn with_journal(mut self, journal: Journal<P>) -> Client<P> {
+        self.journal = Some(Arc::new(Mutex::new(journal)));
+        self
+    }
+
+    /// Journal of this client to inspect and purge packets waiting for an
+    /// acknowledgement.
+    pub fn journal(&self) -> Option<&Arc<Mutex<Journal<P>>>> {
+        self.journal.as_ref()
+    }
+
+    /// Settings of this client
+    pub fn config(&self) -> &ClientConfig {
+        &self.config
+    }
+
+    /// Subscribe to changes of the connection state made after this call
+    pub fn subscribe(&self) -> broadcast::Receiver<StateEvent> {
+        self.events.subscribe()
+    }
+
+    /// Report a change of the connection state to subscribers.
+    async fn emit(&self, state: ClientState) {
+        let event = StateEvent {
+            client_id: self.client_id.read().await.clone(),
+            state,
+            at: Instant::now(),
+        };
+        debug!(state = ?event.state, "Connection state changed");
+        if let Some(ref parent_events) = self.parent_events {
+            // there may be no subscribers
+            let _ = parent_events.send(event.clone());
         }
+        let _ = self.events.send(event);
+    }
+
+    /// Counters of this client
+    pub fn stats(&self) -> &Stats {
+        &self.stats
+    }
+
+    /// Number of requests sent by `send_for_response` waiting for a response.
+    pub async fn pending_requests(&self) -> usize {
+        self.pending.lock().await.len()
+    }
 
-        let mut _pending = self.pending.lock().await;
-        if let Some(done_sender) = _pending.remove(&(msg_seq as usize)) {
-            if done_sender.send(packet).is_ok() {}
-            return Ok(());
+    /// Number of packets waiting in the outbox for a connection.
+    pub async fn queued_packets(&self) -> usize {
+        match self.outbox {
+            Some(ref outbox) => outbox.lock().await.len(),
+            None => 0,
+        }
+    }
+    /// Handle packet received from TCP relay. Packets that aren't responses
+    /// go to the incoming channel together with a flag telling if the dedup
+    /// window saw them before.
+    pub async fn handle_packet(&self, packet: P) -> Result<(), HandlePacketError> {
+        if let (Some(journal), Some(id)) = (&self.journal, packet.correlation_id()) {
+            let acked = journal
+                .lock()
+                .await
+                .get(id)
+                .is_some_and(|entry| packet.is_response_to(&entry.packet));
+            if acked {
+                if let Err(error) = write_journal(journal, move |journal| journal.remove(id)).await
+                {
+                    warn!(id, %error, "Failed to acknowledge journaled packet");
+                }
+            }
+        }
+        // decided on arrival so that a later repeat doesn't flag this one
+        let mut duplicate = false;
+        if let (Some(dedup), Some((sender, id))) = (&self.dedup, packet.dedup_key()) {
+            match dedup.lock().await.check(sender, id, Instant::now()) {
+                Some(DuplicateAction::Drop) => {
+                    self.stats.counters.increase_duplicates();
+                    debug!(sender, id, "Dropping repeated packet");
+                    return Ok(());
+                }
+                Some(DuplicateAction::Flag) => {
+                    self.stats.counters.increase_duplicates();
+                    debug!(sender, id, "Received repeated packet");
+                    duplicate = true;
+                }
+                None => {}
+            }
         }
+        let packet = match self.answer_request(packet).await {
+            Some(packet) => packet,
+            None => return Ok(()),
+        };
 
         let mut tx = self.incoming_tx.clone();
-        tx.send((self.clone(), packet))
+        tx.send((self.clone(), packet, duplicate))
             .await
             .map_err(|e| e.context(HandlePacketErrorKind::SendTo).into())
     }
+
+    /// Pass the packet to the pending request it answers. Returns the packet
+    /// back if there is no such request.
+    async fn answer_request(&self, packet: P) -> Option<P> {
+        if let Some(msg_seq) = packet.correlation_id() {
+            let mut pending = self.pending.lock().await;
+            if let hash_map::Entry::Occupied(entry) = pending.entry(msg_seq) {
+                if packet.is_response_to(&entry.get().request) {
+                    let _ = entry.remove().done.send(packet);
+                    return None;
+                }
+            }
+        }
+        Some(packet)
+    }
     /// 发送数据包
-    pub async fn send_packet(&self, packet: Packet) -> Result<(), SendPacketError> {
-        if let ClientStatus::Connected(ref tx) = *self.status.read().await {
+    pub async fn send_packet(&self, packet: P) -> Result<(), SendPacketError> {
+        // a packet journaled before a connect is sent by it, one journaled
+        // after it finds the client connected
+        let journaled = match self.journal {
+            Some(ref journal) if packet.needs_ack() => {
+                let record = packet.clone();
+                write_journal(journal, move |journal| journal.append(&record))
+                    .await
+                    .map_err(|e| e.context(SendPacketErrorKind::Journal))?;
+                true
+            }
+            _ => false,
+        };
+        let status = self.status.read().await;
+        if let ClientStatus::Connected(ref tx) = *status {
             let mut tx = tx.clone();
+            drop(status);
+            // a journaled packet stays in the journal and is sent again on
+            // the next connect
             tx.send(packet)
                 .await
                 .map_err(|e| e.context(SendPacketErrorKind::SendTo).into())
+        } else if journaled {
+            Ok(())
+        } else if let (Some(outbox), ClientStatus::Disconnected | ClientStatus::Connecting) =
+            (&self.outbox, &*status)
+        {
+            // the status lock is held so that the packet can't be queued
+            // after the outbox was flushed on connect
+            match outbox.lock().await.push(packet, Instant::now()) {
+                Push::Queued | Push::Dropped => Ok(()),
+                Push::Full => Err(SendPacketErrorKind::OutboxFull.into()),
+            }
         } else {
             // Attempt to send packet to TCP relay with wrong status. For
             // instance it can happen when we received ping request from the
@@ -127,86 +359,201 @@ impl Client {
         }
     }
     ///异步发送请求  同步返回
-    pub async fn send_for_response(&self, packet: Packet) -> Result<ChatMessage, SendPacketError> {
-        if let ClientStatus::Connected(ref tx) = *self.status.read().await {
-            if let Packet::ChatMessage(mut r) = packet {
-                let send_id = self.seq.fetch_add(1, Ordering::Relaxed);
-
-                r.msg_id = send_id as u64;
-                let mut tx = tx.clone();
-                tx.send(Packet::ChatMessage(r))
-                    .await
-                    .map_err(|e| e.context(SendPacketErrorKind::NotLinked))?;
+    pub async fn send_for_response(&self, packet: P) -> Result<P, SendPacketError> {
+        self.send_for_response_with_timeout(packet, self.config.request_timeout)
+            .await
+    }
 
-                let (done_sender, done) = oneshot::channel::<Packet>();
+    /// Send a request with a fresh correlation id and wait for the packet
+    /// answering it. Fails with `TimeOut` if there is no response within
+    /// `timeout` and with `Disconnected` if the connection is closed first.
+    pub async fn send_for_response_with_timeout(
+        &self,
+        mut packet: P,
+        timeout: Duration,
+    ) -> Result<P, SendPacketError> {
+        let mut tx = match self.sender().await {
+            Some(tx) => tx,
+            // Attempt to send packet to TCP relay with wrong status. For
+            // instance it can happen when we received ping request from the
+            // relay and right after that relay became sleeping so we are not
+            // able to respond anymore.
+            None => return Err(SendPacketErrorKind::WrongStatus.into()),
+        };
 
-                // insert sender to pending map
-                {
-                    let mut _pending = self.pending.lock().await;
-                    _pending.insert(send_id, done_sender);
-                } //离开作用域会自动释放锁
-
-                match tokio::time::timeout(Duration::from_millis(5 * 1000), done).await {
-                    Ok(resp) => match resp {
-                        Ok(res) => {
-                            if let Packet::ChatMessage(packet) = res {
-                                return Ok(packet);
-                            }
-                        }
-                        Err(__) => {}
-                    },
-                    Err(_) => {
-                        return Err(SendPacketErrorKind::TimeOut.into());
-                    }
-                }
+        let send_id = self.seq.fetch_add(1, Ordering::Relaxed) as u64;
+        packet.set_correlation_id(send_id);
+        if packet.correlation_id() != Some(send_id) {
+            return Err(SendPacketErrorKind::NotCorrelated.into());
+        }
+
+        // registered before sending so that a fast response isn't missed
+        let (done_sender, done) = oneshot::channel::<P>();
+        self.pending.lock().await.insert(
+            send_id,
+            PendingRequest {
+                request: packet.clone(),
+                done: done_sender,
+            },
+        );
+
+        let sent_time = Instant::now();
+        if let Err(e) = tx.send(packet).await {
+            self.pending.lock().await.remove(&send_id);
+            return Err(e.context(SendPacketErrorKind::SendTo).into());
+        }
+
+        match tokio::time::timeout(timeout, done).await {
+            Ok(Ok(packet)) => {
+                self.stats.counters.record_rtt(sent_time.elapsed());
+                Ok(packet)
+            }
+            // the pending map is cleared when the connection is closed
+            Ok(Err(_)) => Err(SendPacketErrorKind::Disconnected.into()),
+            Err(_) => {
+                self.pending.lock().await.remove(&send_id);
+                Err(SendPacketErrorKind::TimeOut.into())
             }
         }
-        // Attempt to send packet to TCP relay with wrong status. For
-        // instance it can happen when we received ping request from the
-        // relay and right after that relay became sleeping so we are not
-        // able to respond anymore.
-        Err(SendPacketErrorKind::WrongStatus.into())
+    }
+
+    /// Sender of packets to the relay if it's connected. The status lock is
+    /// released before sending so that `disconnect` doesn't wait for it.
+    async fn sender(&self) -> Option<mpsc::Sender<P>> {
+        match *self.status.read().await {
+            ClientStatus::Connected(ref tx) => Some(tx.clone()),
+            _ => None,
+        }
     }
 
     /// Spawn a connection to this TCP relay if it is not connected already. The
     /// connection is spawned via `tokio::spawn` so the result future will be
     /// completed after first poll.
-    async fn spawn_inner(&mut self) -> Result<(), SpawnError> {
-        // TODO: send pings periodically
-        match *self.status.write().await {
-            ref mut status @ ClientStatus::Disconnected
-            | ref mut status @ ClientStatus::Sleeping => *status = ClientStatus::Connecting,
-            _ => return Ok(()),
-        }
-        //println!("socket addr {:#?}", &self.addr);
-        let socket = TcpStream::connect(&self.addr)
-            .await
-            .map_err(|e| e.context(SpawnErrorKind::Io))?;
+    async fn spawn_inner(
+        &mut self,
+        done: futures::channel::oneshot::Receiver<()>,
+    ) -> Result<(), SpawnError> {
+        let (request, mut shutdown) = futures::channel::oneshot::channel();
+        *self.shutdown.lock().await = Some(Shutdown { request, done });
+
+        let (rtt_tx, rtt_rx) = mpsc::unbounded();
+        let connecting = async {
+            let (mut socket, peer) = self.connect(rtt_tx).await?;
+            let (negotiated, session) = self.handshake(&mut socket).await?;
+            Result::<_, SpawnError>::Ok((socket, peer, negotiated, session))
+        };
+        let (socket, peer, negotiated, session) = futures::select! {
+            res = connecting.fuse() => res?,
+            _ = shutdown => return Ok(()),
+        };
 
-        let stats = Stats::new();
-        let secure_socket = Framed::new(socket, Codec::new(stats));
+        let codec = Codec::<P>::new(self.stats.clone())
+            .with_session(session)
+            .with_max_frame_size(negotiated.max_frame_size)
+            .with_compression(
+                self.config
+                    .compression
+                    .filter(|_| negotiated.has(CAPABILITY_COMPRESSION)),
+            );
+        let secure_socket = Framed::new(socket, codec);
         let (mut to_server, mut from_server) = secure_socket.split();
-        let (to_server_tx, to_server_rx) = mpsc::channel(2);
-        match *self.status.write().await {
+        let (to_server_tx, to_server_rx) = mpsc::channel(self.config.channel_capacity);
+        let ping_tx = to_server_tx.clone();
+        let pong_tx = to_server_tx.clone();
+        let queued = match *self.status.write().await {
             ref mut status @ ClientStatus::Connecting => {
-                *status = ClientStatus::Connected(to_server_tx)
+                *status = ClientStatus::Connected(to_server_tx);
+                // packets waiting for an acknowledgement are older than
+                // ones queued during this outage
+                let mut queued = match self.journal {
+                    Some(ref journal) => journal
+                        .lock()
+                        .await
+                        .entries()
+                        .map(|entry| entry.packet.clone())
+                        .collect(),
+                    None => Vec::new(),
+                };
+                if let Some(ref outbox) = self.outbox {
+                    queued.extend(outbox.lock().await.drain(Instant::now()));
+                }
+                queued
             }
             _ => return Ok(()),
-        }
+        };
 
         *self.connection_attempts.write().await = 0;
+        *self.next_attempt.write().await = None;
 
         *self.connected_time.write().await = Some(Instant::now());
+        *self.endpoint.write().await = Some(peer.clone());
+        self.stats.counters.increase_connects();
+        info!(%peer, "Connected to TCP relay");
+        self.emit(ClientState::Connected { peer }).await;
+
+        let mut to_server_rx = to_server_rx;
+
+        let writer = async {
+            if !queued.is_empty() {
+                debug!(packets = queued.len(), "Sending queued packets");
+            }
+            for packet in queued {
+                to_server
+                    .feed(packet)
+                    .await
+                    .map_err(|e| e.context(SpawnErrorKind::Encode))?;
+            }
+            to_server
+                .flush()
+                .await
+                .map_err(|e| e.context(SpawnErrorKind::Encode))?;
+            loop {
+                futures::select! {
+                    packet = to_server_rx.next() => match packet {
+                        Some(packet) => to_server
+                            .send(packet)
+                            .await
+                            .map_err(|e| e.context(SpawnErrorKind::Encode))?,
+                        None => return Ok(()),
+                    },
+                    _ = shutdown => break,
+                }
+            }
 
-        let mut to_server_rx = to_server_rx.map(Ok);
+            // send packets queued before the close was requested
+            to_server_rx.close();
+            let flush = async {
+                while let Some(packet) = to_server_rx.next().await {
+                    to_server.feed(packet).await?;
+                }
+                to_server.close().await
+            };
+            match tokio::time::timeout(self.config.close_timeout, flush).await {
+                Ok(res) => res.map_err(|e| e.context(SpawnErrorKind::Encode).into()),
+                Err(_) => {
+                    warn!("Timed out sending queued packets to TCP relay");
+                    Ok(())
+                }
+            }
+        };
 
-        let writer = to_server
-            .send_all(&mut to_server_rx)
-            .map_err(|e| SpawnError::from(e.context(SpawnErrorKind::Encode)));
+        let pending_ping = Mutex::new(None);
 
         let reader = async {
             while let Some(packet) = from_server.next().await {
                 let packet = packet.map_err(|e| e.context(SpawnErrorKind::ReadSocket))?;
+                let packet = match self.handle_pong(packet, &pending_ping).await {
+                    Some(packet) => packet,
+                    None => continue,
+                };
+                if let Some(pong) = packet.pong_response() {
+                    pong_tx
+                        .clone()
+                        .send(pong)
+                        .await
+                        .map_err(|e| e.context(SpawnErrorKind::SendTo))?;
+                    continue;
+                }
                 self.handle_packet(packet)
                     .await
                     .map_err(|e| e.context(SpawnErrorKind::HandlePacket))?;
@@ -215,26 +562,286 @@ impl Client {
             Result::<(), SpawnError>::Ok(())
         };
 
+        let heartbeat = self.heartbeat(ping_tx, &pending_ping);
+        let transport_rtt = self.transport_rtt(rtt_rx, &pending_ping);
+
         futures::select! {
             res = reader.fuse() => res,
             res = writer.fuse() => res,
+            res = heartbeat.fuse() => res,
+            () = transport_rtt.fuse() => Ok(()),
         }
     }
 
-    async fn run(&mut self) -> Result<(), SpawnError> {
-        let result = self.spawn_inner().await;
+    /// Send pings to the relay until it leaves `max_missed_pongs` of them in
+    /// a row unanswered. Never completes if pings are disabled or not
+    /// supported by the protocol.
+    async fn heartbeat(
+        &self,
+        mut tx: mpsc::Sender<P>,
+        pending_ping: &Mutex<Option<PendingPing>>,
+    ) -> Result<(), SpawnError> {
+        let interval = match self.config.ping_interval {
+            Some(interval) => interval,
+            None => return futures::future::pending().await,
+        };
+        let mut wakeups =
+            tokio::time::interval_at(tokio::time::Instant::now() + interval, interval);
+        let mut missed_pongs = 0;
+
+        loop {
+            wakeups.tick().await;
+
+            let ping_id = self.seq.fetch_add(1, Ordering::Relaxed) as u64;
+            let packet = match P::ping_request(ping_id) {
+                Some(packet) => packet,
+                None => return futures::future::pending().await,
+            };
+
+            {
+                let mut pending_ping = pending_ping.lock().await;
+                if pending_ping.is_some() {
+                    missed_pongs += 1;
+                    debug!(missed_pongs, "Relay didn't answer ping");
+                    if missed_pongs >= self.config.max_missed_pongs {
+                        return Err(SpawnErrorKind::PingTimeout.into());
+                    }
+                } else {
+                    missed_pongs = 0;
+                }
+                *pending_ping = Some(PendingPing {
+                    ping_id,
+                    sent_time: Instant::now(),
+                });
+            }
+
+            tx.send(packet)
+                .await
+                .map_err(|e| e.context(SpawnErrorKind::SendTo))?;
+        }
+    }
+
+    /// Take pongs out of the packets received from the relay and update the
+    /// round-trip time if they answer the last ping. A pong is passed to the
+    /// request it answers, the rest are dropped. Returns the packet back if
+    /// it's not a pong.
+    async fn handle_pong(&self, packet: P, pending_ping: &Mutex<Option<PendingPing>>) -> Option<P> {
+        let pong_id = match packet.pong_id() {
+            Some(pong_id) => pong_id,
+            None => return Some(packet),
+        };
+        let mut pending_ping = pending_ping.lock().await;
+        match *pending_ping {
+            Some(ping) if ping.ping_id == pong_id => {
+                let rtt = ping.sent_time.elapsed();
+                trace!(rtt = ?rtt, "Relay answered ping");
+                *pending_ping = None;
+                self.record_rtt(rtt).await;
+            }
+            _ => {
+                drop(pending_ping);
+                if self.answer_request(packet).await.is_some() {
+                    // a pong arriving after its ping was counted as missed
+                    trace!(pong_id, "Relay sent late or unknown pong");
+                }
+            }
+        }
+        None
+    }
+
+    /// Count the keepalive pings answered by the transport like pongs to the
+    /// heartbeat. Never completes.
+    async fn transport_rtt(
+        &self,
+        mut rtt_rx: mpsc::UnboundedReceiver<Duration>,
+        pending_ping: &Mutex<Option<PendingPing>>,
+    ) {
+        while let Some(rtt) = rtt_rx.next().await {
+            trace!(rtt = ?rtt, "Transport answered keepalive ping");
+            *pending_ping.lock().await = None;
+            self.record_rtt(rtt).await;
+        }
+        futures::future::pending().await
+    }
+
+    async fn record_rtt(&self, rtt: Duration) {
+        *self.rtt.write().await = Some(rtt);
+        self.stats.counters.record_rtt(rtt);
+    }
+
+    /// Open a stream to the current endpoint of the target with the
+    /// transport and perform the TLS handshake if it's enabled. A hostname is
+    /// resolved anew and its addresses are tried in turn. Returns the stream
+    /// and the peer it was opened to. Round-trip times of the transport's own
+    /// keepalive pings go to `rtt_tx`.
+    async fn connect(&self, rtt_tx: RttSender) -> Result<(BoxedStream, Peer), SpawnError> {
+        let endpoint = &self.target.endpoints()[self.rotation.lock().await.current()];
+        let peers = match *endpoint {
+            Endpoint::Addr(addr) => vec![Peer::Addr(addr)],
+            Endpoint::Host { ref host, port } => self
+                .config
+                .resolver
+                .resolve(host, port)
+                .await
+                .map_err(|e| e.context(SpawnErrorKind::Resolve))?
+                .into_iter()
+                .map(|addr| Peer::Host {
+                    host: host.clone(),
+                    addr,
+                })
+                .collect(),
+            #[cfg(unix)]
+            Endpoint::Path(ref path) => vec![Peer::Path(path.clone())],
+        };
+
+        let mut error = None;
+        let mut opened = None;
+        for peer in peers {
+            let timeout = self.config.connect_timeout;
+            let connecting = self
+                .config
+                .transport
+                .connect_with_rtt(&peer, rtt_tx.clone());
+            let connecting = tokio::time::timeout(timeout, connecting);
+            let connected = connecting
+                .await
+                .unwrap_or_else(|_| Err(IoError::new(ErrorKind::TimedOut, "Connect timed out")));
+            match connected {
+                Ok(socket) => {
+                    opened = Some((socket, peer));
+                    break;
+                }
+                Err(e) => {
+                    debug!(%peer, error = %e, "Failed to connect to TCP relay address");
+                    error = Some(e);
+                }
+            }
+        }
+        let (socket, peer) = match (opened, error) {
+            (Some(opened), _) => opened,
+            (None, Some(e)) => return Err(e.context(SpawnErrorKind::Io).into()),
+            (None, None) => return Err(SpawnErrorKind::Resolve.into()),
+        };
+
+        match self.config.tls {
+            Some(ref tls) => {
+                let timeout = self.config.handshake_timeout;
+                let socket = tokio::time::timeout(timeout, tls.connect(socket))
+                    .await
+                    .map_err(|e| e.context(SpawnErrorKind::HandshakeTimeout))?
+                    .map_err(|e| e.context(SpawnErrorKind::Tls))?;
+                Ok((Box::new(socket), peer))
+            }
+            None => Ok((socket, peer)),
+        }
+    }
 
+    /// Exchange `Hello` with the relay and establish the secure session if
+    /// it's enabled. Fails if it takes longer than the handshake timeout.
+    async fn handshake<S>(
+        &self,
+        stream: &mut S,
+    ) -> Result<(Negotiated, Option<Session>), SpawnError>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let handshake = async {
+            let encryption = if self.config.secure.is_some() {
+                CAPABILITY_ENCRYPTION
+            } else {
+                0
+            };
+            let compression = if self.config.compression.is_some() {
+                CAPABILITY_COMPRESSION
+            } else {
+                0
+            };
+            let capabilities = Capabilities {
+                supported: encryption | compression,
+                required: encryption,
+                max_frame_size: MAX_FRAME_SIZE,
+            };
+            let client_id = self.client_id.read().await.clone();
+            let negotiated = handshake::client_hello(stream, client_id, capabilities)
+                .await
+                .map_err(|e| e.context(SpawnErrorKind::Handshake))?;
+
+            let session = match self.config.secure {
+                Some(ref config) => Some(
+                    secure::client_handshake(stream, config)
+                        .await
+                        .map_err(|e| e.context(SpawnErrorKind::Handshake))?,
+                ),
+                None => None,
+            };
+
+            Result::<_, SpawnError>::Ok((negotiated, session))
+        };
+
+        tokio::time::timeout(self.config.handshake_timeout, handshake)
+            .await
+            .map_err(|e| e.context(SpawnErrorKind::HandshakeTimeout))?
+    }
+
+    async fn run(&mut self) -> Result<(), SpawnError> {
         match *self.status.write().await {
-            ClientStatus::Sleeping => {}
-            ref mut status => *status = ClientStatus::Disconnected,
+            ref mut status @ ClientStatus::Disconnected
+            | ref mut status @ ClientStatus::Sleeping => *status = ClientStatus::Connecting,
+            _ => return Ok(()),
         }
+        self.emit(ClientState::Connecting).await;
+
+        // dropped when the connection is completely finished
+        let (_done, done) = futures::channel::oneshot::channel::<()>();
+        let result = self.spawn_inner(done).await;
+
+        // `disconnect` and `sleep` change the status and report it themselves
+        let dropped = match *self.status.write().await {
+            ClientStatus::Sleeping | ClientStatus::Disconnected => false,
+            ref mut status => {
+                *status = ClientStatus::Disconnected;
+                true
+            }
+        };
         if let Err(ref e) = result {
-            //error!("TCP relay connection error: {}", e);
+            warn!(error = %e, "TCP relay connection error");
 
+            // the next attempt is scheduled before the failed one is visible
             let mut connection_attempts = self.connection_attempts.write().await;
-            *connection_attempts = connection_attempts.saturating_add(1);
+            let attempts = connection_attempts.saturating_add(1);
+            if self.config.reconnect.gives_up(attempts) {
+                warn!(attempts, "Giving up reconnecting to TCP relay");
+                *self.next_attempt.write().await = None;
+            } else {
+                let delay = self.config.reconnect.delay(attempts);
+                debug!(attempts, ?delay, "Reconnecting to TCP relay later");
+                *self.next_attempt.write().await = Some(Instant::now() + delay);
+            }
+            *connection_attempts = attempts;
+            drop(connection_attempts);
+
+            if self.config.reconnect.gives_up(attempts) {
+                let client_id = self.client_id.read().await.clone();
+                self.config.reconnect.give_up(&client_id, attempts);
+            }
+
+            if self.target.endpoints().len() > 1 {
+                let mut rotation = self.rotation.lock().await;
+                rotation.advance();
+                let next = &self.target.endpoints()[rotation.current()];
+                debug!(%next, "Switching to the next endpoint of TCP relay");
+            }
         }
         *self.connected_time.write().await = None;
+        *self.endpoint.write().await = None;
+        *self.rtt.write().await = None;
+        // fail requests waiting for a response
+        self.pending.lock().await.clear();
+
+        if dropped {
+            let error = result.as_ref().err().map(ErrorCause::from);
+            self.emit(ClientState::Disconnected { error }).await;
+        }
 
         result
     }
@@ -243,67 +850,103 @@ impl Client {
     /// connection is spawned via `tokio::spawn` so the result future will be
     /// completed after first poll.
     pub async fn spawn(mut self) -> Result<(), SpawnError> {
-        // TODO: send pings periodically
-
-        tokio::spawn(async move { self.run().await });
+        let span = info_span!(
+            "relay",
+            target = %self.target,
+            client_id = %self.client_id.read().await
+        );
+        tokio::spawn(async move { self.run().await }.instrument(span));
 
         Ok(())
     }
 
-    pub fn spawn_lua(self, packet: Packet) -> Result<(), SpawnError> {
+    /// Call Lua plugins of the plugin directory with the packet in a
+    /// background task. `duplicate` is the flag received with the packet
+    /// from the incoming channel and is passed on as `IsDuplicate`. A
+    /// missing directory means no plugins, other errors reading it fail with
+    /// `SpawnErrorKind::Plugin`.
+    pub fn spawn_lua(self, packet: P, duplicate: bool) -> Result<(), SpawnError> {
+        let paths = match std::fs::read_dir(&self.config.plugin_dir) {
+            Ok(paths) => paths,
+            Err(ref e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e.context(SpawnErrorKind::Plugin).into()),
+        };
         tokio::spawn(async move {
-            let paths = std::fs::read_dir("./Plugins").unwrap();
             let lua = Lua::new();
+            if let Err(e) = lua.globals().set("IsDuplicate", duplicate) {
+                warn!(error = %e, "plugin global error");
+                return;
+            }
             for path in paths {
-                let path = path.unwrap().path();
+                let path = match path {
+                    Ok(entry) => entry.path(),
+                    Err(e) => {
+                        warn!(error = %e, "plugin directory error");
+                        continue;
+                    }
+                };
 
-                if path.extension().unwrap() == "lua" {
-                    println!("load plugins {}", &path.display());
-                    let mut file = File::open(&path).unwrap();
-                    let mut lua_code = String::new();
-                    file.read_to_string(&mut lua_code).unwrap();
+                if path.extension().is_some_and(|ext| ext == "lua") {
+                    let span = info_span!("plugin", path = %path.display());
+                    let _enter = span.enter();
+                    debug!("load plugin");
+                    let lua_code = match std::fs::read_to_string(&path) {
+                        Ok(lua_code) => lua_code,
+                        Err(e) => {
+                            warn!(error = %e, "plugin read error");
+                            continue;
+                        }
+                    };
 
                     let globals = lua.globals();
 
+                    let name = path.to_string_lossy();
                     if let Err(ref e) = lua
                         .load(&lua_code)
-                        .set_name(path.to_str().unwrap())
-                        .unwrap()
-                        .exec()
+                        .set_name(name.as_ref())
+                        .and_then(|chunk| chunk.exec())
                     {
-                        println!("{}", e);
+                        warn!(error = %e, "plugin load error");
                         return;
                     }
 
                     let mut ret: u32 = 0;
-                    //let packet = packet.clone();
-                    if let Packet::ChatMessage(ref pkg) = packet {
-                        let on_chat_msg = globals.get::<_, Function>("OnChatMsg").unwrap();
-                        ret = match on_chat_msg
-                            .call::<(Client, ChatMessage), u32>((self.clone(), pkg.clone()))
-                        {
+                    let arg = match packet.plugin_arg(&lua) {
+                        Ok(arg) => arg,
+                        Err(e) => {
+                            warn!(error = %e, "plugin argument error");
+                            return;
+                        }
+                    };
+                    let call = |name: &str, pkg: &Value| {
+                        let handler = globals.get::<_, Function>(name)?;
+                        handler.call::<(Client<P>, Value), u32>((self.clone(), pkg.clone()))
+                    };
+                    if let Some(ref pkg) = arg {
+                        self.stats.counters.increase_plugin_calls();
+                        ret = match call("OnChatMsg", pkg) {
                             Ok(result) => {
-                                println!("  call reutrn {}", result);
+                                debug!(result, "OnChatMsg returned");
                                 result
                             }
                             Err(e) => {
-                                println!("{}", e);
+                                self.stats.counters.increase_plugin_errors();
+                                warn!(error = %e, "OnChatMsg error");
                                 0
                             }
                         }
                     }
 
-                    if let Packet::ChatMessage(ref pkg) = packet {
-                        let on_chat_msg = globals.get::<_, Function>("OnChatEvent").unwrap();
-                        ret = match on_chat_msg
-                            .call::<(Client, ChatMessage), u32>((self.clone(), pkg.clone()))
-                        {
+                    if let Some(ref pkg) = arg {
+                        self.stats.counters.increase_plugin_calls();
+                        ret = match call("OnChatEvent", pkg) {
                             Ok(result) => {
-                                println!("  call reutrn {}", result);
+                                debug!(result, "OnChatEvent returned");
                                 result
                             }
                             Err(e) => {
-                                println!("{}", e);
+                                self.stats.counters.increase_plugin_errors();
+                                warn!(error = %e, "OnChatEvent error");
                                 0
                             }
                         }
@@ -322,17 +965,41 @@ impl Client {
         });
         Ok(())
     }
-    /// Drop connection to the TCP relay if it's connected.
+    /// Close connection to the TCP relay if it's connected. Packets queued
+    /// before the call are sent within the close timeout and requests
+    /// waiting for a response fail with `SendPacketErrorKind::Disconnected`.
+    /// Completes when the connection is closed.
     pub async fn disconnect(&self) {
-        // just drop the sink to stop the connection
-        *self.status.write().await = ClientStatus::Disconnected;
+        self.close(ClientStatus::Disconnected).await
     }
 
-    /// Drop connection to the TCP relay if it's connected changing status to
+    /// Close connection to the TCP relay like `disconnect` changing status to
     /// `Sleeping`.
     pub async fn sleep(&self) {
-        // just drop the sink to stop the connection
-        *self.status.write().await = ClientStatus::Sleeping;
+        self.close(ClientStatus::Sleeping).await
+    }
+
+    async fn close(&self, status: ClientStatus<P>) {
+        let state = match status {
+            ClientStatus::Sleeping => ClientState::Sleeping,
+            _ => ClientState::Disconnected { error: None },
+        };
+        // new packets are rejected from now on
+        let changed = {
+            let mut current = self.status.write().await;
+            let changed = std::mem::discriminant(&*current) != std::mem::discriminant(&status);
+            *current = status;
+            changed
+        };
+        if changed {
+            self.emit(state).await;
+        }
+
+        let shutdown = self.shutdown.lock().await.take();
+        if let Some(Shutdown { request, done }) = shutdown {
+            let _ = request.send(());
+            let _ = done.await;
+        }
     }
 
     /// Check if TCP connection to the relay is established.
@@ -357,19 +1024,62 @@ impl Client {
         *self.connection_attempts.read().await
     }
 
+    /// Check if the client gave up reconnecting after too many failed
+    /// attempts according to its `ReconnectPolicy`.
+    pub async fn gave_up(&self) -> bool {
+        let attempts = self.connection_attempts().await;
+        self.config.reconnect.gives_up(attempts)
+    }
+
+    /// Check if a disconnected client may be spawned again: the delay after
+    /// the last failed attempt has passed and the client didn't give up.
+    pub async fn reconnect_due(&self) -> bool {
+        if self.gave_up().await {
+            return false;
+        }
+        self.next_attempt
+            .read()
+            .await
+            .is_none_or(|next_attempt| next_attempt <= Instant::now())
+    }
+
+    /// Endpoints of the relay the client connects to.
+    pub fn target(&self) -> &Target {
+        &self.target
+    }
+
+    /// Address of the relay the client is connected to. Only connected
+    /// relays have this value.
+    pub async fn endpoint(&self) -> Option<Peer> {
+        self.endpoint.read().await.clone()
+    }
+
+    /// IP address of the relay the client is connected to. Only relays
+    /// connected over IP have this value.
+    #[deprecated(note = "use `endpoint`, relays may be reached by name or Unix socket path")]
+    pub async fn addr(&self) -> Option<SocketAddr> {
+        self.endpoint().await.and_then(|peer| peer.addr())
+    }
+
     /// Time when a connection to the relay was established. Only connected
     /// relays have this value.
     pub async fn connected_time(&self) -> Option<Instant> {
         *self.connected_time.read().await
     }
+
+    /// Round-trip time measured by the last ping answered by the relay or its
+    /// transport. Only connected relays have this value.
+    pub async fn rtt(&self) -> Option<Duration> {
+        *self.rtt.read().await
+    }
 }
 
-impl UserData for Client {
+impl<P: Protocol> UserData for Client<P> {
     //绑定lua API
     fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
         methods.add_method("prints", |_, this, arg: String| {
             //lua self:prints()
-            println!("{:#?}", arg);
+            info!("{}", arg);
             Ok(())
         });
 
@@ -377,24 +1087,21 @@ impl UserData for Client {
             //lua self:SendPkg(table)
             let from_user_name = t.get::<_, String>("FromUserName")?;
             let to_user_name = t.get::<_, String>("ToUserName")?;
-            println!(
-                "from_user_name {}  to_user_name {}",
-                from_user_name, to_user_name
-            );
+            debug!(%from_user_name, %to_user_name, "SendPkg");
 
             Ok(())
         });
 
         methods.add_function("print", |_, args: String| {
             //lua self.print("hello")
-            println!("args is {:#?}", args);
+            info!("{}", args);
             Ok(())
         });
-        methods.add_meta_method(MetaMethod::Index, |ctx, this: &Client, arg: String| {
+        methods.add_meta_method(MetaMethod::Index, |ctx, this: &Client<P>, arg: String| {
             let r = match arg.as_str() {
                 "clientid" => this.client_id.try_read().unwrap().as_str().to_lua(ctx).ok(), //lua self.Clientid
                 _ => {
-                    println!("arg {}", arg);
+                    debug!(%arg, "unknown Client field");
                     None
                 }
             };
@@ -402,3 +1109,822 @@ impl UserData for Client {
         });
     }
 }
+
+/// Write to the journal in a blocking task so that syncing the file doesn't
+/// stall the runtime.
+async fn write_journal<P, T, F>(journal: &Arc<Mutex<Journal<P>>>, write: F) -> Result<T, IoError>
+where
+    P: Protocol,
+    T: Send + 'static,
+    F: FnOnce(&mut Journal<P>) -> Result<T, IoError> + Send + 'static,
+{
+    let mut journal = journal.clone().lock_owned().await;
+    tokio::task::spawn_blocking(move || write(&mut journal))
+        .await
+        .map_err(IoError::other)?
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    use crate::chatmsg::ChatMessage;
+    use crate::compression::Compression;
+    use crate::dedup::DedupConfig;
+    use crate::endpoint::Resolver;
+    use crate::outbox::OutboxConfig;
+    use crate::ping_request::PingRequest;
+    use crate::pong_response::PongResponse;
+    use crate::secure::SecureConfig;
+    use crate::server::{tcp_run_connection, Server};
+    use crate::tls::{tests::TestCa, TlsClientConfig, TlsServerConfig};
+    use crate::transport::TcpTransport;
+    use bytes::{Bytes, BytesMut};
+    use futures::future::BoxFuture;
+    use tokio::{
+        io::AsyncWriteExt,
+        net::{TcpListener, TcpStream},
+    };
+    use tokio_util::codec::Encoder;
+
+    /// Time tests wait for a client to connect or disconnect.
+    const WAIT_TIMEOUT: Duration = Duration::from_secs(5);
+
+    /// Packets passed on by a client created with `test_client`.
+    pub type Incoming<P> = mpsc::UnboundedReceiver<(Client<P>, P, bool)>;
+
+    /// Client of the given relay with the given settings. Packets it passes
+    /// on are received from the returned receiver.
+    pub fn test_client<T: Into<Target>>(
+        target: T,
+        config: ClientConfig,
+    ) -> (Client<Packet>, Incoming<Packet>) {
+        protocol_client(target, config)
+    }
+
+    /// `test_client` of another protocol.
+    pub fn protocol_client<P, T>(target: T, config: ClientConfig) -> (Client<P>, Incoming<P>)
+    where
+        P: Protocol,
+        T: Into<Target>,
+    {
+        let (incoming_tx, incoming_rx) = mpsc::unbounded();
+        let client_id = Arc::new(RwLock::new("client".to_string()));
+        let client = Client::from_target(target.into(), client_id, incoming_tx, config);
+        (client, incoming_rx)
+    }
+
+    /// Wait until the client is connected.
+    pub async fn connected<P: Protocol>(client: &Client<P>) {
+        wait_for_status(client, true).await
+    }
+
+    /// Wait until the client is not connected.
+    pub async fn disconnected<P: Protocol>(client: &Client<P>) {
+        wait_for_status(client, false).await
+    }
+
+    /// Check the status on every state event until the client is connected
+    /// or not as given. Fails after `WAIT_TIMEOUT`.
+    async fn wait_for_status<P: Protocol>(client: &Client<P>, connected: bool) {
+        let mut events = client.subscribe();
+        let status = async {
+            while client.is_connected().await != connected {
+                // a lagged receiver checks the status again as well
+                let _ = events.recv().await;
+            }
+        };
+        tokio::time::timeout(WAIT_TIMEOUT, status)
+            .await
+            .expect("Client status didn't change in time");
+    }
+
+    fn chat_message(msg_id: u64) -> Packet {
+        Packet::ChatMessage(ChatMessage {
+            msg_id,
+            to_user: "client".to_string(),
+            from_user: "server".to_string(),
+            content: Bytes::from_static(b"pong"),
+        })
+    }
+
+    /// The answer to `chat_message(msg_id)`, addressed back to its sender.
+    fn reply(msg_id: u64) -> Packet {
+        Packet::ChatMessage(ChatMessage {
+            msg_id,
+            to_user: "server".to_string(),
+            from_user: "client".to_string(),
+            content: Bytes::from_static(b"pong"),
+        })
+    }
+
+    #[tokio::test]
+    async fn spawn_coalesced_and_torn_frames() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (client, mut incoming_rx) = test_client(addr, ClientConfig::default());
+        client.clone().spawn().await.unwrap();
+
+        let (mut stream, _) = listener.accept().await.unwrap();
+        stream.set_nodelay(true).unwrap();
+        let capabilities = Capabilities {
+            supported: 0,
+            required: 0,
+            max_frame_size: MAX_FRAME_SIZE,
+        };
+        let negotiated = handshake::server_hello(&mut stream, capabilities)
+            .await
+            .unwrap();
+        assert_eq!(negotiated.client_id, "client");
+
+        let mut codec = Codec::<Packet>::new(Stats::new());
+        let mut coalesced = BytesMut::new();
+        codec.encode(chat_message(1), &mut coalesced).unwrap();
+        codec.encode(chat_message(2), &mut coalesced).unwrap();
+        let mut torn = BytesMut::new();
+        codec.encode(chat_message(3), &mut torn).unwrap();
+
+        stream.write_all(&coalesced).await.unwrap();
+        for byte in torn.iter() {
+            stream.write_all(&[*byte]).await.unwrap();
+            tokio::task::yield_now().await;
+        }
+
+        for msg_id in 1..=3 {
+            let (_, packet, _) = incoming_rx.next().await.unwrap();
+            assert_eq!(packet, chat_message(msg_id));
+        }
+        assert!(client.is_connected().await);
+    }
+
+    #[tokio::test]
+    async fn spawn_secure() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server_config = SecureConfig::generate();
+        let server = Server::new().with_secure(server_config.clone());
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            tcp_run_connection(&server, stream, Stats::new()).await
+        });
+
+        let config = ClientConfig::default()
+            .with_secure(SecureConfig::generate().pin_server_key(server_config.public_key()));
+        let (client, _incoming_rx) = test_client(addr, config);
+        client.clone().spawn().await.unwrap();
+        connected(&client).await;
+
+        match client.send_for_response(chat_message(0)).await.unwrap() {
+            Packet::ChatMessage(p) => assert!(p.msg_id > 0),
+            packet => panic!("unexpected packet {:?}", packet),
+        }
+        let snapshot = client.stats().snapshot();
+        assert_eq!(snapshot.connects, 1);
+        assert_eq!(snapshot.rtt.count(), 1);
+    }
+
+    #[tokio::test]
+    async fn spawn_compressed() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = Server::new().with_compression(Compression::new(64));
+        let server_stats = Stats::new();
+        let stats = server_stats.clone();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            tcp_run_connection(&server, stream, stats).await
+        });
+
+        let config = ClientConfig::default().with_compression(Compression::new(64));
+        let (client, _incoming_rx) = test_client(addr, config);
+        client.clone().spawn().await.unwrap();
+        connected(&client).await;
+
+        let mut packet = chat_message(0);
+        if let Packet::ChatMessage(ref mut p) = packet {
+            p.content = b"0a0b0c0d".repeat(512).into();
+        }
+        client.send_for_response(packet).await.unwrap();
+
+        let counters = &server_stats.counters;
+        assert!(counters.raw_bytes() > 4096);
+        assert!(counters.compressed_bytes() < counters.raw_bytes() / 4);
+    }
+
+    #[tokio::test]
+    async fn spawn_tls() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let ca = TestCa::new();
+        let cert = ca.issue("localhost");
+        let tls = TlsServerConfig::new(
+            cert.cert.pem().as_bytes(),
+            cert.key_pair.serialize_pem().as_bytes(),
+        )
+        .unwrap()
+        .build()
+        .unwrap();
+        let server = Server::new().with_tls(tls);
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            tcp_run_connection(&server, stream, Stats::new()).await
+        });
+
+        let tls = TlsClientConfig::new("localhost")
+            .unwrap()
+            .with_root_certificates(ca.cert.pem().as_bytes())
+            .unwrap()
+            .build()
+            .unwrap();
+        let config = ClientConfig::default().with_tls(tls);
+        let (client, _incoming_rx) = test_client(addr, config);
+        client.clone().spawn().await.unwrap();
+        connected(&client).await;
+
+        client.send_for_response(chat_message(0)).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn spawn_secure_server_key_mismatch() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = Server::new().with_secure(SecureConfig::generate());
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            tcp_run_connection(&server, stream, Stats::new()).await
+        });
+
+        let config = ClientConfig::default().with_secure(
+            SecureConfig::generate().pin_server_key(SecureConfig::generate().public_key()),
+        );
+        let (mut client, _incoming_rx) = test_client(addr, config);
+
+        let error = client.run().await.unwrap_err();
+        assert_eq!(*error.kind(), SpawnErrorKind::Handshake);
+        assert!(client.is_disconnected().await);
+        assert_eq!(client.connection_attempts().await, 1);
+    }
+
+    /// Accept the client and complete the plaintext handshake.
+    async fn accept_relay(listener: &TcpListener) -> Framed<TcpStream, Codec<Packet>> {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let capabilities = Capabilities {
+            supported: 0,
+            required: 0,
+            max_frame_size: MAX_FRAME_SIZE,
+        };
+        handshake::server_hello(&mut stream, capabilities)
+            .await
+            .unwrap();
+        Framed::new(stream, Codec::new(Stats::new()))
+    }
+
+    /// Resolves hostnames from a fixed map instead of DNS.
+    #[derive(Debug)]
+    struct StaticResolver(HashMap<String, Vec<SocketAddr>>);
+
+    impl Resolver for StaticResolver {
+        fn resolve(
+            &self,
+            host: &str,
+            _port: u16,
+        ) -> BoxFuture<'static, Result<Vec<SocketAddr>, std::io::Error>> {
+            let unknown = || std::io::Error::new(std::io::ErrorKind::NotFound, "unknown host");
+            let addrs = self.0.get(host).cloned().ok_or_else(unknown);
+            futures::future::ready(addrs).boxed()
+        }
+    }
+
+    async fn closed_addr() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        listener.local_addr().unwrap()
+    }
+
+    #[tokio::test]
+    async fn endpoint_failover() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let closed = closed_addr().await;
+        let mut hosts = HashMap::new();
+        // addresses of a name are tried in turn
+        hosts.insert("relay.test".to_string(), vec![closed_addr().await, addr]);
+        let config = ClientConfig::default().with_resolver(Arc::new(StaticResolver(hosts)));
+        let target = Target::new(closed)
+            .with_fallback(Endpoint::host("unknown.test", 33445))
+            .with_fallback(Endpoint::host("relay.test", 33445));
+
+        let (mut client, _incoming_rx) = test_client(target, config);
+
+        let error = client.run().await.unwrap_err();
+        assert_eq!(*error.kind(), SpawnErrorKind::Io);
+        let error = client.run().await.unwrap_err();
+        assert_eq!(*error.kind(), SpawnErrorKind::Resolve);
+        assert_eq!(client.endpoint().await, None);
+
+        client.clone().spawn().await.unwrap();
+        let _relay = accept_relay(&listener).await;
+        connected(&client).await;
+        let peer = Peer::Host {
+            host: "relay.test".to_string(),
+            addr,
+        };
+        assert_eq!(client.endpoint().await, Some(peer));
+        #[allow(deprecated)]
+        let connected_addr = client.addr().await;
+        assert_eq!(connected_addr, Some(addr));
+    }
+
+    /// TCP transport that never answers for one address.
+    #[derive(Debug)]
+    struct SilentTransport(SocketAddr);
+
+    impl Transport for SilentTransport {
+        fn connect(&self, peer: &Peer) -> BoxFuture<'static, Result<BoxedStream, IoError>> {
+            if peer.addr() == Some(self.0) {
+                futures::future::pending().boxed()
+            } else {
+                TcpTransport.connect(peer)
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn connect_timeout() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let silent = closed_addr().await;
+        let mut hosts = HashMap::new();
+        hosts.insert("relay.test".to_string(), vec![silent, addr]);
+        let config = ClientConfig::default()
+            .with_resolver(Arc::new(StaticResolver(hosts)))
+            .with_transport(Arc::new(SilentTransport(silent)))
+            .with_connect_timeout(Duration::from_millis(50));
+
+        let (mut client, _incoming_rx) = test_client(silent, config.clone());
+        let error = client.run().await.unwrap_err();
+        assert_eq!(*error.kind(), SpawnErrorKind::Io);
+        let cause = error.cause().and_then(|e| e.downcast_ref::<IoError>());
+        assert_eq!(cause.map(IoError::kind), Some(ErrorKind::TimedOut));
+
+        // the next address of the name is tried after the silent one
+        let (client, _incoming_rx) = test_client(Endpoint::host("relay.test", 33445), config);
+        client.clone().spawn().await.unwrap();
+        let _relay = accept_relay(&listener).await;
+        connected(&client).await;
+        let peer = Peer::Host {
+            host: "relay.test".to_string(),
+            addr,
+        };
+        assert_eq!(client.endpoint().await, Some(peer));
+    }
+
+    #[tokio::test]
+    async fn heartbeat_rtt() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let config = ClientConfig::default().with_heartbeat(Some(Duration::from_millis(50)), 3);
+        let (client, _incoming_rx) = test_client(addr, config);
+        client.clone().spawn().await.unwrap();
+
+        let mut relay = accept_relay(&listener).await;
+        let ping_id = match relay.next().await.unwrap().unwrap() {
+            Packet::PingRequest(p) => p.ping_id,
+            packet => panic!("unexpected packet {:?}", packet),
+        };
+        assert_ne!(ping_id, 0);
+        relay
+            .send(Packet::PongResponse(PongResponse { ping_id }))
+            .await
+            .unwrap();
+
+        while client.rtt().await.is_none() {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        assert!(client.is_connected().await);
+        assert_eq!(client.stats().snapshot().rtt.count(), 1);
+    }
+
+    #[tokio::test]
+    async fn heartbeat_swallows_stale_pongs() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let config = ClientConfig::default().with_heartbeat(None, 0);
+        let (client, mut incoming_rx) = test_client(addr, config);
+        client.clone().spawn().await.unwrap();
+
+        // a pong for a ping that was already counted as missed
+        let mut relay = accept_relay(&listener).await;
+        relay
+            .send(Packet::PongResponse(PongResponse { ping_id: 99 }))
+            .await
+            .unwrap();
+        relay.send(chat_message(1)).await.unwrap();
+
+        assert_eq!(incoming_rx.next().await.unwrap().1, chat_message(1));
+        assert!(incoming_rx.try_recv().is_err());
+        assert!(client.rtt().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn heartbeat_dead_relay() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let config = ClientConfig::default().with_heartbeat(Some(Duration::from_millis(20)), 2);
+        let (mut client, _incoming_rx) = test_client(addr, config);
+        // the relay completes the handshake and never answers pings
+        let (result, _relay) = tokio::join!(client.run(), accept_relay(&listener));
+
+        assert_eq!(*result.unwrap_err().kind(), SpawnErrorKind::PingTimeout);
+        assert!(client.is_disconnected().await);
+        assert!(client.rtt().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn heartbeat_answers_server_pings() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = Server::new()
+            .with_heartbeat(Some(Duration::from_millis(10)), Duration::from_millis(50));
+        let server_c = server.clone();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            tcp_run_connection(&server_c, stream, Stats::new()).await
+        });
+
+        let config = ClientConfig::default().with_heartbeat(None, 0);
+        let (client, _incoming_rx) = test_client(addr, config);
+        client.clone().spawn().await.unwrap();
+        connected(&client).await;
+
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(client.is_connected().await);
+        assert_eq!(server.clients.read().await.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn disconnect_flushes_and_closes() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let config = ClientConfig::default().with_heartbeat(None, 0);
+        let (client, _incoming_rx) = test_client(addr, config);
+        client.clone().spawn().await.unwrap();
+        let mut relay = accept_relay(&listener).await;
+        connected(&client).await;
+
+        for msg_id in 1..=3 {
+            client.send_packet(chat_message(msg_id)).await.unwrap();
+        }
+        client.disconnect().await;
+        assert!(client.is_disconnected().await);
+        assert_eq!(
+            *client
+                .send_packet(chat_message(4))
+                .await
+                .unwrap_err()
+                .kind(),
+            SendPacketErrorKind::WrongStatus
+        );
+
+        for msg_id in 1..=3 {
+            assert_eq!(relay.next().await.unwrap().unwrap(), chat_message(msg_id));
+        }
+        assert!(relay.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn sleep_fails_pending_requests() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let config = ClientConfig::default().with_heartbeat(None, 0);
+        let (client, _incoming_rx) = test_client(addr, config);
+        client.clone().spawn().await.unwrap();
+        // the relay never answers
+        let _relay = accept_relay(&listener).await;
+        connected(&client).await;
+
+        let client_c = client.clone();
+        let request =
+            tokio::spawn(async move { client_c.send_for_response(chat_message(0)).await });
+        while client.pending_requests().await == 0 {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        client.sleep().await;
+
+        let error = request.await.unwrap().unwrap_err();
+        assert_eq!(*error.kind(), SendPacketErrorKind::Disconnected);
+        assert!(client.is_sleeping().await);
+        assert_eq!(client.pending_requests().await, 0);
+        assert_eq!(client.connection_attempts().await, 0);
+    }
+
+    #[tokio::test]
+    async fn outbox_replayed_on_connect() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let config = ClientConfig::default()
+            .with_heartbeat(None, 0)
+            .with_outbox(OutboxConfig::new(2));
+        let (client, _incoming_rx) = test_client(addr, config);
+
+        client.send_packet(chat_message(1)).await.unwrap();
+        client.send_packet(chat_message(2)).await.unwrap();
+        let error = client.send_packet(chat_message(3)).await.unwrap_err();
+        assert_eq!(*error.kind(), SendPacketErrorKind::OutboxFull);
+        assert_eq!(client.queued_packets().await, 2);
+
+        client.clone().spawn().await.unwrap();
+        let mut relay = accept_relay(&listener).await;
+        connected(&client).await;
+        client.send_packet(chat_message(4)).await.unwrap();
+        assert_eq!(client.queued_packets().await, 0);
+
+        for msg_id in &[1, 2, 4] {
+            let packet = relay.next().await.unwrap().unwrap();
+            assert_eq!(packet, chat_message(*msg_id));
+        }
+
+        // packets are not queued by a sleeping client
+        client.sleep().await;
+        let error = client.send_packet(chat_message(5)).await.unwrap_err();
+        assert_eq!(*error.kind(), SendPacketErrorKind::WrongStatus);
+    }
+
+    #[tokio::test]
+    async fn spawn_lua_plugin_errors() {
+        let dir = std::env::temp_dir().join(format!("rust-network-plugins-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let addr: SocketAddr = "127.0.0.1:0".parse().unwrap();
+        let plugin_client = |dir: &std::path::Path| {
+            test_client(addr, ClientConfig::default().with_plugin_dir(dir)).0
+        };
+
+        // a missing directory means no plugins
+        plugin_client(&dir)
+            .spawn_lua(chat_message(1), false)
+            .unwrap();
+
+        std::fs::write(&dir, b"").unwrap();
+        let error = plugin_client(&dir)
+            .spawn_lua(chat_message(1), false)
+            .unwrap_err();
+        assert_eq!(*error.kind(), SpawnErrorKind::Plugin);
+        std::fs::remove_file(&dir).unwrap();
+
+        // files without an extension are skipped, missing handlers are errors
+        std::fs::create_dir(&dir).unwrap();
+        std::fs::write(dir.join("README"), b"").unwrap();
+        std::fs::write(dir.join("empty.lua"), b"").unwrap();
+        let client = plugin_client(&dir);
+        client.clone().spawn_lua(chat_message(1), false).unwrap();
+        while client.stats().snapshot().plugin_errors != 2 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn journal_acknowledged_by_responses_only() {
+        let path = std::env::temp_dir().join(format!(
+            "rust-network-client-ack-{}.journal",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+        let addr: SocketAddr = "127.0.0.1:0".parse().unwrap();
+        let (client, _incoming_rx) = test_client(addr, ClientConfig::default());
+        let client = client.with_journal(Journal::open(&path).unwrap());
+        client.send_packet(chat_message(7)).await.unwrap();
+
+        // a pong with the same id is not a response to a chat message
+        let pong = Packet::PongResponse(PongResponse { ping_id: 7 });
+        client.handle_packet(pong).await.unwrap();
+        let journal = client.journal().unwrap().clone();
+        assert!(journal.lock().await.contains(7));
+
+        // nor is an unrelated message that happens to reuse the id
+        client.handle_packet(chat_message(7)).await.unwrap();
+        assert!(journal.lock().await.contains(7));
+
+        client.handle_packet(reply(7)).await.unwrap();
+        assert!(journal.lock().await.is_empty());
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[tokio::test]
+    async fn journal_kept_when_send_fails() {
+        let path = std::env::temp_dir().join(format!(
+            "rust-network-client-send-{}.journal",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+        let addr: SocketAddr = "127.0.0.1:0".parse().unwrap();
+        let (client, _incoming_rx) = test_client(addr, ClientConfig::default());
+        let client = client.with_journal(Journal::open(&path).unwrap());
+        // the connection went away before the status was updated
+        let (tx, _) = mpsc::channel(1);
+        *client.status.write().await = ClientStatus::Connected(tx);
+
+        let error = client.send_packet(chat_message(3)).await.unwrap_err();
+        assert_eq!(*error.kind(), SendPacketErrorKind::SendTo);
+        assert!(client.journal().unwrap().lock().await.contains(3));
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[tokio::test]
+    async fn journal_resent_until_acknowledged() {
+        let path = std::env::temp_dir().join(format!(
+            "rust-network-client-{}.journal",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let config = ClientConfig::default().with_heartbeat(None, 0);
+        let (client, _incoming_rx) = test_client(addr, config);
+        let client = client.with_journal(Journal::open(&path).unwrap());
+
+        // journaled packets are kept while disconnected without an outbox
+        client.send_packet(chat_message(1)).await.unwrap();
+        client.send_packet(chat_message(2)).await.unwrap();
+
+        client.clone().spawn().await.unwrap();
+        let mut relay = accept_relay(&listener).await;
+        for msg_id in &[1, 2] {
+            assert_eq!(relay.next().await.unwrap().unwrap(), chat_message(*msg_id));
+        }
+        relay.send(reply(1)).await.unwrap();
+        let journal = client.journal().unwrap().clone();
+        while journal.lock().await.len() != 1 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        // the unacknowledged packet is sent again after a reconnect
+        drop(relay);
+        disconnected(&client).await;
+        client.clone().spawn().await.unwrap();
+        let mut relay = accept_relay(&listener).await;
+        assert_eq!(relay.next().await.unwrap().unwrap(), chat_message(2));
+        client.disconnect().await;
+
+        // and after a restart
+        let ids: Vec<_> = Journal::<Packet>::open(&path)
+            .unwrap()
+            .entries()
+            .map(|entry| entry.id)
+            .collect();
+        assert_eq!(ids, vec![2]);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn dedup_drops_repeats() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let config = ClientConfig::default()
+            .with_heartbeat(None, 0)
+            .with_dedup(DedupConfig::new(16, Duration::from_secs(60)));
+        let (client, mut incoming_rx) = test_client(addr, config);
+        client.clone().spawn().await.unwrap();
+        let mut relay = accept_relay(&listener).await;
+
+        for msg_id in &[1, 1, 2, 1, 3] {
+            relay.send(chat_message(*msg_id)).await.unwrap();
+        }
+        for msg_id in &[1, 2, 3] {
+            let (_, packet, _) = incoming_rx.next().await.unwrap();
+            assert_eq!(packet, chat_message(*msg_id));
+        }
+        assert_eq!(client.stats().snapshot().duplicates, 2);
+    }
+
+    #[tokio::test]
+    async fn dedup_flags_repeats_on_arrival() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let dedup =
+            DedupConfig::new(16, Duration::from_secs(60)).with_action(DuplicateAction::Flag);
+        let config = ClientConfig::default()
+            .with_heartbeat(None, 0)
+            .with_dedup(dedup);
+        let (client, mut incoming_rx) = test_client(addr, config);
+        client.clone().spawn().await.unwrap();
+        let mut relay = accept_relay(&listener).await;
+
+        // the repeat arrives before the first copy is taken from the channel
+        for msg_id in &[1, 1, 2] {
+            relay.send(chat_message(*msg_id)).await.unwrap();
+        }
+        for &(msg_id, duplicate) in &[(1, false), (1, true), (2, false)] {
+            let (_, packet, flag) = incoming_rx.next().await.unwrap();
+            assert_eq!(packet, chat_message(msg_id));
+            assert_eq!(flag, duplicate);
+        }
+    }
+
+    #[tokio::test]
+    async fn state_events() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let config = ClientConfig::default().with_heartbeat(None, 0);
+        let (client, _incoming_rx) = test_client(addr, config);
+        let mut events = client.subscribe();
+
+        client.clone().spawn().await.unwrap();
+        let relay = accept_relay(&listener).await;
+        let connecting = events.recv().await.unwrap();
+        assert_eq!(connecting.client_id, "client");
+        assert_eq!(connecting.state, ClientState::Connecting);
+        let connected = events.recv().await.unwrap();
+        let peer = Peer::Addr(addr);
+        assert_eq!(connected.state, ClientState::Connected { peer });
+        assert!(connected.at >= connecting.at);
+
+        // the relay drops the connection
+        drop(relay);
+        let state = events.recv().await.unwrap().state;
+        assert_eq!(state, ClientState::Disconnected { error: None });
+
+        client.sleep().await;
+        assert_eq!(events.recv().await.unwrap().state, ClientState::Sleeping);
+
+        // nobody listens anymore
+        drop(listener);
+        client.clone().spawn().await.unwrap();
+        assert_eq!(events.recv().await.unwrap().state, ClientState::Connecting);
+        match events.recv().await.unwrap().state {
+            ClientState::Disconnected { error: Some(cause) } => {
+                assert_eq!(cause.kind, SpawnErrorKind::Io);
+                assert!(cause.message.starts_with("Tcp client io error: "));
+            }
+            state => panic!("unexpected state {:?}", state),
+        }
+    }
+
+    #[tokio::test]
+    async fn send_for_response_ping() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = Server::new();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            tcp_run_connection(&server, stream, Stats::new()).await
+        });
+
+        let (client, _incoming_rx) = test_client(addr, ClientConfig::default());
+        client.clone().spawn().await.unwrap();
+        connected(&client).await;
+
+        let ping = Packet::PingRequest(PingRequest { ping_id: 0 });
+        match client.send_for_response(ping).await.unwrap() {
+            Packet::PongResponse(p) => assert_ne!(p.ping_id, 0),
+            packet => panic!("unexpected packet {:?}", packet),
+        }
+        assert_eq!(client.pending_requests().await, 0);
+    }
+
+    #[tokio::test]
+    async fn send_for_response_matches_kind_and_times_out() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let config = ClientConfig::default().with_heartbeat(None, 0);
+        let (client, _incoming_rx) = test_client(addr, config);
+        client.clone().spawn().await.unwrap();
+        let mut relay = accept_relay(&listener).await;
+        connected(&client).await;
+
+        let client_c = client.clone();
+        let request = tokio::spawn(async move {
+            client_c
+                .send_for_response_with_timeout(chat_message(0), Duration::from_secs(5))
+                .await
+        });
+        let request_packet = relay.next().await.unwrap().unwrap();
+        let msg_id = request_packet.correlation_id().unwrap();
+        // a pong with the same id doesn't answer a chat message
+        let pong = Packet::PongResponse(PongResponse { ping_id: msg_id });
+        relay.send(pong).await.unwrap();
+        relay.send(reply(msg_id)).await.unwrap();
+        assert_eq!(request.await.unwrap().unwrap(), reply(msg_id));
+
+        let error = client
+            .send_for_response_with_timeout(chat_message(0), Duration::from_millis(20))
+            .await
+            .unwrap_err();
+        assert_eq!(*error.kind(), SendPacketErrorKind::TimeOut);
+        assert_eq!(client.pending_requests().await, 0);
+
+        client.disconnect().await;
+        let error = client.send_for_response(chat_message(0)).await.unwrap_err();
+        assert_eq!(*error.kind(), SendPacketErrorKind::WrongStatus);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn handshake_timeout() {
+        let addr: SocketAddr = "127.0.0.1:0".parse().unwrap();
+        let (client, _incoming_rx) = test_client(addr, ClientConfig::default());
+        // the relay never answers
+        let (mut stream, _relay) = tokio::io::duplex(1024);
+
+        let error = client.handshake(&mut stream).await.unwrap_err();
+        assert_eq!(*error.kind(), SpawnErrorKind::HandshakeTimeout);
+    }
+}
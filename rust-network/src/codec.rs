@@ -3,13 +3,29 @@
 
 use std::io::Error as IoError;
 
-use crate::{stats::Stats, FromBytes, Packet, ToBytes};
-use bytes::{ BytesMut};
+use crate::{compression::Compression, secure::Session, stats::Stats, Packet, Protocol};
+use std::marker::PhantomData;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
 use crate::errors::{PacketError};
 use failure::Fail;
 use nom::{error::ErrorKind, Err};
 use tokio_util::codec::{Decoder, Encoder};
+use tracing::{debug_span, field, trace, Span};
 //https://github.com/lucis-fluxum/utp-rs/blob/1be2589d924ac2053a6f31f20e134bbb77545b69/src/packet.rs
+
+/** Size of the frame header that precedes every serialized `Packet`.
+
+Serialized form:
+Length   | Content
+-------- | ------
+`4`      | Length of the packet in BigEndian
+variable | Serialized `Packet`
+*/
+pub const FRAME_HEADER_SIZE: usize = 4;
+
+/// Maximum length of a single serialized `Packet` inside a frame.
+pub const MAX_FRAME_SIZE: usize = 8 * 1024 * 1024;
+
 /// Error that can happen when decoding `Packet` from bytes
 #[derive(Debug, Fail)]
 pub enum DecodeError {
@@ -27,6 +43,9 @@ pub enum DecodeError {
     /// Error indicates that received encrypted packet can't be decrypted
     #[fail(display = "Decrypt EncryptedPacket error")]
     DecryptError,
+    /// Error indicates that received compressed packet can't be decompressed
+    #[fail(display = "Decompress packet error")]
+    DecompressError,
     /// Error indicates that more data is needed to parse decrypted packet
     #[fail(
         display = "Decrypted packet should not be incomplete, packet: {:?}",
@@ -37,16 +56,31 @@ pub enum DecodeError {
         packet: Vec<u8>,
     },
     /// Error indicates that decrypted packet can't be parsed
-    // #[fail(
-    //     display = "Deserialize decrypted packet error: {:?}, packet: {:?}",
-    //     error, packet
-    // )]
-    // DeserializeDecryptedError {
-    //     /// Parsing error
-    //     error: ErrorKind,
-    //     /// Received packet
-    //     packet: Vec<u8>,
-    // },
+    #[fail(
+        display = "Deserialize decrypted packet error: {:?}, packet: {:?}",
+        error, packet
+    )]
+    DeserializeDecryptedError {
+        /// Parsing error
+        error: ErrorKind,
+        /// Received packet
+        packet: Vec<u8>,
+    },
+    /// Error indicates that frame header announces a packet larger than
+    /// the maximum frame size
+    #[fail(display = "Frame is too large: {} bytes", len)]
+    FrameTooLarge {
+        /// Length announced by the frame header
+        len: usize,
+    },
+    /// Error indicates that received packet kind is not reserved by any packet
+    #[fail(display = "Unknown packet kind: {:#04x}, length: {}", kind, len)]
+    UnknownPacketKind {
+        /// Kind of the received packet
+        kind: u8,
+        /// Length of the received packet
+        len: usize,
+    },
     /// General IO error
     #[fail(display = "IO error: {:?}", error)]
     IoError {
@@ -71,6 +105,15 @@ pub enum EncodeError {
         /// Serialization error
         error: PacketError,
     },
+    /// Error indicates that serialized `Packet` can't be encrypted
+    #[fail(display = "Encrypt Packet error")]
+    EncryptError,
+    /// Error indicates that serialized `Packet` doesn't fit into a frame
+    #[fail(display = "Frame is too large: {} bytes", len)]
+    FrameTooLarge {
+        /// Length of the serialized packet
+        len: usize,
+    },
     /// General IO error
     #[fail(display = "IO error: {:?}", error)]
     IoError {
@@ -87,79 +130,550 @@ impl From<IoError> for EncodeError {
 }
 
 /// implements tokio-io's Decoder and Encoder to deal with Packet
-pub struct Codec {
+pub struct Codec<P = Packet> {
     stats: Stats,
+    /// Secure session used to encrypt and decrypt frames
+    session: Option<Session>,
+    /// Maximum length of a packet inside a frame
+    max_frame_size: usize,
+    /// Compression of frames enabled during the handshake
+    compression: Option<Compression>,
+    phantom: PhantomData<fn() -> P>,
 }
 
-impl Codec {
+impl<P: Protocol> Codec<P> {
     /// create a new Codec with the given Channel
-    pub fn new(stats: Stats) -> Codec {
-        Codec { stats }
+    pub fn new(stats: Stats) -> Codec<P> {
+        Codec {
+            stats,
+            session: None,
+            max_frame_size: MAX_FRAME_SIZE,
+            compression: None,
+            phantom: PhantomData,
+        }
     }
-}
 
-impl Decoder for Codec {
-    type Item = Packet;
-    type Error = DecodeError;
+    /// Encrypt and decrypt every frame with the established secure session
+    pub fn with_session(mut self, session: Option<Session>) -> Codec<P> {
+        self.session = session;
+        self
+    }
 
-    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-        // deserialize EncryptedPacket
+    /// Limit the length of packets to the size negotiated during the handshake
+    pub fn with_max_frame_size(mut self, max_frame_size: usize) -> Codec<P> {
+        self.max_frame_size = max_frame_size.min(MAX_FRAME_SIZE);
+        self
+    }
 
-        // buf.advance(1);
-        println!("bufs {:#?}", hex::encode(&buf));
-        // deserialize Packet
+    /// Prepend the compression flag to every frame and compress large packets
+    pub fn with_compression(mut self, compression: Option<Compression>) -> Codec<P> {
+        self.compression = compression;
+        self
+    }
 
-        if buf.is_empty() {
-            
+    /// Decode one frame from the buffer if it's received completely.
+    fn decode_frame(&mut self, buf: &mut BytesMut) -> Result<Option<P>, DecodeError> {
+        // wait until the frame header is received
+        if buf.len() < FRAME_HEADER_SIZE {
             return Ok(None);
         }
-        match Packet::from_bytes(&buf) {
-            Err(Err::Incomplete(_needed)) => {
-                // println!("{:#?}", needed);
-                Err(DecodeError::IncompleteDecryptedPacket {
-                    packet: buf.to_vec(),
-                })
+
+        let mut header = [0; FRAME_HEADER_SIZE];
+        header.copy_from_slice(&buf[..FRAME_HEADER_SIZE]);
+        let len = u32::from_be_bytes(header) as usize;
+        if len > self.max_frame_size {
+            return Err(DecodeError::FrameTooLarge { len });
+        }
+
+        // wait until the whole frame is received
+        if buf.len() < FRAME_HEADER_SIZE + len {
+            buf.reserve(FRAME_HEADER_SIZE + len - buf.len());
+            return Ok(None);
+        }
+
+        let span = debug_span!(
+            "packet",
+            kind = field::Empty,
+            msg_id = field::Empty,
+            size = len
+        );
+        let _enter = span.enter();
+
+        // consume exactly one frame leaving the rest in the buffer
+        buf.advance(FRAME_HEADER_SIZE);
+        let frame = buf.split_to(len).freeze();
+        let frame = match self.session {
+            Some(ref mut session) => {
+                Bytes::from(session.decrypt(&frame).ok_or(DecodeError::DecryptError)?)
+            }
+            None => frame,
+        };
+        let frame = match self.compression {
+            Some(ref compression) => {
+                let packet = compression
+                    .decompress(&frame, self.max_frame_size)
+                    .ok_or(DecodeError::DecompressError)?;
+                self.stats.counters.add_compressed(packet.len(), frame.len());
+                Bytes::from(packet)
             }
-            Err(Err::Error(_)) => {
-                // let (_, kind) = error;
-                //return Err(DecodeError::DeserializeEncryptedError { error: kind, buf: buf.to_vec() })
-                //println!("1");
-                return Ok(None);
+            None => frame,
+        };
+        trace_packet("received", &frame);
+
+        if let Some(&kind) = frame.first() {
+            if !P::is_known_kind(kind) {
+                return Err(DecodeError::UnknownPacketKind {
+                    kind,
+                    len: frame.len(),
+                });
             }
-            Err(Err::Failure(_)) => {
-                // let (_, kind) = error;
-                //return Err(DecodeError::DeserializeEncryptedError { error: kind, buf: buf.to_vec() })
-                // println!("2");
-                return Ok(None);
+        }
+
+        // deserialize Packet
+        match P::from_frame(&frame) {
+            Err(Err::Incomplete(_)) => Err(DecodeError::IncompleteDecryptedPacket {
+                packet: frame.to_vec(),
+            }),
+            Err(Err::Error(error)) | Err(Err::Failure(error)) => {
+                Err(DecodeError::DeserializeDecryptedError {
+                    error: error.code,
+                    packet: frame.to_vec(),
+                })
             }
             Ok((_i, packet)) => {
-                //println!("buf len {}", buf.len());
+                span.record("msg_id", packet.correlation_id());
                 // Add 1 to incoming counter
                 self.stats.counters.increase_incoming();
+                self.stats
+                    .counters
+                    .add_incoming(kind_of(&frame), FRAME_HEADER_SIZE + len);
 
-                buf.clear();
                 Ok(Some(packet))
             }
         }
     }
+
+    /// Append the frame without its header to the buffer and return the kind
+    /// of the packet. Plain packets are serialized right into `buf`,
+    /// compressed and encrypted ones go through a temporary buffer.
+    fn encode_frame(&mut self, packet: P, buf: &mut BytesMut) -> Result<u8, EncodeError> {
+        if self.compression.is_none() && self.session.is_none() {
+            let start = buf.len();
+            packet
+                .to_bytes(buf)
+                .map_err(|error| EncodeError::SerializeError { error })?;
+            trace_packet("sending", &buf[start..]);
+            return Ok(kind_of(&buf[start..]));
+        }
+
+        let mut plain = BytesMut::new();
+        packet
+            .to_bytes(&mut plain)
+            .map_err(|error| EncodeError::SerializeError { error })?;
+        trace_packet("sending", &plain);
+        let compressed;
+        let mut frame = &plain[..];
+        if let Some(ref compression) = self.compression {
+            compressed = compression.compress(&plain)?;
+            self.stats.counters.add_compressed(plain.len(), compressed.len());
+            frame = &compressed;
+        }
+        match self.session {
+            Some(ref mut session) => {
+                let encrypted = session.encrypt(frame).ok_or(EncodeError::EncryptError)?;
+                buf.extend_from_slice(&encrypted);
+            }
+            None => buf.extend_from_slice(frame),
+        }
+        Ok(kind_of(&plain))
+    }
+}
+
+/// Kind of a serialized packet is its leading byte.
+fn kind_of(packet: &[u8]) -> u8 {
+    packet.first().copied().unwrap_or_default()
+}
+
+/// Record the kind of a serialized packet in the current span. The packet
+/// itself is dumped only at trace level.
+fn trace_packet(message: &str, packet: &[u8]) {
+    Span::current().record("kind", packet.first().copied());
+    trace!(payload = %hex::encode(packet), "{} packet", message);
+}
+
+impl<P: Protocol> Decoder for Codec<P> {
+    type Item = P;
+    type Error = DecodeError;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        let result = self.decode_frame(buf);
+        if result.is_err() {
+            self.stats.counters.increase_decode_errors();
+        }
+        result
+    }
 }
 
-impl Encoder<Packet> for Codec {
+impl<P: Protocol> Encoder<P> for Codec<P> {
     type Error = EncodeError;
 
-    fn encode(&mut self, packet: Packet, buf: &mut BytesMut) -> Result<(), Self::Error> {
+    fn encode(&mut self, packet: P, buf: &mut BytesMut) -> Result<(), Self::Error> {
+        let span = debug_span!(
+            "packet",
+            kind = field::Empty,
+            msg_id = packet.correlation_id(),
+            size = field::Empty
+        );
+        let _enter = span.enter();
+
+        // length is written once the frame is complete
+        let start = buf.len();
+        buf.put_u32(0);
+        let kind = match self.encode_frame(packet, buf) {
+            Ok(kind) => kind,
+            Err(error) => {
+                buf.truncate(start);
+                self.stats.counters.increase_encode_errors();
+                return Err(error);
+            }
+        };
+
+        let len = buf.len() - start - FRAME_HEADER_SIZE;
+        if len > self.max_frame_size {
+            buf.truncate(start);
+            self.stats.counters.increase_encode_errors();
+            return Err(EncodeError::FrameTooLarge { len });
+        }
+        buf[start..start + FRAME_HEADER_SIZE].copy_from_slice(&(len as u32).to_be_bytes());
+        span.record("size", len);
+
         // Add 1 to outgoing counter
         self.stats.counters.increase_outgoing();
+        self.stats
+            .counters
+            .add_outgoing(kind, FRAME_HEADER_SIZE + len);
 
-        // serialize Packet
-        // let mut packet_buf = [0; 2032];
-
-        //let data:Vec<u8>=vec![1,2,3,4,5,66,7];
-        let bufs = packet
-            .to_bytes()
-            .map_err(|error| EncodeError::SerializeError { error })?;
-        println!("send {:#?}", hex::encode(&bufs));
-        buf.extend_from_slice(&bufs);
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::{
+        chatmsg::ChatMessage, packet_kind, ping_request::PingRequest,
+        pong_response::PongResponse, ToBytes,
+        secure::{self, SecureConfig},
+    };
+
+    fn packets() -> Vec<Packet> {
+        vec![
+            Packet::ChatMessage(ChatMessage {
+                msg_id: 42,
+                to_user: "to".to_string(),
+                from_user: "from".to_string(),
+                content: Bytes::from_static(b"hello"),
+            }),
+            Packet::PongResponse(PongResponse { ping_id: 123 }),
+            Packet::ChatMessage(ChatMessage {
+                msg_id: 43,
+                to_user: "".to_string(),
+                from_user: "from".to_string(),
+                content: Bytes::new(),
+            }),
+        ]
+    }
+
+    fn encode_all(codec: &mut Codec, packets: &[Packet]) -> BytesMut {
+        let mut buf = BytesMut::new();
+        for packet in packets {
+            codec.encode(packet.clone(), &mut buf).unwrap();
+        }
+        buf
+    }
+
+    #[test]
+    fn encode_decode() {
+        let mut codec = Codec::<Packet>::new(Stats::new());
+        let packet = Packet::PongResponse(PongResponse { ping_id: 123 });
+        let mut buf = encode_all(&mut codec, std::slice::from_ref(&packet));
+
+        assert_eq!(&buf[..FRAME_HEADER_SIZE], &[0, 0, 0, 9]);
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some(packet));
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn encode_decode_every_kind() {
+        let mut codec = Codec::<Packet>::new(Stats::new());
+        let mut packets = packets();
+        packets.push(Packet::PingRequest(PingRequest { ping_id: 7 }));
+
+        for packet in packets {
+            let mut buf = encode_all(&mut codec, std::slice::from_ref(&packet));
+            assert_eq!(buf[FRAME_HEADER_SIZE], packet.kind());
+            assert_eq!(codec.decode(&mut buf).unwrap(), Some(packet));
+        }
+    }
+
+    #[test]
+    fn decode_unknown_kind() {
+        let mut codec = Codec::<Packet>::new(Stats::new());
+        let mut buf = BytesMut::new();
+        buf.put_u32(9);
+        buf.put_u8(0x7f);
+        buf.put_u64(123);
+        // next frame must stay in the buffer
+        buf.put_u32(0);
+
+        assert!(matches!(
+            codec.decode(&mut buf),
+            Err(DecodeError::UnknownPacketKind { kind: 0x7f, len: 9 })
+        ));
+        assert_eq!(buf.len(), FRAME_HEADER_SIZE);
+    }
+
+    #[test]
+    fn decode_multiple_packets_in_one_buffer() {
+        let mut codec = Codec::<Packet>::new(Stats::new());
+        let packets = packets();
+        let mut buf = encode_all(&mut codec, &packets);
+
+        for packet in packets {
+            assert_eq!(codec.decode(&mut buf).unwrap(), Some(packet));
+        }
+        assert_eq!(codec.decode(&mut buf).unwrap(), None);
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn decode_byte_by_byte() {
+        let mut codec = Codec::<Packet>::new(Stats::new());
+        let packets = packets();
+        let encoded = encode_all(&mut codec, &packets);
+
+        let mut buf = BytesMut::new();
+        let mut decoded = Vec::new();
+        for byte in encoded.iter() {
+            buf.put_u8(*byte);
+            while let Some(packet) = codec.decode(&mut buf).unwrap() {
+                decoded.push(packet);
+            }
+        }
+        assert_eq!(decoded, packets);
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn decode_torn_packet() {
+        let mut codec = Codec::<Packet>::new(Stats::new());
+        let packets = packets();
+        let encoded = encode_all(&mut codec, &packets);
+
+        // split inside of the header of the second frame
+        let mut first = BytesMut::new();
+        packets[0].to_bytes(&mut first).unwrap();
+        let first_len = FRAME_HEADER_SIZE + first.len();
+        let mut buf = BytesMut::from(&encoded[..first_len + 2]);
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some(packets[0].clone()));
+        assert_eq!(codec.decode(&mut buf).unwrap(), None);
+        assert_eq!(buf.len(), 2);
+
+        // split inside of the body of the second frame
+        buf.extend_from_slice(&encoded[first_len + 2..first_len + 7]);
+        assert_eq!(codec.decode(&mut buf).unwrap(), None);
+
+        buf.extend_from_slice(&encoded[first_len + 7..]);
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some(packets[1].clone()));
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some(packets[2].clone()));
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn decode_too_large_frame() {
+        let mut codec = Codec::<Packet>::new(Stats::new());
+        let mut buf = BytesMut::new();
+        buf.put_u32(MAX_FRAME_SIZE as u32 + 1);
+
+        assert!(matches!(
+            codec.decode(&mut buf),
+            Err(DecodeError::FrameTooLarge { len }) if len == MAX_FRAME_SIZE + 1
+        ));
+    }
+
+    #[test]
+    fn negotiated_max_frame_size() {
+        let mut codec = Codec::<Packet>::new(Stats::new()).with_max_frame_size(16);
+        let mut buf = BytesMut::new();
+        assert!(matches!(
+            codec.encode(packets().remove(0), &mut buf),
+            Err(EncodeError::FrameTooLarge { .. })
+        ));
+
+        buf.put_u32(17);
+        assert!(matches!(
+            codec.decode(&mut buf),
+            Err(DecodeError::FrameTooLarge { len: 17 })
+        ));
+    }
+
+    #[test]
+    fn decode_without_copying_content() {
+        let mut codec = Codec::<Packet>::new(Stats::new());
+        let mut buf = encode_all(&mut codec, &packets()[..1]);
+        let frame = buf.as_ptr_range();
+
+        match codec.decode(&mut buf).unwrap() {
+            Some(Packet::ChatMessage(p)) => assert!(frame.contains(&p.content.as_ptr())),
+            packet => panic!("unexpected packet {:?}", packet),
+        }
+    }
+
+    #[test]
+    fn decode_incomplete_packet_in_complete_frame() {
+        let mut codec = Codec::<Packet>::new(Stats::new());
+        let mut buf = BytesMut::new();
+        buf.put_u32(3);
+        buf.extend_from_slice(&[packet_kind::CHAT_MESSAGE, 0, 0]);
+
+        assert!(matches!(
+            codec.decode(&mut buf),
+            Err(DecodeError::IncompleteDecryptedPacket { .. })
+        ));
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn encode_too_large_packet() {
+        let mut codec = Codec::<Packet>::new(Stats::new());
+        let packet = Packet::ChatMessage(ChatMessage {
+            msg_id: 1,
+            to_user: "to".to_string(),
+            from_user: "from".to_string(),
+            content: vec![0; MAX_FRAME_SIZE].into(),
+        });
+        let mut buf = BytesMut::new();
+
+        assert!(matches!(
+            codec.encode(packet, &mut buf),
+            Err(EncodeError::FrameTooLarge { .. })
+        ));
+        assert!(buf.is_empty());
+    }
+
+    async fn sessions() -> (Session, Session) {
+        let (mut client_stream, mut server_stream) = tokio::io::duplex(1024);
+        let server_config = SecureConfig::generate();
+        let server = tokio::spawn(async move {
+            secure::server_handshake(&mut server_stream, &server_config).await
+        });
+        let client = secure::client_handshake(&mut client_stream, &SecureConfig::generate())
+            .await
+            .unwrap();
+        (client, server.await.unwrap().unwrap())
+    }
+
+    #[tokio::test]
+    async fn encode_decode_encrypted() {
+        let (client, server) = sessions().await;
+        let mut client_codec = Codec::<Packet>::new(Stats::new()).with_session(Some(client));
+        let mut server_codec = Codec::<Packet>::new(Stats::new()).with_session(Some(server));
+        let packets = packets();
+        let mut buf = encode_all(&mut client_codec, &packets);
+
+        // payload must not travel in plaintext
+        assert!(!buf.windows(5).any(|w| w == b"hello"));
+
+        for packet in packets {
+            assert_eq!(server_codec.decode(&mut buf).unwrap(), Some(packet));
+        }
+        assert!(buf.is_empty());
+    }
+
+    #[tokio::test]
+    async fn decode_tampered_encrypted() {
+        let (client, server) = sessions().await;
+        let mut client_codec = Codec::<Packet>::new(Stats::new()).with_session(Some(client));
+        let mut server_codec = Codec::<Packet>::new(Stats::new()).with_session(Some(server));
+        let mut buf = encode_all(&mut client_codec, &packets());
+        buf[FRAME_HEADER_SIZE] ^= 1;
+
+        assert!(matches!(
+            server_codec.decode(&mut buf),
+            Err(DecodeError::DecryptError)
+        ));
+    }
+
+    #[tokio::test]
+    async fn encode_decode_compressed_encrypted() {
+        let (client, server) = sessions().await;
+        let stats = Stats::new();
+        let compression = Some(Compression::new(64));
+        let mut client_codec = Codec::<Packet>::new(stats.clone())
+            .with_session(Some(client))
+            .with_compression(compression);
+        let mut server_codec = Codec::<Packet>::new(Stats::new())
+            .with_session(Some(server))
+            .with_compression(compression);
+        let mut packets = packets();
+        packets.push(Packet::ChatMessage(ChatMessage {
+            msg_id: 44,
+            to_user: "to".to_string(),
+            from_user: "from".to_string(),
+            content: b"0a0b0c0d".repeat(512).into(),
+        }));
+        let mut buf = encode_all(&mut client_codec, &packets);
+
+        assert!(stats.counters.compressed_bytes() < stats.counters.raw_bytes() / 4);
+        for packet in packets {
+            assert_eq!(server_codec.decode(&mut buf).unwrap(), Some(packet));
+        }
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn decode_malformed_compressed() {
+        let mut codec =
+            Codec::<Packet>::new(Stats::new()).with_compression(Some(Compression::default()));
+        let mut buf = BytesMut::new();
+        buf.put_u32(2);
+        buf.extend_from_slice(&[0x01, 0xff]);
+
+        assert!(matches!(
+            codec.decode(&mut buf),
+            Err(DecodeError::DecompressError)
+        ));
+    }
+
+    #[test]
+    fn counters() {
+        let stats = Stats::new();
+        let mut codec = Codec::<Packet>::new(stats.clone());
+        let mut buf = encode_all(&mut codec, &packets());
+        while codec.decode(&mut buf).unwrap().is_some() {}
+
+        assert_eq!(stats.counters.outgoing(), 3);
+        assert_eq!(stats.counters.incoming(), 3);
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.incoming_bytes, snapshot.outgoing_bytes);
+        assert_eq!(snapshot.incoming_kinds, snapshot.outgoing_kinds);
+        assert_eq!(snapshot.incoming_kinds[&packet_kind::CHAT_MESSAGE], 2);
+        assert_eq!(snapshot.incoming_kinds[&packet_kind::PONG_RESPONSE], 1);
+    }
+
+    #[test]
+    fn error_counters() {
+        let stats = Stats::new();
+        let mut codec = Codec::<Packet>::new(stats.clone()).with_max_frame_size(16);
+        let mut buf = BytesMut::new();
+        assert!(codec.encode(packets().remove(0), &mut buf).is_err());
+        buf.put_u32(17);
+        assert!(codec.decode(&mut buf).is_err());
+
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.encode_errors, 1);
+        assert_eq!(snapshot.decode_errors, 1);
+        assert_eq!(snapshot.outgoing, 0);
+    }
+}
@@ -0,0 +1,145 @@
+/*! Frame compression
+
+When both peers announce `CAPABILITY_COMPRESSION` during the handshake every
+frame of the connection starts with a flag byte telling whether the rest of
+the frame is compressed:
+
+Length   | Content
+-------- | ------
+`1`      | `0x00` for a raw packet, `0x01` for a deflate compressed packet
+variable | Serialized `Packet`
+
+Packets shorter than the threshold are sent raw since compressing them wastes
+CPU without saving bandwidth. Packets that don't shrink are sent raw as well.
+*/
+
+use std::io::{Error as IoError, Read, Write};
+
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+
+/// Packets of at least this size are compressed by default.
+pub const DEFAULT_COMPRESSION_THRESHOLD: usize = 1024;
+
+/// Flag of a frame carrying a raw packet.
+const FLAG_RAW: u8 = 0x00;
+/// Flag of a frame carrying a deflate compressed packet.
+const FLAG_DEFLATE: u8 = 0x01;
+
+/// Compression settings of one side of the connection.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Compression {
+    /// Minimum size of a serialized packet that gets compressed.
+    threshold: usize,
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Compression::new(DEFAULT_COMPRESSION_THRESHOLD)
+    }
+}
+
+impl Compression {
+    /// Compress packets of at least `threshold` bytes.
+    pub fn new(threshold: usize) -> Compression {
+        Compression { threshold }
+    }
+
+    /// Minimum size of a serialized packet that gets compressed.
+    pub fn threshold(&self) -> usize {
+        self.threshold
+    }
+
+    /// Prepend the flag byte to the serialized packet compressing it if it's
+    /// large enough.
+    pub fn compress(&self, packet: &[u8]) -> Result<Vec<u8>, IoError> {
+        if packet.len() >= self.threshold {
+            let mut encoder =
+                DeflateEncoder::new(vec![FLAG_DEFLATE], flate2::Compression::default());
+            encoder.write_all(packet)?;
+            let compressed = encoder.finish()?;
+            if compressed.len() <= packet.len() {
+                return Ok(compressed);
+            }
+        }
+
+        let mut raw = Vec::with_capacity(1 + packet.len());
+        raw.push(FLAG_RAW);
+        raw.extend_from_slice(packet);
+        Ok(raw)
+    }
+
+    /// Strip the flag byte and decompress the packet. Returns `None` if the
+    /// frame is malformed or the packet is larger than `max_size`.
+    pub fn decompress(&self, frame: &[u8], max_size: usize) -> Option<Vec<u8>> {
+        let (&flag, packet) = frame.split_first()?;
+        match flag {
+            FLAG_RAW => Some(packet.to_vec()),
+            FLAG_DEFLATE => {
+                // don't let a small frame inflate into an unbounded buffer
+                let mut decompressed = Vec::new();
+                DeflateDecoder::new(packet)
+                    .take(max_size as u64 + 1)
+                    .read_to_end(&mut decompressed)
+                    .ok()?;
+                if decompressed.len() > max_size {
+                    return None;
+                }
+                Some(decompressed)
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn small_packet_is_raw() {
+        let compression = Compression::new(16);
+        let frame = compression.compress(b"ping").unwrap();
+
+        assert_eq!(frame, b"\x00ping");
+        assert_eq!(compression.decompress(&frame, 1024).unwrap(), b"ping");
+    }
+
+    #[test]
+    fn large_packet_is_compressed() {
+        let compression = Compression::new(16);
+        let packet = vec![0x2a; 4096];
+        let frame = compression.compress(&packet).unwrap();
+
+        assert_eq!(frame[0], FLAG_DEFLATE);
+        assert!(frame.len() < packet.len());
+        assert_eq!(compression.decompress(&frame, 4096).unwrap(), packet);
+    }
+
+    #[test]
+    fn incompressible_packet_is_raw() {
+        let compression = Compression::new(0);
+        let packet: Vec<u8> = (0..=255).collect();
+        let frame = compression.compress(&packet).unwrap();
+
+        assert_eq!(frame[0], FLAG_RAW);
+        assert_eq!(compression.decompress(&frame, 1024).unwrap(), packet);
+    }
+
+    #[test]
+    fn decompress_too_large() {
+        let compression = Compression::new(0);
+        let frame = compression.compress(&[0; 4096]).unwrap();
+
+        assert!(compression.decompress(&frame, 4095).is_none());
+    }
+
+    #[test]
+    fn decompress_malformed() {
+        let compression = Compression::default();
+
+        assert!(compression.decompress(&[], 1024).is_none());
+        assert!(compression.decompress(&[0x02, 0x2a], 1024).is_none());
+        assert!(compression.decompress(&[FLAG_DEFLATE, 0xff, 0xff], 1024).is_none());
+    }
+}
@@ -0,0 +1,251 @@
+/*! Settings of a `Client`
+
+`ClientConfig` collects everything a client can be tuned with besides its
+relay endpoints and id. It's passed to `Client::from_config` or to
+`Connections::add_client_with_config`, `Connections::with_client_config` sets
+the one used by `Connections::add_client`.
+
+```
+use std::time::Duration;
+use rust_network::config::ClientConfig;
+use rust_network::reconnect::ReconnectPolicy;
+
+let config = ClientConfig::default()
+    .with_channel_capacity(64)
+    .with_request_timeout(Duration::from_secs(30))
+    .with_heartbeat(Some(Duration::from_secs(20)), 2)
+    .with_reconnect_policy(ReconnectPolicy::fixed(Duration::from_secs(5)))
+    .with_plugin_dir("/etc/bot/plugins");
+```
+*/
+
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::compression::Compression;
+use crate::dedup::DedupConfig;
+use crate::endpoint::{Resolver, SystemResolver};
+use crate::outbox::OutboxConfig;
+use crate::reconnect::ReconnectPolicy;
+use crate::secure::SecureConfig;
+use crate::tls::TlsClient;
+use crate::transport::{TcpTransport, Transport};
+
+/// Number of packets buffered for sending before `send_packet` waits.
+pub const DEFAULT_CHANNEL_CAPACITY: usize = 2;
+
+/// Time given to a single relay address to accept the connection.
+pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Time given to the relay to complete the handshake.
+pub const DEFAULT_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Time given to the relay to respond to `send_for_response`.
+pub const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Time given to queued packets to be sent when the connection is closed.
+pub const DEFAULT_CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Interval of time between pings sent to the relay.
+pub const DEFAULT_PING_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Number of pings in a row the relay may leave unanswered before the
+/// connection is considered dead.
+pub const DEFAULT_MAX_MISSED_PONGS: u32 = 3;
+
+/// Directory Lua plugins are loaded from.
+pub const DEFAULT_PLUGIN_DIR: &str = "./Plugins";
+
+/// Settings of a client.
+#[derive(Debug, Clone)]
+pub struct ClientConfig {
+    pub(crate) channel_capacity: usize,
+    pub(crate) connect_timeout: Duration,
+    pub(crate) handshake_timeout: Duration,
+    pub(crate) request_timeout: Duration,
+    pub(crate) close_timeout: Duration,
+    /// Pings are not sent when `None`.
+    pub(crate) ping_interval: Option<Duration>,
+    pub(crate) max_missed_pongs: u32,
+    pub(crate) reconnect: ReconnectPolicy,
+    pub(crate) plugin_dir: PathBuf,
+    pub(crate) transport: Arc<dyn Transport>,
+    pub(crate) resolver: Arc<dyn Resolver>,
+    /// The stream opened by the transport is used as is when `None`.
+    pub(crate) tls: Option<TlsClient>,
+    /// Plaintext connection is used when `None`.
+    pub(crate) secure: Option<Arc<SecureConfig>>,
+    pub(crate) compression: Option<Compression>,
+    pub(crate) outbox: Option<OutboxConfig>,
+    pub(crate) dedup: Option<DedupConfig>,
+}
+
+impl Default for ClientConfig {
+    /// Plaintext TCP with the default timeouts, heartbeat and reconnect
+    /// policy, without an outbox and a dedup window.
+    fn default() -> Self {
+        ClientConfig {
+            channel_capacity: DEFAULT_CHANNEL_CAPACITY,
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            handshake_timeout: DEFAULT_HANDSHAKE_TIMEOUT,
+            request_timeout: DEFAULT_REQUEST_TIMEOUT,
+            close_timeout: DEFAULT_CLOSE_TIMEOUT,
+            ping_interval: Some(DEFAULT_PING_INTERVAL),
+            max_missed_pongs: DEFAULT_MAX_MISSED_PONGS,
+            reconnect: ReconnectPolicy::default(),
+            plugin_dir: PathBuf::from(DEFAULT_PLUGIN_DIR),
+            transport: Arc::new(TcpTransport),
+            resolver: Arc::new(SystemResolver),
+            tls: None,
+            secure: None,
+            compression: None,
+            outbox: None,
+            dedup: None,
+        }
+    }
+}
+
+impl ClientConfig {
+    /// Buffer up to `capacity` packets for sending before `send_packet`
+    /// waits for the connection to catch up.
+    pub fn with_channel_capacity(mut self, capacity: usize) -> ClientConfig {
+        self.channel_capacity = capacity;
+        self
+    }
+
+    /// Give every address of the relay `timeout` to accept the connection
+    /// before the next one is tried.
+    pub fn with_connect_timeout(mut self, timeout: Duration) -> ClientConfig {
+        self.connect_timeout = timeout;
+        self
+    }
+
+    /// Give the relay `timeout` to complete the TLS and protocol handshakes.
+    pub fn with_handshake_timeout(mut self, timeout: Duration) -> ClientConfig {
+        self.handshake_timeout = timeout;
+        self
+    }
+
+    /// Give the relay `timeout` to respond to `send_for_response`.
+    pub fn with_request_timeout(mut self, timeout: Duration) -> ClientConfig {
+        self.request_timeout = timeout;
+        self
+    }
+
+    /// Give queued packets `timeout` to be sent when the connection is
+    /// closed by `disconnect` or `sleep`.
+    pub fn with_close_timeout(mut self, timeout: Duration) -> ClientConfig {
+        self.close_timeout = timeout;
+        self
+    }
+
+    /// Ping the relay every `interval` and drop the connection when
+    /// `max_missed_pongs` pings in a row are left unanswered. Pings are
+    /// disabled with `None`.
+    pub fn with_heartbeat(
+        mut self,
+        interval: Option<Duration>,
+        max_missed_pongs: u32,
+    ) -> ClientConfig {
+        self.ping_interval = interval;
+        self.max_missed_pongs = max_missed_pongs;
+        self
+    }
+
+    /// Reconnect after failed attempts according to the given policy.
+    pub fn with_reconnect_policy(mut self, policy: ReconnectPolicy) -> ClientConfig {
+        self.reconnect = policy;
+        self
+    }
+
+    /// Load Lua plugins from the given directory.
+    pub fn with_plugin_dir<T: Into<PathBuf>>(mut self, dir: T) -> ClientConfig {
+        self.plugin_dir = dir.into();
+        self
+    }
+
+    /// Connect to the relay with the given transport instead of TCP.
+    pub fn with_transport(mut self, transport: Arc<dyn Transport>) -> ClientConfig {
+        self.transport = transport;
+        self
+    }
+
+    /// Resolve hostnames of endpoints with the given resolver instead of the
+    /// system one.
+    pub fn with_resolver(mut self, resolver: Arc<dyn Resolver>) -> ClientConfig {
+        self.resolver = resolver;
+        self
+    }
+
+    /// Wrap the stream opened by the transport with TLS.
+    pub fn with_tls(mut self, tls: TlsClient) -> ClientConfig {
+        self.tls = Some(tls);
+        self
+    }
+
+    /// Encrypt the connection with a secure session established using the
+    /// given keys.
+    pub fn with_secure(mut self, config: SecureConfig) -> ClientConfig {
+        self.secure = Some(Arc::new(config));
+        self
+    }
+
+    /// Compress large packets sent to the relay if it supports compression.
+    pub fn with_compression(mut self, compression: Compression) -> ClientConfig {
+        self.compression = Some(compression);
+        self
+    }
+
+    /// Queue packets sent while the client is disconnected.
+    pub fn with_outbox(mut self, outbox: OutboxConfig) -> ClientConfig {
+        self.outbox = Some(outbox);
+        self
+    }
+
+    /// Drop or flag packets received again within the given window.
+    pub fn with_dedup(mut self, dedup: DedupConfig) -> ClientConfig {
+        self.dedup = Some(dedup);
+        self
+    }
+
+    /// Number of packets buffered for sending.
+    pub fn channel_capacity(&self) -> usize {
+        self.channel_capacity
+    }
+
+    /// Time given to the relay to complete the handshakes.
+    pub fn handshake_timeout(&self) -> Duration {
+        self.handshake_timeout
+    }
+
+    /// Time given to the relay to respond to `send_for_response`.
+    pub fn request_timeout(&self) -> Duration {
+        self.request_timeout
+    }
+
+    /// Time given to queued packets when the connection is closed.
+    pub fn close_timeout(&self) -> Duration {
+        self.close_timeout
+    }
+
+    /// Interval of pings and the number of pings that may be missed.
+    pub fn heartbeat(&self) -> (Option<Duration>, u32) {
+        (self.ping_interval, self.max_missed_pongs)
+    }
+
+    /// Reconnect policy of the client.
+    pub fn reconnect_policy(&self) -> &ReconnectPolicy {
+        &self.reconnect
+    }
+
+    /// Directory Lua plugins are loaded from.
+    pub fn plugin_dir(&self) -> &Path {
+        &self.plugin_dir
+    }
+
+    /// Transport opening connections to the relay.
+    pub fn transport(&self) -> &Arc<dyn Transport> {
+        &self.transport
+    }
+}
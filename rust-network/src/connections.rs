@@ -1,33 +1,72 @@
 use crate::client::Client;
-use crate::{errors::*, Packet};
+use crate::config::ClientConfig;
+use crate::endpoint::Target;
+use crate::metrics::{self, Metrics};
+use crate::reconnect::ReconnectPolicy;
+use crate::state::{self, StateEvent};
+use crate::stats::Stats;
+use crate::transport::Transport;
+use crate::{errors::*, Packet, Protocol};
 use failure::Fail;
 use futures::channel::mpsc;
-use futures::TryFutureExt;
+use futures::{FutureExt, TryFutureExt};
 use rand_core::{OsRng, RngCore};
 use std::collections::{hash_map, HashMap};
 use std::net::SocketAddr;
 use std::sync::Arc;
 use std::time::Duration;
-use tokio::sync::RwLock;
+use tokio::sync::{broadcast, RwLock};
+use tracing::{info, trace};
+
+/// Interval of time between runs of the main loop of `Connections::run`.
+pub const DEFAULT_TICK: Duration = Duration::from_secs(1);
+
+/// Decides which disconnected clients are removed by `Connections::run`.
+/// Clients that aren't removed keep reconnecting according to their
+/// `ReconnectPolicy`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum EvictionPolicy {
+    /// Keep every client until it's removed with `remove_client`.
+    #[default]
+    Never,
+    /// Remove clients that gave up reconnecting.
+    GaveUp,
+    /// Remove clients that failed more than `attempts` times in a row while
+    /// another client is connected, i.e. the network is fine but the relay
+    /// is unreachable.
+    Unreachable {
+        /// Number of failed attempts tolerated.
+        attempts: u32,
+    },
+}
 // TCP connections provides reliable connection to a friend via multiple TCP
 /// relays.
 #[derive(Clone)]
-pub struct Connections {
+pub struct Connections<P = Packet> {
     /// belongs to TCP relay we received packet from.
-    incoming_tx: mpsc::UnboundedSender<(Client, Packet)>,
+    incoming_tx: mpsc::UnboundedSender<(Client<P>, P, bool)>,
     /// List of TCP relays we are connected to. Key is a `Clientid` of TCP
     /// relay.
-    pub clients: Arc<RwLock<HashMap<String, Client>>>,
+    pub clients: Arc<RwLock<HashMap<String, Client<P>>>>,
+    /// Counters of all clients
+    stats: Stats,
+    /// Address of the Prometheus metrics endpoint started by `run`.
+    metrics_addr: Option<SocketAddr>,
+    /// Settings of clients created by `new_client` and `add_client`.
+    config: ClientConfig,
+    /// Transport set by `with_transport`, kept by `with_client_config`.
+    transport: Option<Arc<dyn Transport>>,
+    /// Policy set by `with_reconnect_policy`, kept by `with_client_config`.
+    reconnect: Option<ReconnectPolicy>,
+    /// Interval of time between runs of the main loop.
+    tick: Duration,
+    /// Decides which disconnected clients are removed.
+    eviction: EvictionPolicy,
+    /// Changes of the connection state of all clients.
+    events: broadcast::Sender<StateEvent>,
 }
 
 impl Connections {
-    /// Create new TCP connections object.
-    pub fn new(incoming_tx: mpsc::UnboundedSender<(Client, Packet)>) -> Self {
-        Connections {
-            incoming_tx,
-            clients: Arc::new(RwLock::new(HashMap::new())),
-        }
-    }
     pub fn gen_random_string(n: usize) -> String {
         let alphabet = b"abcdefghijklmnopqrstuvwxyz0123456789";
         let mut result = String::new();
@@ -37,6 +76,147 @@ impl Connections {
         }
         result
     }
+}
+
+impl<P: Protocol> Connections<P> {
+    /// Create new TCP connections object.
+    pub fn new(incoming_tx: mpsc::UnboundedSender<(Client<P>, P, bool)>) -> Self {
+        Connections {
+            incoming_tx,
+            clients: Arc::new(RwLock::new(HashMap::new())),
+            stats: Stats::new(),
+            metrics_addr: None,
+            config: ClientConfig::default(),
+            transport: None,
+            reconnect: None,
+            tick: DEFAULT_TICK,
+            eviction: EvictionPolicy::default(),
+            events: state::channel(),
+        }
+    }
+
+    /// Connect clients added after this call to relays with the given
+    /// transport instead of TCP. Takes precedence over the transport of
+    /// `with_client_config` whichever is called first.
+    pub fn with_transport(mut self, transport: Arc<dyn Transport>) -> Self {
+        self.config = self.config.with_transport(transport.clone());
+        self.transport = Some(transport);
+        self
+    }
+
+    /// Reconnect clients added after this call according to the given
+    /// policy. Takes precedence over the policy of `with_client_config`
+    /// whichever is called first.
+    pub fn with_reconnect_policy(mut self, policy: ReconnectPolicy) -> Self {
+        self.config = self.config.with_reconnect_policy(policy.clone());
+        self.reconnect = Some(policy);
+        self
+    }
+
+    /// Create clients added after this call with the given settings. A
+    /// transport and a reconnect policy set by `with_transport` and
+    /// `with_reconnect_policy` are kept.
+    pub fn with_client_config(mut self, mut config: ClientConfig) -> Self {
+        if let Some(ref transport) = self.transport {
+            config = config.with_transport(transport.clone());
+        }
+        if let Some(ref policy) = self.reconnect {
+            config = config.with_reconnect_policy(policy.clone());
+        }
+        self.config = config;
+        self
+    }
+
+    /// Reconnect and evict clients every `tick` instead of every second.
+    pub fn with_tick(mut self, tick: Duration) -> Self {
+        self.tick = tick;
+        self
+    }
+
+    /// Remove disconnected clients according to the given policy. No client
+    /// is removed by default.
+    pub fn with_eviction(mut self, eviction: EvictionPolicy) -> Self {
+        self.eviction = eviction;
+        self
+    }
+
+    /// Serve Prometheus metrics on the given address while `run` is running.
+    pub fn with_metrics(mut self, addr: SocketAddr) -> Self {
+        self.metrics_addr = Some(addr);
+        self
+    }
+
+    /// Counters aggregated over all clients. Counters of a single client are
+    /// available via `Client::stats`.
+    pub fn stats(&self) -> &Stats {
+        &self.stats
+    }
+
+    /// Subscribe to changes of the connection state of every client created
+    /// by `new_client` or `add_client`.
+    pub fn subscribe(&self) -> broadcast::Receiver<StateEvent> {
+        self.events.subscribe()
+    }
+
+    /// Metrics exported by the Prometheus endpoint.
+    pub async fn metrics(&self) -> Metrics {
+        let mut connected_clients = 0;
+        let mut connection_attempts = 0;
+        let mut pending_requests = 0;
+        for client in self.clients.read().await.values() {
+            if client.is_connected().await {
+                connected_clients += 1;
+            }
+            connection_attempts += u64::from(client.connection_attempts().await);
+            pending_requests += client.pending_requests().await;
+        }
+
+        Metrics {
+            stats: self.stats.snapshot(),
+            connected_clients,
+            connection_attempts: Some(connection_attempts),
+            pending_requests: Some(pending_requests),
+        }
+    }
+
+    /// Create a client for the relay that reports received packets to these
+    /// connections. The client can be configured before it's added with
+    /// `insert_client`.
+    pub fn new_client(&self, id: String, relay_addr: SocketAddr) -> Client<P> {
+        self.new_client_with_config(id, relay_addr, self.config.clone())
+    }
+
+    /// Create a client like `new_client` but with the given settings. The
+    /// relay may be given by a `Target` with several endpoints.
+    pub fn new_client_with_config<T: Into<Target>>(
+        &self,
+        id: String,
+        relay: T,
+        config: ClientConfig,
+    ) -> Client<P> {
+        Client::from_target(
+            relay.into(),
+            Arc::new(RwLock::new(id)),
+            self.incoming_tx.clone(),
+            config,
+        )
+        .with_stats(self.stats.child())
+        .with_events(self.events.clone())
+    }
+
+    /// Add a configured client created by `new_client`.
+    pub async fn insert_client(&self, client: Client<P>) -> Result<(), ConnectionError> {
+        let id = client.client_id.read().await.clone();
+        if let hash_map::Entry::Vacant(vacant) = self.clients.write().await.entry(id) {
+            vacant.insert(client.clone());
+            client
+                .spawn()
+                .map_err(|e| e.context(ConnectionErrorKind::Spawn).into())
+                .await
+        } else {
+            Ok(())
+        }
+    }
 
     /// Add relay we are supposed to be connected to. These relays are necessary
     /// for initial connection so that we are able to find friends and to send
@@ -47,27 +227,44 @@ impl Connections {
         id: String,
         relay_addr: SocketAddr,
     ) -> Result<(), ConnectionError> {
+        self.add_client_with_config(id, relay_addr, self.config.clone())
+            .await
+    }
+
+    /// Add relay like `add_client` but connect to it with the given
+    /// settings. The relay may be given by a `Target` with several endpoints.
+    pub async fn add_client_with_config<T: Into<Target>>(
+        &self,
+        id: String,
+        relay: T,
+        config: ClientConfig,
+    ) -> Result<(), ConnectionError> {
+        let relay = relay.into();
         if let hash_map::Entry::Vacant(vacant) = self.clients.write().await.entry(id.clone()) {
-            let client = Client::new(
-                relay_addr,
-                Arc::new(RwLock::new(id)),
-                self.incoming_tx.clone(),
-            );
+            let client = self.new_client_with_config(id, relay, config);
             vacant.insert(client.clone());
             client
                 .spawn()
                 .map_err(|e| e.context(ConnectionErrorKind::Spawn).into())
                 .await
         } else {
-            //trace!("Attempt to add relay that already exists: {}", relay_addr);
+            trace!("Attempt to add relay that already exists: {}", relay);
             Ok(())
         }
     }
+
+    /// Remove the client with the given id and drop its connection.
+    pub async fn remove_client(&self, id: &str) -> Option<Client<P>> {
+        let client = self.clients.write().await.remove(id)?;
+        client.disconnect().await;
+        Some(client)
+    }
+
     /// Add a connection to our friend via relay. It means that we will send
     /// `RouteRequest` packet to this relay and wait for the friend to become
     /// connected.
     /// Send `Data` packet to a node via one of the relays.
-    pub async fn send_data(&self, packet: Packet) -> Result<(), ConnectionError> {
+    pub async fn send_data(&self, packet: P) -> Result<(), ConnectionError> {
         // send packet to the first relay only that can accept it
         // errors are ignored
         // TODO: return error if stream is exhausted?
@@ -86,9 +283,9 @@ impl Connections {
         Ok(())
     }
 
-    /// Main loop that should be run periodically. It removes unreachable and
-    /// redundant relays, reconnects to relays if a connection was lost, puts
-    /// relays to sleep if they are not used right now.
+    /// Main loop that should be run periodically. It reconnects to relays if
+    /// a connection was lost once their reconnect delay has passed and
+    /// removes relays according to the `EvictionPolicy`.
     async fn main_loop(&self) -> Result<(), ConnectionError> {
         let mut clients = self.clients.write().await;
         // let mut connections = self.connections.write().await;
@@ -107,21 +304,31 @@ impl Connections {
         let mut to_remove = Vec::new();
 
         for (pk, client) in clients.iter_mut() {
-            if client.is_disconnected().await {
-                if connected && client.connection_attempts().await > 1 {
-                    //重连
-                    to_remove.push(pk.clone());
-                } else {
-                    client
-                        .clone()
-                        .spawn()
-                        .await
-                        .map_err(|e| e.context(ConnectionErrorKind::Spawn))?;
+            if !client.is_disconnected().await {
+                continue;
+            }
+
+            let evict = match self.eviction {
+                EvictionPolicy::Never => false,
+                EvictionPolicy::GaveUp => client.gave_up().await,
+                EvictionPolicy::Unreachable { attempts } => {
+                    connected && client.connection_attempts().await > attempts
                 }
+            };
+            if evict {
+                to_remove.push(pk.clone());
+            } else if client.reconnect_due().await {
+                //重连
+                client
+                    .clone()
+                    .spawn()
+                    .await
+                    .map_err(|e| e.context(ConnectionErrorKind::Spawn))?;
             }
         }
 
         for id in to_remove {
+            info!(client_id = %id, "Evicting relay");
             clients.remove(&id);
         }
 
@@ -130,27 +337,66 @@ impl Connections {
     /// Run TCP periodical tasks. Result future will never be completed
     /// successfully.
     pub async fn run(&self) -> Result<(), ConnectionError> {
-        let mut wakeups = tokio::time::interval(Duration::from_secs(1));
+        let wakeups_future = async {
+            let mut wakeups = tokio::time::interval(self.tick);
 
-        loop {
-            wakeups.tick().await;
+            loop {
+                wakeups.tick().await;
+
+                self.main_loop().await?
+            }
+        };
+
+        let metrics_future = async {
+            match self.metrics_addr {
+                Some(addr) => metrics::serve::<P, _, _>(addr, || self.metrics())
+                    .await
+                    .map_err(|e| e.context(ConnectionErrorKind::Metrics).into()),
+                None => futures::future::pending().await,
+            }
+        };
 
-            self.main_loop().await?
+        futures::select! {
+            res = wakeups_future.fuse() => res,
+            res = metrics_future.fuse() => res,
         }
     }
 }
 #[cfg(test)]
 mod tests {
 
+    use std::net::SocketAddr;
     use std::time::Duration;
 
+    use futures::channel::mpsc;
+    use futures::{FutureExt, StreamExt};
+    use tokio::{net::TcpListener, time::sleep};
+
+    use std::sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    };
+
+    use super::{Connections, EvictionPolicy};
+    use crate::codec::MAX_FRAME_SIZE;
+    use crate::config::{ClientConfig, DEFAULT_REQUEST_TIMEOUT};
+    use crate::errors::SpawnErrorKind;
+    use crate::handshake::{self, Capabilities};
     use crate::ping_request::PingRequest;
     use crate::pong_response::PongResponse;
+    use crate::reconnect::{Backoff, ReconnectPolicy};
+    use crate::state::ClientState;
+    use crate::websocket::{WebSocketConfig, WebSocketTransport};
     use crate::Packet;
-    use futures::{channel::mpsc, StreamExt};
-    use tokio::time::sleep;
 
-    use super::Connections;
+    /// Address nobody listens on once the listener is dropped.
+    async fn unreachable_addr() -> std::net::SocketAddr {
+        TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap()
+    }
 
     //#[tokio::test]
     #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
@@ -160,7 +406,7 @@ mod tests {
 
         for _ in 0..1 {
             //let &mut conn = &mut connections;
-            let id = Connections::gen_random_string(16);
+            let id = Connections::<Packet>::gen_random_string(16);
             connections
                 .add_client(id, "192.168.198.120:8080".parse().unwrap())
                 .await
@@ -170,18 +416,18 @@ mod tests {
         let on_receive = async {
             while let Some(packet) = incoming_rx.next().await {
                 match packet {
-                    (c, Packet::PingRequest(pkg)) => {
+                    (c, Packet::PingRequest(pkg), duplicate) => {
                         if let Ok(res) = c.send_for_response(Packet::PingRequest(pkg.clone())).await
                         {
                             println!("rcv pkg conn  {:#?} {:#?}", c.client_id.read().await, res);
                         }
 
-                        c.spawn_lua(Packet::PingRequest(pkg)).unwrap();
+                        c.spawn_lua(Packet::PingRequest(pkg), duplicate).unwrap();
                     }
-                    (_, Packet::PongResponse(pkg)) => {
+                    (_, Packet::PongResponse(pkg), _) => {
                         println!("rcv pkg  {:#?}", pkg)
                     }
-                    (_, Packet::ChatMessage(pkg)) => {
+                    (_, Packet::ChatMessage(pkg), _) => {
                         println!("rcv pkg  {:#?}", pkg)
                     }
                 }
@@ -210,4 +456,190 @@ mod tests {
             }
         };
     }
+
+    #[tokio::test]
+    async fn main_loop_evicts_unreachable() {
+        let (incoming_tx, _incoming_rx) = mpsc::unbounded();
+        let connections = Connections::<Packet>::new(incoming_tx)
+            .with_reconnect_policy(ReconnectPolicy::fixed(Duration::from_millis(10)))
+            .with_eviction(EvictionPolicy::Unreachable { attempts: 1 });
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let reachable = listener.local_addr().unwrap();
+        let unreachable = unreachable_addr().await;
+
+        connections
+            .add_client("reachable".to_string(), reachable)
+            .await
+            .unwrap();
+        connections
+            .add_client("unreachable".to_string(), unreachable)
+            .await
+            .unwrap();
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let capabilities = Capabilities {
+            supported: 0,
+            required: 0,
+            max_frame_size: MAX_FRAME_SIZE,
+        };
+        handshake::server_hello(&mut stream, capabilities)
+            .await
+            .unwrap();
+
+        for _ in 0..100 {
+            sleep(Duration::from_millis(20)).await;
+            connections.main_loop().await.unwrap();
+            if !connections.clients.read().await.contains_key("unreachable") {
+                break;
+            }
+        }
+
+        let clients = connections.clients.read().await;
+        assert!(clients.contains_key("reachable"));
+        assert!(!clients.contains_key("unreachable"));
+        assert_eq!(clients["reachable"].stats().snapshot().connects, 1);
+        assert_eq!(connections.stats().snapshot().connects, 1);
+        drop(clients);
+
+        let metrics = connections.metrics().await;
+        assert_eq!(metrics.connected_clients, 1);
+        assert_eq!(metrics.connection_attempts, Some(0));
+        assert_eq!(metrics.pending_requests, Some(0));
+    }
+
+    #[tokio::test]
+    async fn add_client_with_config() {
+        let (incoming_tx, _incoming_rx) = mpsc::unbounded();
+        let connections =
+            Connections::<Packet>::new(incoming_tx).with_tick(Duration::from_millis(10));
+        let config = ClientConfig::default()
+            .with_reconnect_policy(
+                ReconnectPolicy::fixed(Duration::from_millis(10)).with_max_attempts(2),
+            )
+            .with_request_timeout(Duration::from_millis(50));
+        let addr = unreachable_addr().await;
+        connections
+            .add_client_with_config("custom".to_string(), addr, config)
+            .await
+            .unwrap();
+        connections
+            .add_client("default".to_string(), addr)
+            .await
+            .unwrap();
+        let clients = connections.clients.read().await.clone();
+
+        let gave_up = async {
+            while !clients["custom"].gave_up().await {
+                sleep(Duration::from_millis(10)).await;
+            }
+        };
+        futures::select! {
+            _ = connections.run().fuse() => unreachable!(),
+            _ = gave_up.fuse() => {}
+        }
+
+        assert_eq!(clients["custom"].connection_attempts().await, 2);
+        assert!(!clients["default"].gave_up().await);
+        let request_timeout = |id: &str| clients[id].config().request_timeout();
+        assert_eq!(request_timeout("custom"), Duration::from_millis(50));
+        assert_eq!(request_timeout("default"), DEFAULT_REQUEST_TIMEOUT);
+    }
+
+    #[test]
+    fn client_config_precedence() {
+        let (incoming_tx, _incoming_rx) = mpsc::unbounded();
+        let config = ClientConfig::default()
+            .with_reconnect_policy(ReconnectPolicy::fixed(Duration::from_secs(1)))
+            .with_request_timeout(Duration::from_millis(50));
+        let policy = ReconnectPolicy::fixed(Duration::from_millis(10));
+        let transport = WebSocketTransport::new(WebSocketConfig::default());
+        let connections = Connections::<Packet>::new(incoming_tx)
+            .with_transport(Arc::new(transport))
+            .with_client_config(config)
+            .with_reconnect_policy(policy);
+        let addr = "127.0.0.1:0".parse::<SocketAddr>().unwrap();
+
+        // settings of the shorthands are kept, the rest comes from the config
+        let client = connections.new_client("relay".to_string(), addr);
+        let config = client.config();
+        let backoff = Backoff::Fixed(Duration::from_millis(10));
+        assert_eq!(config.reconnect_policy().backoff(), backoff);
+        assert_eq!(config.request_timeout(), Duration::from_millis(50));
+        let transport = format!("{:?}", config.transport());
+        assert!(transport.starts_with("WebSocketTransport"), "{}", transport);
+    }
+
+    #[tokio::test]
+    async fn main_loop_backoff_and_give_up() {
+        let given_up = Arc::new(AtomicU32::new(0));
+        let given_up_c = given_up.clone();
+        let policy = ReconnectPolicy::fixed(Duration::from_millis(50))
+            .with_max_attempts(3)
+            .with_give_up(move |id, attempts| {
+                assert_eq!(id, "unreachable");
+                given_up_c.store(attempts, Ordering::SeqCst);
+            });
+        let (incoming_tx, _incoming_rx) = mpsc::unbounded();
+        let connections = Connections::<Packet>::new(incoming_tx).with_reconnect_policy(policy);
+        connections
+            .add_client("unreachable".to_string(), unreachable_addr().await)
+            .await
+            .unwrap();
+        let client = connections.clients.read().await["unreachable"].clone();
+
+        // the client is respawned only after the delay
+        while client.connection_attempts().await == 0 {
+            sleep(Duration::from_millis(5)).await;
+        }
+        assert!(client.is_disconnected().await);
+        assert!(!client.reconnect_due().await);
+        connections.main_loop().await.unwrap();
+        assert!(client.is_disconnected().await);
+        assert_eq!(client.connection_attempts().await, 1);
+
+        for _ in 0..100 {
+            sleep(Duration::from_millis(10)).await;
+            connections.main_loop().await.unwrap();
+            if client.gave_up().await {
+                break;
+            }
+        }
+        assert_eq!(client.connection_attempts().await, 3);
+        assert_eq!(given_up.load(Ordering::SeqCst), 3);
+
+        // clients that gave up are kept unless evicted explicitly
+        sleep(Duration::from_millis(100)).await;
+        connections.main_loop().await.unwrap();
+        assert_eq!(client.connection_attempts().await, 3);
+        assert!(connections.clients.read().await.contains_key("unreachable"));
+
+        let connections = connections.with_eviction(EvictionPolicy::GaveUp);
+        connections.main_loop().await.unwrap();
+        assert!(connections.clients.read().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn subscribe_all_clients() {
+        let (incoming_tx, _incoming_rx) = mpsc::unbounded();
+        let connections = Connections::<Packet>::new(incoming_tx);
+        let mut events = connections.subscribe();
+
+        for id in ["first", "second"].iter() {
+            connections
+                .add_client(id.to_string(), unreachable_addr().await)
+                .await
+                .unwrap();
+        }
+
+        let mut disconnected = Vec::new();
+        while disconnected.len() < 2 {
+            let event = events.recv().await.unwrap();
+            if let ClientState::Disconnected { error } = event.state {
+                assert_eq!(error.unwrap().kind, SpawnErrorKind::Io);
+                disconnected.push(event.client_id);
+            }
+        }
+        disconnected.sort();
+        assert_eq!(disconnected, vec!["first", "second"]);
+    }
 }
@@ -0,0 +1,176 @@
+/*! Suppression of packets received more than once
+
+Retries of a relay and packets resent by a journal after a reconnect may
+deliver the same packet twice. A `Client` with a dedup window remembers
+`Protocol::dedup_key` of received packets and drops repeats before they
+reach the incoming channel and Lua plugins, or only flags them. The flag is
+decided when a packet arrives and travels with it through the incoming
+channel.
+
+The window is bounded both by the number of remembered keys and by their age
+so a packet repeated after it fell out of the window is delivered again.
+*/
+
+use std::collections::{HashSet, VecDeque};
+use std::time::{Duration, Instant};
+
+/// Whose packets share ids.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DedupScope {
+    /// Ids are unique across all packets received by the client.
+    Client,
+    /// Ids are unique per sender, e.g. `from_user` of a `ChatMessage`.
+    Sender,
+}
+
+/// What to do with a repeated packet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DuplicateAction {
+    /// Drop the packet.
+    Drop,
+    /// Deliver the packet flagged as a duplicate in the incoming channel so
+    /// that Lua plugins get `IsDuplicate` set.
+    Flag,
+}
+
+/// Settings of the dedup window of a client.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DedupConfig {
+    capacity: usize,
+    max_age: Duration,
+    scope: DedupScope,
+    action: DuplicateAction,
+}
+
+impl DedupConfig {
+    /// Remember up to `capacity` keys for up to `max_age`. Ids are unique
+    /// per sender and repeats are dropped.
+    pub fn new(capacity: usize, max_age: Duration) -> DedupConfig {
+        DedupConfig {
+            capacity,
+            max_age,
+            scope: DedupScope::Sender,
+            action: DuplicateAction::Drop,
+        }
+    }
+
+    /// Tell ids of packets apart according to the given scope.
+    pub fn with_scope(mut self, scope: DedupScope) -> DedupConfig {
+        self.scope = scope;
+        self
+    }
+
+    /// Handle repeated packets with the given action.
+    pub fn with_action(mut self, action: DuplicateAction) -> DedupConfig {
+        self.action = action;
+        self
+    }
+}
+
+type Key = (Option<String>, u64);
+
+/// Keys of packets received recently.
+#[derive(Debug)]
+pub(crate) struct DedupWindow {
+    config: DedupConfig,
+    /// Keys in the window.
+    seen: HashSet<Key>,
+    /// Keys in the order they were first received.
+    order: VecDeque<(Key, Instant)>,
+}
+
+impl DedupWindow {
+    pub fn new(config: DedupConfig) -> DedupWindow {
+        DedupWindow {
+            config,
+            seen: HashSet::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    /// Remember a packet received at `now`. Returns the action to take if
+    /// it's a repeat.
+    pub fn check(&mut self, sender: &str, id: u64, now: Instant) -> Option<DuplicateAction> {
+        self.remove_expired(now);
+        let key = self.key(sender, id);
+        if self.seen.contains(&key) {
+            return Some(self.config.action);
+        }
+        if self.config.capacity == 0 {
+            return None;
+        }
+        if self.order.len() >= self.config.capacity {
+            if let Some((oldest, _)) = self.order.pop_front() {
+                self.seen.remove(&oldest);
+            }
+        }
+        self.seen.insert(key.clone());
+        self.order.push_back((key, now));
+        None
+    }
+
+    fn key(&self, sender: &str, id: u64) -> Key {
+        match self.config.scope {
+            DedupScope::Client => (None, id),
+            DedupScope::Sender => (Some(sender.to_string()), id),
+        }
+    }
+
+    fn remove_expired(&mut self, now: Instant) {
+        while let Some(&(_, first)) = self.order.front() {
+            if now.saturating_duration_since(first) < self.config.max_age {
+                break;
+            }
+            if let Some((key, _)) = self.order.pop_front() {
+                self.seen.remove(&key);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn repeats_within_window() {
+        let now = Instant::now();
+        let mut window = DedupWindow::new(DedupConfig::new(2, Duration::from_secs(10)));
+        assert_eq!(window.check("alice", 1, now), None);
+        assert_eq!(window.check("alice", 1, now), Some(DuplicateAction::Drop));
+        // the same id from another sender is a different packet
+        assert_eq!(window.check("bob", 1, now), None);
+
+        // the first key is evicted by the third one
+        assert_eq!(window.check("alice", 2, now), None);
+        assert_eq!(window.check("alice", 1, now), None);
+    }
+
+    #[test]
+    fn client_scope_and_flag() {
+        let now = Instant::now();
+        let config = DedupConfig::new(8, Duration::from_secs(10))
+            .with_scope(DedupScope::Client)
+            .with_action(DuplicateAction::Flag);
+        let mut window = DedupWindow::new(config);
+        assert_eq!(window.check("alice", 1, now), None);
+        assert_eq!(window.check("bob", 1, now), Some(DuplicateAction::Flag));
+    }
+
+    #[test]
+    fn max_age() {
+        let start = Instant::now();
+        let mut window = DedupWindow::new(DedupConfig::new(8, Duration::from_secs(10)));
+        assert_eq!(window.check("alice", 1, start), None);
+        let later = start + Duration::from_secs(5);
+        assert_eq!(window.check("alice", 2, later), None);
+        assert_eq!(window.check("alice", 1, later), Some(DuplicateAction::Drop));
+
+        let expired = start + Duration::from_secs(10);
+        assert_eq!(window.check("alice", 1, expired), None);
+        assert_eq!(
+            window.check("alice", 2, expired),
+            Some(DuplicateAction::Drop)
+        );
+    }
+}
@@ -0,0 +1,326 @@
+/*! Endpoints of the relay a `Client` connects to
+
+A client connects to a `Target`: one or more equivalent endpoints of the
+relay, each a socket address, a hostname with a port or, on Unix, the path of
+a Unix socket. The `Transport` of the client opens a stream to the `Peer` an
+endpoint stands for, e.g. `unix::UnixTransport` to paths. Hostnames are
+resolved by a `Resolver` on every connect attempt, so DNS changes are picked
+up on reconnect, and all addresses of a name are tried in turn. When an
+attempt fails or an established connection drops with an error the client
+moves on to the next endpoint of the target. Endpoints are tried in the
+given order or shuffled anew on every round.
+
+The resolver is part of `ClientConfig`, tests can replace the system one
+with a static map.
+*/
+
+use std::fmt;
+use std::io::Error as IoError;
+use std::net::SocketAddr;
+#[cfg(unix)]
+use std::path::{Path, PathBuf};
+
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use rand_core::{OsRng, RngCore};
+
+/// Address of a relay.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Endpoint {
+    /// Socket address used as is.
+    Addr(SocketAddr),
+    /// Hostname resolved on every connect attempt.
+    Host {
+        /// Name of the host.
+        host: String,
+        /// Port of the relay.
+        port: u16,
+    },
+    /// Path of a Unix socket.
+    #[cfg(unix)]
+    Path(PathBuf),
+}
+
+impl Endpoint {
+    /// Endpoint with the given hostname and port.
+    pub fn host<T: Into<String>>(host: T, port: u16) -> Endpoint {
+        Endpoint::Host {
+            host: host.into(),
+            port,
+        }
+    }
+
+    /// Endpoint with the given Unix socket path.
+    #[cfg(unix)]
+    pub fn path<T: Into<PathBuf>>(path: T) -> Endpoint {
+        Endpoint::Path(path.into())
+    }
+}
+
+impl From<SocketAddr> for Endpoint {
+    fn from(addr: SocketAddr) -> Endpoint {
+        Endpoint::Addr(addr)
+    }
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Endpoint::Addr(ref addr) => write!(f, "{}", addr),
+            Endpoint::Host { ref host, port } => write!(f, "{}:{}", host, port),
+            #[cfg(unix)]
+            Endpoint::Path(ref path) => write!(f, "{}", path.display()),
+        }
+    }
+}
+
+/// Address a transport opens a stream to: an endpoint with its hostname
+/// resolved. The hostname is kept for protocols that send it to the relay,
+/// e.g. as the `Host` header of the WebSocket handshake.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Peer {
+    /// Socket address.
+    Addr(SocketAddr),
+    /// Socket address a hostname was resolved to.
+    Host {
+        /// Name of the host.
+        host: String,
+        /// Resolved address of the host.
+        addr: SocketAddr,
+    },
+    /// Path of a Unix socket.
+    #[cfg(unix)]
+    Path(PathBuf),
+}
+
+impl Peer {
+    /// Socket address of the peer if it has one.
+    pub fn addr(&self) -> Option<SocketAddr> {
+        match *self {
+            Peer::Addr(addr) | Peer::Host { addr, .. } => Some(addr),
+            #[cfg(unix)]
+            Peer::Path(_) => None,
+        }
+    }
+
+    /// Unix socket path of the peer if it has one.
+    #[cfg(unix)]
+    pub fn path(&self) -> Option<&Path> {
+        match *self {
+            Peer::Addr(_) | Peer::Host { .. } => None,
+            Peer::Path(ref path) => Some(path),
+        }
+    }
+
+    /// Hostname the peer was resolved from if any.
+    pub fn host(&self) -> Option<&str> {
+        match *self {
+            Peer::Host { ref host, .. } => Some(host),
+            _ => None,
+        }
+    }
+}
+
+impl From<SocketAddr> for Peer {
+    fn from(addr: SocketAddr) -> Peer {
+        Peer::Addr(addr)
+    }
+}
+
+impl fmt::Display for Peer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Peer::Addr(ref addr) => write!(f, "{}", addr),
+            Peer::Host { ref host, ref addr } => write!(f, "{} ({})", host, addr),
+            #[cfg(unix)]
+            Peer::Path(ref path) => write!(f, "{}", path.display()),
+        }
+    }
+}
+
+/// Order in which endpoints of a target are tried.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum EndpointOrder {
+    /// In the order they were given starting with the first one.
+    #[default]
+    Ordered,
+    /// In a random order shuffled again after every round.
+    Random,
+}
+
+/// Equivalent endpoints of a relay.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Target {
+    endpoints: Vec<Endpoint>,
+    order: EndpointOrder,
+}
+
+impl Target {
+    /// Connect to the given endpoint.
+    pub fn new<T: Into<Endpoint>>(endpoint: T) -> Target {
+        Target {
+            endpoints: vec![endpoint.into()],
+            order: EndpointOrder::default(),
+        }
+    }
+
+    /// Fall back to the given endpoint when the previous ones fail.
+    pub fn with_fallback<T: Into<Endpoint>>(mut self, endpoint: T) -> Target {
+        self.endpoints.push(endpoint.into());
+        self
+    }
+
+    /// Try endpoints in the given order.
+    pub fn with_order(mut self, order: EndpointOrder) -> Target {
+        self.order = order;
+        self
+    }
+
+    /// Endpoints of the target in the order they were given.
+    pub fn endpoints(&self) -> &[Endpoint] {
+        &self.endpoints
+    }
+}
+
+impl From<SocketAddr> for Target {
+    fn from(addr: SocketAddr) -> Target {
+        Target::new(addr)
+    }
+}
+
+impl From<Endpoint> for Target {
+    fn from(endpoint: Endpoint) -> Target {
+        Target::new(endpoint)
+    }
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, endpoint) in self.endpoints.iter().enumerate() {
+            if i > 0 {
+                f.write_str(",")?;
+            }
+            write!(f, "{}", endpoint)?;
+        }
+        Ok(())
+    }
+}
+
+/// Resolves hostnames of endpoints.
+pub trait Resolver: fmt::Debug + Send + Sync + 'static {
+    /// Addresses of the given host with the given port, in the order they
+    /// should be tried.
+    fn resolve(
+        &self,
+        host: &str,
+        port: u16,
+    ) -> BoxFuture<'static, Result<Vec<SocketAddr>, IoError>>;
+}
+
+/// Resolver of the operating system used by default.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemResolver;
+
+impl Resolver for SystemResolver {
+    fn resolve(
+        &self,
+        host: &str,
+        port: u16,
+    ) -> BoxFuture<'static, Result<Vec<SocketAddr>, IoError>> {
+        let host = format!("{}:{}", host, port);
+        async move { Ok(tokio::net::lookup_host(host).await?.collect()) }.boxed()
+    }
+}
+
+/// Endpoint of a target used by the next connect attempt.
+#[derive(Debug)]
+pub(crate) struct Rotation {
+    /// Indices of endpoints in the order they are tried.
+    order: Vec<usize>,
+    /// Position of the current endpoint in `order`.
+    next: usize,
+    random: bool,
+}
+
+impl Rotation {
+    pub fn new(target: &Target) -> Rotation {
+        let mut rotation = Rotation {
+            order: (0..target.endpoints.len()).collect(),
+            next: 0,
+            random: target.order == EndpointOrder::Random,
+        };
+        if rotation.random {
+            rotation.shuffle();
+        }
+        rotation
+    }
+
+    /// Index of the current endpoint.
+    pub fn current(&self) -> usize {
+        self.order[self.next]
+    }
+
+    /// Move on to the next endpoint.
+    pub fn advance(&mut self) {
+        self.next += 1;
+        if self.next == self.order.len() {
+            self.next = 0;
+            if self.random {
+                self.shuffle();
+            }
+        }
+    }
+
+    fn shuffle(&mut self) {
+        for i in (1..self.order.len()).rev() {
+            let j = (OsRng.next_u64() % (i as u64 + 1)) as usize;
+            self.order.swap(i, j);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn target(len: u16) -> Target {
+        (1..len).fold(Target::new(Endpoint::host("relay", 0)), |target, port| {
+            target.with_fallback(Endpoint::host("relay", port))
+        })
+    }
+
+    #[test]
+    fn ordered_rotation() {
+        let mut rotation = Rotation::new(&target(3));
+        let mut tried = Vec::new();
+        for _ in 0..5 {
+            tried.push(rotation.current());
+            rotation.advance();
+        }
+        assert_eq!(tried, vec![0, 1, 2, 0, 1]);
+    }
+
+    #[test]
+    fn random_rotation() {
+        let mut rotation = Rotation::new(&target(4).with_order(EndpointOrder::Random));
+        for _ in 0..10 {
+            let mut round: Vec<_> = (0..4)
+                .map(|_| {
+                    let current = rotation.current();
+                    rotation.advance();
+                    current
+                })
+                .collect();
+            // every endpoint is tried once per round
+            round.sort_unstable();
+            assert_eq!(round, vec![0, 1, 2, 3]);
+        }
+    }
+
+    #[test]
+    fn display() {
+        let addr: SocketAddr = "127.0.0.1:33445".parse().unwrap();
+        let target = Target::new(addr).with_fallback(Endpoint::host("relay.example.com", 443));
+        assert_eq!(target.to_string(), "127.0.0.1:33445,relay.example.com:443");
+    }
+}
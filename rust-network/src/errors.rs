@@ -99,9 +99,21 @@ error_kind! {
         #[doc = "Send packet(s) to a connection but no such connection."]
         #[fail(display = "Send packet(s) to a connection but no such connection")]
         NoSuchConnection,
+        #[doc = "Send packet(s) that can't be correlated with a response."]
+        #[fail(display = "Send packet(s) that can't be correlated with a response")]
+        NotCorrelated,
+        #[doc = "Connection was closed before the response arrived."]
+        #[fail(display = "Connection was closed before the response arrived")]
+        Disconnected,
         #[doc = "Send packet(s) to a connection TimeOut."]
         #[fail(display = "Send packet(s) to a TimeOut")]
         TimeOut,
+        #[doc = "Outbox of a disconnected client is full."]
+        #[fail(display = "Outbox of a disconnected client is full")]
+        OutboxFull,
+        #[doc = "Failed to record packet(s) in the journal."]
+        #[fail(display = "Failed to record packet(s) in the journal")]
+        Journal,
     }
 }
 
@@ -124,9 +136,82 @@ error_kind! {
         #[doc = "Tcp client io error."]
         #[fail(display = "Tcp client io error")]
         Io,
+        #[doc = "Hostname of the relay couldn't be resolved to an address."]
+        #[fail(display = "Hostname resolution error")]
+        Resolve,
         #[doc = "Tcp codec encode error."]
         #[fail(display = "Tcp codec encode error")]
         Encode,
+        #[doc = "Handshake error."]
+        #[fail(display = "Handshake error")]
+        Handshake,
+        #[doc = "Handshake was not completed in time."]
+        #[fail(display = "Handshake was not completed in time")]
+        HandshakeTimeout,
+        #[doc = "TLS handshake error."]
+        #[fail(display = "TLS handshake error")]
+        Tls,
+        #[doc = "Relay didn't answer several pings in a row."]
+        #[fail(display = "Relay didn't answer pings")]
+        PingTimeout,
+        #[doc = "Lua plugin directory can't be read."]
+        #[fail(display = "Lua plugin directory error")]
+        Plugin,
+    }
+}
+
+error_kind! {
+    #[doc = "Error that can happen when building a TLS configuration."]
+    #[derive(Debug)]
+    TlsError,
+    #[doc = "The specific kind of error that can occur."]
+    #[derive(Clone, Debug, Eq, PartialEq, failure::Fail)]
+    TlsErrorKind {
+        #[doc = "PEM data doesn't contain a valid certificate."]
+        #[fail(display = "Invalid certificate")]
+        Certificate,
+        #[doc = "PEM data doesn't contain a valid private key."]
+        #[fail(display = "Invalid private key")]
+        PrivateKey,
+        #[doc = "Server name is neither a DNS name nor an IP address."]
+        #[fail(display = "Invalid server name")]
+        ServerName,
+        #[doc = "Neither root certificates nor pins are configured."]
+        #[fail(display = "No trusted certificates")]
+        NoTrustedCertificates,
+        #[doc = "rustls rejected the configuration."]
+        #[fail(display = "Invalid TLS configuration")]
+        Config,
+    }
+}
+
+error_kind! {
+    #[doc = "Error that can happen during the secure session handshake."]
+    #[derive(Debug)]
+    HandshakeError,
+    #[doc = "The specific kind of error that can occur."]
+    #[derive(Clone, Debug, Eq, PartialEq, failure::Fail)]
+    HandshakeErrorKind {
+        #[doc = "Handshake io error."]
+        #[fail(display = "Handshake io error")]
+        Io,
+        #[doc = "Server presented a key that differs from the pinned one."]
+        #[fail(display = "Server presented a key that differs from the pinned one")]
+        ServerKeyMismatch,
+        #[doc = "Peer speaks a different protocol version."]
+        #[fail(display = "Protocol version mismatch: local {}, remote {}", local, remote)]
+        VersionMismatch {
+            #[doc = "Protocol version of this side."]
+            local: u16,
+            #[doc = "Protocol version of the peer."]
+            remote: u16,
+        },
+        #[doc = "Peer doesn't support a required capability."]
+        #[fail(display = "Peer doesn't support a required capability")]
+        MissingCapability,
+        #[doc = "Received handshake packet can't be parsed."]
+        #[fail(display = "Received handshake packet can't be parsed")]
+        InvalidPacket,
     }
 }
 
@@ -170,6 +255,9 @@ error_kind! {
         #[doc = "Add connection to client error."]
         #[fail(display = "Add connection to client error")]
         AddConnection,
+        #[doc = "Metrics endpoint error."]
+        #[fail(display = "Metrics endpoint error")]
+        Metrics,
     }
 }
 
@@ -0,0 +1,351 @@
+/*! Protocol version and capability handshake
+
+Right after the connection is established the client sends `Hello` and the
+server answers with `HelloAck`. Both packets are framed the same way as
+packets handled by `Codec`. Application packets are exchanged only after the
+server has accepted the client. Capabilities enabled for the connection are
+the ones supported by both sides, maximum frame size is the smaller of the two.
+*/
+
+use bytes::{BufMut, BytesMut};
+use failure::Fail;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::codec::FRAME_HEADER_SIZE;
+use crate::errors::*;
+use crate::hello::*;
+use crate::{FromBytes, ToBytes};
+
+/// Maximum size of a handshake frame.
+const MAX_HANDSHAKE_FRAME_SIZE: usize = 4096;
+
+/// Parameters of the connection agreed on during the handshake.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Negotiated {
+    /// Id announced by the client
+    pub client_id: String,
+    /// Capabilities enabled for the connection
+    pub capabilities: u32,
+    /// Maximum frame size used by both sides
+    pub max_frame_size: usize,
+}
+
+impl Negotiated {
+    /// Check if the capability is enabled for the connection.
+    pub fn has(&self, capability: u32) -> bool {
+        self.capabilities & capability == capability
+    }
+}
+
+/// Capabilities of one side of the handshake.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Capabilities {
+    /// Capabilities supported by this side
+    pub supported: u32,
+    /// Capabilities the other side must support
+    pub required: u32,
+    /// Maximum frame size this side is able to receive
+    pub max_frame_size: usize,
+}
+
+async fn write_frame<S, T>(stream: &mut S, packet: &T) -> Result<(), HandshakeError>
+where
+    S: AsyncWrite + Unpin,
+    T: ToBytes,
+{
+    let mut buf = BytesMut::new();
+    buf.put_u32(0);
+    packet
+        .to_bytes(&mut buf)
+        .map_err(|e| e.context(HandshakeErrorKind::InvalidPacket))?;
+    let len = (buf.len() - FRAME_HEADER_SIZE) as u32;
+    buf[..FRAME_HEADER_SIZE].copy_from_slice(&len.to_be_bytes());
+    stream
+        .write_all(&buf)
+        .await
+        .map_err(|e| e.context(HandshakeErrorKind::Io))?;
+    stream
+        .flush()
+        .await
+        .map_err(|e| e.context(HandshakeErrorKind::Io).into())
+}
+
+async fn read_frame<S, T>(stream: &mut S) -> Result<T, HandshakeError>
+where
+    S: AsyncRead + Unpin,
+    T: FromBytes,
+{
+    let mut header = [0; FRAME_HEADER_SIZE];
+    stream
+        .read_exact(&mut header)
+        .await
+        .map_err(|e| e.context(HandshakeErrorKind::Io))?;
+    let len = u32::from_be_bytes(header) as usize;
+    if len > MAX_HANDSHAKE_FRAME_SIZE {
+        return Err(HandshakeErrorKind::InvalidPacket.into());
+    }
+    let mut buf = vec![0; len];
+    stream
+        .read_exact(&mut buf)
+        .await
+        .map_err(|e| e.context(HandshakeErrorKind::Io))?;
+    match T::from_bytes(&buf) {
+        Ok((_, packet)) => Ok(packet),
+        Err(_) => Err(HandshakeErrorKind::InvalidPacket.into()),
+    }
+}
+
+/// Run the client side of the handshake.
+pub async fn client_hello<S>(
+    stream: &mut S,
+    client_id: String,
+    capabilities: Capabilities,
+) -> Result<Negotiated, HandshakeError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let hello = Hello {
+        version: PROTOCOL_VERSION,
+        capabilities: capabilities.supported,
+        max_frame_size: capabilities.max_frame_size as u32,
+        client_id: client_id.clone(),
+    };
+    write_frame(stream, &hello).await?;
+    let ack: HelloAck = read_frame(stream).await?;
+
+    match ack.status {
+        HelloStatus::VersionMismatch => {
+            return Err(HandshakeErrorKind::VersionMismatch {
+                local: PROTOCOL_VERSION,
+                remote: ack.version,
+            }
+            .into())
+        }
+        HelloStatus::MissingCapability => return Err(HandshakeErrorKind::MissingCapability.into()),
+        HelloStatus::Accepted => {}
+    }
+    if ack.version != PROTOCOL_VERSION {
+        return Err(HandshakeErrorKind::VersionMismatch {
+            local: PROTOCOL_VERSION,
+            remote: ack.version,
+        }
+        .into());
+    }
+    if ack.capabilities & capabilities.required != capabilities.required
+        || ack.capabilities & !capabilities.supported != 0
+    {
+        return Err(HandshakeErrorKind::MissingCapability.into());
+    }
+
+    Ok(Negotiated {
+        client_id,
+        capabilities: ack.capabilities,
+        max_frame_size: capabilities.max_frame_size.min(ack.max_frame_size as usize),
+    })
+}
+
+/// Run the server side of the handshake.
+pub async fn server_hello<S>(
+    stream: &mut S,
+    capabilities: Capabilities,
+) -> Result<Negotiated, HandshakeError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let hello: Hello = read_frame(stream).await?;
+    let max_frame_size = capabilities
+        .max_frame_size
+        .min(hello.max_frame_size as usize);
+    let mut ack = HelloAck {
+        status: HelloStatus::Accepted,
+        version: PROTOCOL_VERSION,
+        capabilities: capabilities.supported & hello.capabilities,
+        max_frame_size: max_frame_size as u32,
+    };
+
+    let error = if hello.version != PROTOCOL_VERSION {
+        ack.status = HelloStatus::VersionMismatch;
+        Some(HandshakeErrorKind::VersionMismatch {
+            local: PROTOCOL_VERSION,
+            remote: hello.version,
+        })
+    } else if hello.capabilities & capabilities.required != capabilities.required {
+        ack.status = HelloStatus::MissingCapability;
+        Some(HandshakeErrorKind::MissingCapability)
+    } else {
+        None
+    };
+
+    write_frame(stream, &ack).await?;
+    if let Some(error) = error {
+        return Err(error.into());
+    }
+
+    Ok(Negotiated {
+        client_id: hello.client_id,
+        capabilities: ack.capabilities,
+        max_frame_size,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NO_CAPABILITIES: Capabilities = Capabilities {
+        supported: 0,
+        required: 0,
+        max_frame_size: 1024,
+    };
+
+    async fn handshake(
+        client_id: &str,
+        client: Capabilities,
+        server: Capabilities,
+    ) -> (
+        Result<Negotiated, HandshakeError>,
+        Result<Negotiated, HandshakeError>,
+    ) {
+        let (mut client_stream, mut server_stream) = tokio::io::duplex(1024);
+        let server = tokio::spawn(async move { server_hello(&mut server_stream, server).await });
+        let client = client_hello(&mut client_stream, client_id.to_string(), client).await;
+        (client, server.await.unwrap())
+    }
+
+    #[tokio::test]
+    async fn negotiate() {
+        let client = Capabilities {
+            supported: CAPABILITY_COMPRESSION | CAPABILITY_ENCRYPTION,
+            required: 0,
+            max_frame_size: 2048,
+        };
+        let server = Capabilities {
+            supported: CAPABILITY_ENCRYPTION,
+            required: CAPABILITY_ENCRYPTION,
+            max_frame_size: 4096,
+        };
+        let (client, server) = handshake("bot", client, server).await;
+        let client = client.unwrap();
+        let server = server.unwrap();
+
+        assert_eq!(client, server);
+        assert_eq!(server.client_id, "bot");
+        assert!(server.has(CAPABILITY_ENCRYPTION));
+        assert!(!server.has(CAPABILITY_COMPRESSION));
+        assert_eq!(server.max_frame_size, 2048);
+    }
+
+    #[tokio::test]
+    async fn server_requires_missing_capability() {
+        let server = Capabilities {
+            supported: CAPABILITY_ENCRYPTION,
+            required: CAPABILITY_ENCRYPTION,
+            max_frame_size: 1024,
+        };
+        let (client, server) = handshake("bot", NO_CAPABILITIES, server).await;
+
+        assert_eq!(
+            *client.unwrap_err().kind(),
+            HandshakeErrorKind::MissingCapability
+        );
+        assert_eq!(
+            *server.unwrap_err().kind(),
+            HandshakeErrorKind::MissingCapability
+        );
+    }
+
+    #[tokio::test]
+    async fn client_requires_missing_capability() {
+        let client = Capabilities {
+            supported: CAPABILITY_ENCRYPTION,
+            required: CAPABILITY_ENCRYPTION,
+            max_frame_size: 1024,
+        };
+        let (client, server) = handshake("bot", client, NO_CAPABILITIES).await;
+
+        assert_eq!(
+            *client.unwrap_err().kind(),
+            HandshakeErrorKind::MissingCapability
+        );
+        assert!(server.is_ok());
+    }
+
+    #[tokio::test]
+    async fn version_mismatch() {
+        let (mut client_stream, mut server_stream) = tokio::io::duplex(1024);
+        let server =
+            tokio::spawn(async move { server_hello(&mut server_stream, NO_CAPABILITIES).await });
+
+        let hello = Hello {
+            version: PROTOCOL_VERSION + 1,
+            capabilities: 0,
+            max_frame_size: 1024,
+            client_id: "bot".to_string(),
+        };
+        write_frame(&mut client_stream, &hello).await.unwrap();
+        let ack: HelloAck = read_frame(&mut client_stream).await.unwrap();
+
+        assert_eq!(ack.status, HelloStatus::VersionMismatch);
+        assert_eq!(ack.version, PROTOCOL_VERSION);
+        assert_eq!(
+            *server.await.unwrap().unwrap_err().kind(),
+            HandshakeErrorKind::VersionMismatch {
+                local: PROTOCOL_VERSION,
+                remote: PROTOCOL_VERSION + 1,
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn server_version_mismatch() {
+        let (mut client_stream, mut server_stream) = tokio::io::duplex(1024);
+        let server = tokio::spawn(async move {
+            let _: Hello = read_frame(&mut server_stream).await.unwrap();
+            let ack = HelloAck {
+                status: HelloStatus::VersionMismatch,
+                version: PROTOCOL_VERSION + 1,
+                capabilities: 0,
+                max_frame_size: 1024,
+            };
+            write_frame(&mut server_stream, &ack).await.unwrap();
+        });
+
+        let error = client_hello(&mut client_stream, "bot".to_string(), NO_CAPABILITIES)
+            .await
+            .unwrap_err();
+        server.await.unwrap();
+
+        assert_eq!(
+            *error.kind(),
+            HandshakeErrorKind::VersionMismatch {
+                local: PROTOCOL_VERSION,
+                remote: PROTOCOL_VERSION + 1,
+            }
+        );
+        assert_eq!(
+            error.to_string(),
+            format!(
+                "Protocol version mismatch: local {}, remote {}",
+                PROTOCOL_VERSION,
+                PROTOCOL_VERSION + 1
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn not_a_hello() {
+        let (mut client_stream, mut server_stream) = tokio::io::duplex(1024);
+        let server =
+            tokio::spawn(async move { server_hello(&mut server_stream, NO_CAPABILITIES).await });
+
+        // application packet sent without handshake
+        let mut buf = vec![0, 0, 0, 9, crate::packet_kind::PING_REQUEST];
+        buf.extend_from_slice(&7u64.to_be_bytes());
+        client_stream.write_all(&buf).await.unwrap();
+
+        assert_eq!(
+            *server.await.unwrap().unwrap_err().kind(),
+            HandshakeErrorKind::InvalidPacket
+        );
+    }
+}
@@ -0,0 +1,195 @@
+/*! Hello and HelloAck handshake packets
+*/
+
+use crate::errors::PacketError;
+use crate::{packet_kind, FromBytes, ToBytes};
+use bytes::{BufMut, BytesMut};
+use nom::{
+    do_parse, map_opt, map_res, named,
+    number::streaming::{be_u16, be_u32, be_u64, be_u8},
+    tag, take,
+};
+
+/// Version of the protocol spoken by this crate. Peers speaking a different
+/// version are rejected during the handshake.
+pub const PROTOCOL_VERSION: u16 = 1;
+
+/// Peer is able to compress frames.
+pub const CAPABILITY_COMPRESSION: u32 = 1 << 0;
+/// Peer is able to encrypt frames with a secure session.
+pub const CAPABILITY_ENCRYPTION: u32 = 1 << 1;
+
+/** Sent by client right after the connection is established.
+Serialized form:
+Length   | Content
+-------- | ------
+`1`      | `0x01`
+`2`      | Protocol version in BigEndian
+`4`      | Capabilities bitset in BigEndian
+`4`      | Maximum frame size in BigEndian
+`8`      | Length of `client_id` in BigEndian
+variable | client_id
+*/
+#[derive(Debug, PartialEq, Clone)]
+pub struct Hello {
+    /// Protocol version of the client
+    pub version: u16,
+    /// Capabilities supported by the client
+    pub capabilities: u32,
+    /// Maximum frame size the client is able to receive
+    pub max_frame_size: u32,
+    /// Id of the client
+    pub client_id: String,
+}
+
+impl FromBytes for Hello {
+    named!(
+        from_bytes<Hello>,
+        do_parse!(
+            tag!(&[packet_kind::HELLO][..])
+                >> version: be_u16
+                >> capabilities: be_u32
+                >> max_frame_size: be_u32
+                >> len: be_u64
+                >> client_id: map_res!(take!(len as usize), std::str::from_utf8)
+                >> (Hello {
+                    version,
+                    capabilities,
+                    max_frame_size,
+                    client_id: client_id.to_string(),
+                })
+        )
+    );
+}
+
+impl ToBytes for Hello {
+    fn to_bytes(&self, buf: &mut BytesMut) -> Result<(), PacketError> {
+        buf.put_u8(packet_kind::HELLO);
+        buf.put_u16(self.version);
+        buf.put_u32(self.capabilities);
+        buf.put_u32(self.max_frame_size);
+        buf.put_u64(self.client_id.len() as u64);
+        buf.extend_from_slice(self.client_id.as_bytes());
+        Ok(())
+    }
+}
+
+/// Decision of the server about the `Hello` of the client.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum HelloStatus {
+    /// Client is accepted, application packets follow
+    Accepted = 0,
+    /// Client speaks a different protocol version
+    VersionMismatch = 1,
+    /// Client doesn't support a capability required by the server
+    MissingCapability = 2,
+}
+
+impl HelloStatus {
+    fn from_u8(status: u8) -> Option<HelloStatus> {
+        match status {
+            0 => Some(HelloStatus::Accepted),
+            1 => Some(HelloStatus::VersionMismatch),
+            2 => Some(HelloStatus::MissingCapability),
+            _ => None,
+        }
+    }
+}
+
+/** Sent by server in response to `Hello`.
+Serialized form:
+Length | Content
+------ | ------
+`1`    | `0x02`
+`1`    | Status
+`2`    | Protocol version in BigEndian
+`4`    | Negotiated capabilities bitset in BigEndian
+`4`    | Negotiated maximum frame size in BigEndian
+*/
+#[derive(Debug, PartialEq, Clone)]
+pub struct HelloAck {
+    /// Decision of the server
+    pub status: HelloStatus,
+    /// Protocol version of the server
+    pub version: u16,
+    /// Capabilities enabled for the connection
+    pub capabilities: u32,
+    /// Maximum frame size used by both sides
+    pub max_frame_size: u32,
+}
+
+impl FromBytes for HelloAck {
+    named!(
+        from_bytes<HelloAck>,
+        do_parse!(
+            tag!(&[packet_kind::HELLO_ACK][..])
+                >> status: map_opt!(be_u8, HelloStatus::from_u8)
+                >> version: be_u16
+                >> capabilities: be_u32
+                >> max_frame_size: be_u32
+                >> (HelloAck {
+                    status,
+                    version,
+                    capabilities,
+                    max_frame_size,
+                })
+        )
+    );
+}
+
+impl ToBytes for HelloAck {
+    fn to_bytes(&self, buf: &mut BytesMut) -> Result<(), PacketError> {
+        buf.put_u8(packet_kind::HELLO_ACK);
+        buf.put_u8(self.status as u8);
+        buf.put_u16(self.version);
+        buf.put_u32(self.capabilities);
+        buf.put_u32(self.max_frame_size);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hello_encode_decode() {
+        let hello = Hello {
+            version: PROTOCOL_VERSION,
+            capabilities: CAPABILITY_ENCRYPTION,
+            max_frame_size: 1024,
+            client_id: "client".to_string(),
+        };
+        let mut bytes = BytesMut::new();
+        hello.to_bytes(&mut bytes).unwrap();
+        assert_eq!(Hello::from_bytes(&bytes).unwrap(), (&[][..], hello));
+    }
+
+    #[test]
+    fn hello_ack_encode_decode() {
+        let ack = HelloAck {
+            status: HelloStatus::VersionMismatch,
+            version: PROTOCOL_VERSION,
+            capabilities: 0,
+            max_frame_size: 1024,
+        };
+        let mut bytes = BytesMut::new();
+        ack.to_bytes(&mut bytes).unwrap();
+        assert_eq!(HelloAck::from_bytes(&bytes).unwrap(), (&[][..], ack));
+    }
+
+    #[test]
+    fn hello_ack_unknown_status() {
+        let mut bytes = BytesMut::new();
+        HelloAck {
+            status: HelloStatus::Accepted,
+            version: PROTOCOL_VERSION,
+            capabilities: 0,
+            max_frame_size: 1024,
+        }
+        .to_bytes(&mut bytes)
+        .unwrap();
+        bytes[1] = 0xff;
+        assert!(HelloAck::from_bytes(&bytes).is_err());
+    }
+}
@@ -0,0 +1,453 @@
+/*! Disk-backed journal of packets waiting for an acknowledgement
+
+A `Client` with a journal records every packet that `Protocol::needs_ack`
+before sending it. The packet stays in the journal until the relay answers
+it with a packet of the same correlation id that `Protocol::is_response_to`
+it, i.e. a `ChatMessage` with the same `msg_id`. All packets left in the journal are sent again on every
+connect, including the first one after the process restarts, so delivery is
+at-least-once and the relay may see a packet more than once.
+
+The journal is an append-only file of records:
+
+Length   | Content
+-------- | ------
+`1`      | `0x01` packet queued
+`8`      | correlation id in BigEndian
+`8`      | time of queuing in milliseconds since the Unix epoch in BigEndian
+`4`      | length of the packet in BigEndian
+variable | packet
+or:
+
+Length   | Content
+-------- | ------
+`1`      | `0x02` packet acknowledged or removed
+`8`      | correlation id in BigEndian
+
+A record cut off by a crash is dropped when the journal is opened, a record
+of an unknown kind fails opening instead. Once the
+file holds more dead records than live ones it's compacted, i.e. rewritten
+with the live records only and atomically renamed over the old one.
+
+Writes are synchronous and flushed to disk before returning, keep the
+journal on a local filesystem. `Client` writes to it in a blocking task.
+*/
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File, OpenOptions};
+use std::io::{Error as IoError, ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use bytes::{Buf, BufMut, BytesMut};
+use tracing::{debug, warn};
+
+use crate::Protocol;
+
+/// Number of dead records that triggers compaction by default.
+pub const DEFAULT_COMPACT_THRESHOLD: usize = 1024;
+
+const RECORD_QUEUED: u8 = 0x01;
+const RECORD_REMOVED: u8 = 0x02;
+
+/// Packet recorded in a journal.
+#[derive(Debug, Clone, PartialEq)]
+pub struct JournalEntry<P> {
+    /// Correlation id of the packet.
+    pub id: u64,
+    /// The packet as it's sent to the relay.
+    pub packet: P,
+    /// Time when the packet was recorded.
+    pub queued_at: SystemTime,
+}
+
+/// Packets waiting for an acknowledgement persisted in a file.
+#[derive(Debug)]
+pub struct Journal<P> {
+    path: PathBuf,
+    file: File,
+    /// Live entries in the order they were recorded.
+    entries: BTreeMap<u64, JournalEntry<P>>,
+    /// Position in `entries` by correlation id.
+    index: HashMap<u64, u64>,
+    next_seq: u64,
+    /// Number of records in the file.
+    records: usize,
+    compact_threshold: usize,
+}
+
+impl<P: Protocol> Journal<P> {
+    /// Open the journal at the given path creating it if it doesn't exist.
+    /// Packets left in the journal are loaded to be sent again.
+    pub fn open<T: AsRef<Path>>(path: T) -> Result<Journal<P>, IoError> {
+        let path = path.as_ref().to_path_buf();
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(&path)?;
+        let mut data = Vec::new();
+        file.read_to_end(&mut data)?;
+
+        let mut journal = Journal {
+            path,
+            file,
+            entries: BTreeMap::new(),
+            index: HashMap::new(),
+            next_seq: 0,
+            records: 0,
+            compact_threshold: DEFAULT_COMPACT_THRESHOLD,
+        };
+        let valid = journal.replay(&data)?;
+        if valid < data.len() {
+            warn!(
+                path = %journal.path.display(),
+                bytes = data.len() - valid,
+                "Dropping incomplete journal record"
+            );
+            journal.file.set_len(valid as u64)?;
+        }
+        debug!(entries = journal.entries.len(), "Journal opened");
+        Ok(journal)
+    }
+
+    /// Compact the journal once it holds `records` dead records and more
+    /// dead records than live ones.
+    pub fn with_compact_threshold(mut self, records: usize) -> Journal<P> {
+        self.compact_threshold = records;
+        self
+    }
+
+    /// Path of the journal file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Number of packets waiting for an acknowledgement.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Check if there are no packets waiting for an acknowledgement.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Packets waiting for an acknowledgement in the order they were
+    /// recorded.
+    pub fn entries(&self) -> impl Iterator<Item = &JournalEntry<P>> {
+        self.entries.values()
+    }
+
+    /// Check if a packet with the given correlation id is recorded.
+    pub fn contains(&self, id: u64) -> bool {
+        self.index.contains_key(&id)
+    }
+
+    /// Packet with the given correlation id.
+    pub fn get(&self, id: u64) -> Option<&JournalEntry<P>> {
+        self.index.get(&id).and_then(|seq| self.entries.get(seq))
+    }
+
+    /// Record a packet. A packet recorded before with the same correlation
+    /// id is replaced.
+    pub fn append(&mut self, packet: &P) -> Result<(), IoError> {
+        let id = packet
+            .correlation_id()
+            .ok_or_else(|| IoError::new(ErrorKind::InvalidInput, "Packet has no correlation id"))?;
+        let millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |time| time.as_millis() as u64);
+        let entry = JournalEntry {
+            id,
+            packet: packet.clone(),
+            queued_at: UNIX_EPOCH + Duration::from_millis(millis),
+        };
+
+        let mut buf = BytesMut::new();
+        put_queued(&mut buf, &entry)?;
+        self.write(&buf)?;
+        self.insert(entry);
+        Ok(())
+    }
+
+    /// Remove the packet with the given correlation id because it was
+    /// acknowledged or is stuck. Returns `false` if there is no such packet.
+    pub fn remove(&mut self, id: u64) -> Result<bool, IoError> {
+        if !self.contains(id) {
+            return Ok(false);
+        }
+        let mut buf = BytesMut::with_capacity(9);
+        buf.put_u8(RECORD_REMOVED);
+        buf.put_u64(id);
+        self.write(&buf)?;
+        self.delete(id);
+
+        let dead = self.records - self.entries.len();
+        if dead >= self.compact_threshold && dead > self.entries.len() {
+            self.compact()?;
+        }
+        Ok(true)
+    }
+
+    /// Remove packets recorded before the given time. Returns the number of
+    /// removed packets.
+    pub fn remove_older_than(&mut self, time: SystemTime) -> Result<usize, IoError> {
+        let stuck: Vec<u64> = self
+            .entries
+            .values()
+            .filter(|entry| entry.queued_at < time)
+            .map(|entry| entry.id)
+            .collect();
+        for &id in &stuck {
+            self.remove(id)?;
+        }
+        Ok(stuck.len())
+    }
+
+    /// Rewrite the journal file with live records only.
+    pub fn compact(&mut self) -> Result<(), IoError> {
+        let mut tmp_path = self.path.clone().into_os_string();
+        tmp_path.push(".compact");
+        let tmp_path = PathBuf::from(tmp_path);
+
+        let mut buf = BytesMut::new();
+        for entry in self.entries.values() {
+            put_queued(&mut buf, entry)?;
+        }
+        let mut tmp = File::create(&tmp_path)?;
+        tmp.write_all(&buf)?;
+        tmp.sync_all()?;
+        fs::rename(&tmp_path, &self.path)?;
+
+        self.file = OpenOptions::new().append(true).open(&self.path)?;
+        debug!(
+            dead = self.records - self.entries.len(),
+            live = self.entries.len(),
+            "Journal compacted"
+        );
+        self.records = self.entries.len();
+        Ok(())
+    }
+
+    /// Apply records of a journal file. Returns the length of `data`
+    /// without a torn record at its end. Fails with `InvalidData` on a
+    /// record of an unknown kind as the rest of the file can't be trusted.
+    fn replay(&mut self, data: &[u8]) -> Result<usize, IoError> {
+        let mut buf = data;
+        loop {
+            let valid = data.len() - buf.len();
+            match buf.first() {
+                Some(&RECORD_QUEUED) if buf.len() >= 21 => {
+                    let len = (&buf[17..21]).get_u32() as usize;
+                    if buf.len() < 21 + len {
+                        return Ok(valid);
+                    }
+                    buf.advance(1);
+                    let id = buf.get_u64();
+                    let millis = buf.get_u64();
+                    buf.advance(4);
+                    match P::from_bytes(&buf[..len]) {
+                        Ok((_, packet)) => self.insert(JournalEntry {
+                            id,
+                            packet,
+                            queued_at: UNIX_EPOCH + Duration::from_millis(millis),
+                        }),
+                        Err(e) => warn!(id, error = ?e, "Can't parse journaled packet"),
+                    }
+                    buf.advance(len);
+                }
+                Some(&RECORD_REMOVED) if buf.len() >= 9 => {
+                    buf.advance(1);
+                    let id = buf.get_u64();
+                    self.delete(id);
+                }
+                Some(&RECORD_QUEUED) | Some(&RECORD_REMOVED) | None => return Ok(valid),
+                Some(&kind) => {
+                    return Err(IoError::new(
+                        ErrorKind::InvalidData,
+                        format!("Unknown journal record 0x{:02x} at {}", kind, valid),
+                    ))
+                }
+            }
+            self.records += 1;
+        }
+    }
+
+    fn write(&mut self, record: &[u8]) -> Result<(), IoError> {
+        self.file.write_all(record)?;
+        self.file.sync_data()?;
+        self.records += 1;
+        Ok(())
+    }
+
+    fn insert(&mut self, entry: JournalEntry<P>) {
+        self.delete(entry.id);
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        self.index.insert(entry.id, seq);
+        self.entries.insert(seq, entry);
+    }
+
+    fn delete(&mut self, id: u64) {
+        if let Some(seq) = self.index.remove(&id) {
+            self.entries.remove(&seq);
+        }
+    }
+}
+
+/// Append a record of a queued packet to the buffer.
+fn put_queued<P: Protocol>(buf: &mut BytesMut, entry: &JournalEntry<P>) -> Result<(), IoError> {
+    let millis = entry
+        .queued_at
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.as_millis() as u64);
+    let mut payload = BytesMut::new();
+    entry
+        .packet
+        .to_bytes(&mut payload)
+        .map_err(|e| IoError::new(ErrorKind::InvalidInput, e.to_string()))?;
+    buf.reserve(21 + payload.len());
+    buf.put_u8(RECORD_QUEUED);
+    buf.put_u64(entry.id);
+    buf.put_u64(millis);
+    buf.put_u32(payload.len() as u32);
+    buf.extend_from_slice(&payload);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use bytes::Bytes;
+
+    use crate::chatmsg::ChatMessage;
+    use crate::Packet;
+
+    fn journal_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "rust-network-{}-{}.journal",
+            name,
+            std::process::id()
+        ));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    fn chat_message(msg_id: u64) -> Packet {
+        Packet::ChatMessage(ChatMessage {
+            msg_id,
+            to_user: "relay".to_string(),
+            from_user: "client".to_string(),
+            content: Bytes::from_static(b"hello"),
+        })
+    }
+
+    fn ids(journal: &Journal<Packet>) -> Vec<u64> {
+        journal.entries().map(|entry| entry.id).collect()
+    }
+
+    #[test]
+    fn replay_on_open() {
+        let path = journal_path("replay");
+        let mut journal = Journal::<Packet>::open(&path).unwrap();
+        for msg_id in 1..=3 {
+            journal.append(&chat_message(msg_id)).unwrap();
+        }
+        assert!(journal.remove(2).unwrap());
+        assert!(!journal.remove(2).unwrap());
+        drop(journal);
+
+        let journal = Journal::<Packet>::open(&path).unwrap();
+        assert_eq!(ids(&journal), vec![1, 3]);
+        let entry = journal.entries().next().unwrap();
+        assert_eq!(entry.packet, chat_message(1));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn incomplete_record() {
+        let path = journal_path("incomplete");
+        let mut journal = Journal::<Packet>::open(&path).unwrap();
+        journal.append(&chat_message(1)).unwrap();
+        journal.append(&chat_message(2)).unwrap();
+        drop(journal);
+
+        // crash in the middle of the second record
+        let len = fs::metadata(&path).unwrap().len();
+        OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_len(len - 3)
+            .unwrap();
+
+        let mut journal = Journal::<Packet>::open(&path).unwrap();
+        assert_eq!(ids(&journal), vec![1]);
+        journal.append(&chat_message(3)).unwrap();
+        drop(journal);
+        let journal = Journal::<Packet>::open(&path).unwrap();
+        assert_eq!(ids(&journal), vec![1, 3]);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn unknown_record() {
+        let path = journal_path("unknown");
+        let mut journal = Journal::<Packet>::open(&path).unwrap();
+        journal.append(&chat_message(1)).unwrap();
+        drop(journal);
+        let record = fs::read(&path).unwrap();
+        let mut data = record.clone();
+        data.extend_from_slice(&[0x7f; 16]);
+        data.extend_from_slice(&record);
+        fs::write(&path, &data).unwrap();
+
+        // records after the unknown one are not dropped
+        let error = Journal::<Packet>::open(&path).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+        assert_eq!(fs::read(&path).unwrap(), data);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn compaction() {
+        let path = journal_path("compaction");
+        let mut journal = Journal::<Packet>::open(&path)
+            .unwrap()
+            .with_compact_threshold(4);
+        for msg_id in 1..=5 {
+            journal.append(&chat_message(msg_id)).unwrap();
+        }
+        let full = fs::metadata(&path).unwrap().len();
+        for msg_id in 1..=4 {
+            journal.remove(msg_id).unwrap();
+        }
+        // only the record of the last live packet is left
+        assert!(fs::metadata(&path).unwrap().len() < full / 4);
+        journal.append(&chat_message(6)).unwrap();
+        drop(journal);
+
+        let journal = Journal::<Packet>::open(&path).unwrap();
+        assert_eq!(ids(&journal), vec![5, 6]);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn remove_stuck() {
+        let path = journal_path("stuck");
+        let mut journal = Journal::<Packet>::open(&path).unwrap();
+        journal.append(&chat_message(1)).unwrap();
+        journal.append(&chat_message(2)).unwrap();
+        // a packet sent again moves to the end
+        journal.append(&chat_message(1)).unwrap();
+        assert_eq!(ids(&journal), vec![2, 1]);
+
+        let removed = journal
+            .remove_older_than(SystemTime::now() + Duration::from_secs(1))
+            .unwrap();
+        assert_eq!(removed, 2);
+        assert!(journal.is_empty());
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,16 +1,39 @@
 #![allow(dead_code,unused)]
+// `failure::Fail` derive puts its impls in an anonymous const
+#![allow(non_local_definitions)]
 pub mod chatmsg;
 pub mod client;
 pub mod codec;
+pub mod compression;
+pub mod config;
 pub mod connections;
+pub mod dedup;
+pub mod endpoint;
 pub mod errors;
+pub mod handshake;
+pub mod hello;
+pub mod journal;
+pub mod metrics;
+pub mod outbox;
+pub mod packet_kind;
 pub mod ping_request;
 pub mod pong_response;
+pub mod reconnect;
+pub mod secure;
 pub mod server;
+pub mod state;
 pub mod stats;
+pub mod tls;
+pub mod transport;
+#[cfg(unix)]
+pub mod unix;
+pub mod websocket;
 
+use bytes::{Bytes, BytesMut};
 use chatmsg::ChatMessage;
+use mlua::{Lua, ToLua, Value};
 use nom::{alt, map, named, IResult};
+use std::fmt::Debug;
 use ping_request::PingRequest;
 use pong_response::PongResponse;
 use errors::PacketError;
@@ -18,13 +41,93 @@ use errors::PacketError;
 pub trait FromBytes: Sized {
     /// Deserialize struct using `nom` from raw bytes
     fn from_bytes(i: &[u8]) -> IResult<&[u8], Self>;
+
+    /// Deserialize struct from a frame received by `Codec`. Payloads may be
+    /// sliced out of `frame` instead of copied, by default it's the same as
+    /// `from_bytes`.
+    fn from_frame(frame: &Bytes) -> IResult<&[u8], Self> {
+        Self::from_bytes(frame)
+    }
 }
 
 /// The trait provides method to serialize struct into raw bytes
 pub trait ToBytes: Sized {
-    /// Serialize struct into raw bytes using `cookie_factory`
-    //  fn to_bytes<'a>(&self, buf: (&'a mut [u8], usize)) -> Result<(&'a mut [u8], usize), GenError>;
-    fn to_bytes(&self) -> Result<Vec<u8>, PacketError>;
+    /// Serialize struct appending raw bytes to the buffer
+    fn to_bytes(&self, buf: &mut BytesMut) -> Result<(), PacketError>;
+}
+
+/** Packet type carried by [`Codec`](./codec/struct.Codec.html),
+[`Client`](./client/struct.Client.html),
+[`Connections`](./connections/struct.Connections.html) and
+[`Server`](./server/struct.Server.html).
+
+Implement it for your own packet type to run a custom protocol on top of the
+reconnect and plugin machinery. [`Packet`](./enum.Packet.html) is a ready-made
+implementation.
+*/
+pub trait Protocol: FromBytes + ToBytes + Clone + Debug + Send + Sync + 'static {
+    /// Check if the leading byte of a frame is a kind of this protocol. Frames
+    /// of unknown kinds are rejected with `DecodeError::UnknownPacketKind`.
+    fn is_known_kind(_kind: u8) -> bool {
+        true
+    }
+
+    /// Name of the packet kind used to label metrics. Kinds without a name
+    /// are labeled with their hex value.
+    fn kind_name(_kind: u8) -> Option<&'static str> {
+        None
+    }
+
+    /// Id used by `Client::send_for_response` to match a response with its
+    /// request. `None` means that the packet can't be correlated.
+    fn correlation_id(&self) -> Option<u64>;
+
+    /// Set the id used to match a response with this request.
+    fn set_correlation_id(&mut self, id: u64);
+
+    /// Check if this packet with the same correlation id as `request` is
+    /// its response. Packets that aren't responses are handled as usual.
+    /// No packet is a response unless the protocol says so, otherwise any
+    /// packet reusing an id would complete a request or acknowledge it.
+    fn is_response_to(&self, _request: &Self) -> bool {
+        false
+    }
+
+    /// Ping sent by `Client` to check that the relay is alive. Clients of
+    /// protocols without pings don't send them.
+    fn ping_request(_ping_id: u64) -> Option<Self> {
+        None
+    }
+
+    /// Id of the ping answered by this packet if it's a pong.
+    fn pong_id(&self) -> Option<u64> {
+        None
+    }
+
+    /// Check if delivery of this packet must be confirmed by a response
+    /// with the same correlation id. Such packets are recorded in the
+    /// journal of a `Client` until they are answered.
+    fn needs_ack(&self) -> bool {
+        false
+    }
+
+    /// Sender and id of a packet that must be delivered at most once. Packets
+    /// without a key are never suppressed as repeats.
+    fn dedup_key(&self) -> Option<(&str, u64)> {
+        None
+    }
+
+    /// Pong answering this packet if it's a ping. Pings are answered by
+    /// `Client` and the server before they reach any other handler.
+    fn pong_response(&self) -> Option<Self> {
+        None
+    }
+
+    /// Convert the packet to the value passed to `OnChatMsg` and `OnChatEvent`
+    /// of Lua plugins. Plugins are not called for packets converted to `None`.
+    fn plugin_arg<'lua>(&self, _lua: &'lua Lua) -> mlua::Result<Option<Value<'lua>>> {
+        Ok(None)
+    }
 }
 #[derive(Debug, PartialEq, Clone)]
 pub enum Packet {
@@ -36,6 +139,17 @@ pub enum Packet {
     
 }
 
+impl Packet {
+    /// Kind of the packet as reserved in [`packet_kind`](./packet_kind/index.html).
+    pub fn kind(&self) -> u8 {
+        match *self {
+            Packet::PingRequest(_) => packet_kind::PING_REQUEST,
+            Packet::PongResponse(_) => packet_kind::PONG_RESPONSE,
+            Packet::ChatMessage(_) => packet_kind::CHAT_MESSAGE,
+        }
+    }
+}
+
 impl FromBytes for Packet {
     named!(
         from_bytes<Packet>,
@@ -45,23 +159,217 @@ impl FromBytes for Packet {
                 |map!(PingRequest::from_bytes, Packet::PingRequest)
         )
     );
+
+    fn from_frame(frame: &Bytes) -> IResult<&[u8], Packet> {
+        match frame.first() {
+            Some(&packet_kind::CHAT_MESSAGE) => {
+                let (i, p) = ChatMessage::from_frame(frame)?;
+                Ok((i, Packet::ChatMessage(p)))
+            }
+            _ => Packet::from_bytes(frame),
+        }
+    }
 }
 
 impl ToBytes for Packet {
-    //fn to_bytes<'a>(&self, buf: (&'a mut [u8], usize)) -> Result<(&'a mut [u8], usize), GenError> {
-    fn to_bytes(&self) -> Result<Vec<u8>, PacketError> {
+    fn to_bytes(&self, buf: &mut BytesMut) -> Result<(), PacketError> {
+        match *self {
+            Packet::PingRequest(ref p) => p.to_bytes(buf),
+            Packet::PongResponse(ref p) => p.to_bytes(buf),
+            Packet::ChatMessage(ref p) => p.to_bytes(buf),
+        }
+    }
+}
+
+impl Protocol for Packet {
+    fn is_known_kind(kind: u8) -> bool {
+        packet_kind::is_known(kind)
+    }
+
+    fn kind_name(kind: u8) -> Option<&'static str> {
+        packet_kind::name(kind)
+    }
+
+    fn correlation_id(&self) -> Option<u64> {
+        match *self {
+            Packet::PingRequest(ref p) => Some(p.ping_id),
+            Packet::PongResponse(ref p) => Some(p.ping_id),
+            Packet::ChatMessage(ref p) => Some(p.msg_id),
+        }
+    }
+
+    fn set_correlation_id(&mut self, id: u64) {
+        match *self {
+            Packet::PingRequest(ref mut p) => p.ping_id = id,
+            Packet::PongResponse(ref mut p) => p.ping_id = id,
+            Packet::ChatMessage(ref mut p) => p.msg_id = id,
+        }
+    }
+
+    fn is_response_to(&self, request: &Packet) -> bool {
+        match (request, self) {
+            (Packet::PingRequest(_), Packet::PongResponse(_)) => true,
+            // a reply is addressed to the sender of the request, other
+            // messages may reuse its id
+            (Packet::ChatMessage(request), Packet::ChatMessage(response)) => {
+                response.to_user == request.from_user
+            }
+            _ => false,
+        }
+    }
+
+    fn ping_request(ping_id: u64) -> Option<Packet> {
+        Some(Packet::PingRequest(PingRequest { ping_id }))
+    }
+
+    fn pong_id(&self) -> Option<u64> {
+        match *self {
+            Packet::PongResponse(ref p) => Some(p.ping_id),
+            _ => None,
+        }
+    }
+
+    fn needs_ack(&self) -> bool {
+        matches!(*self, Packet::ChatMessage(_))
+    }
+
+    fn dedup_key(&self) -> Option<(&str, u64)> {
+        match *self {
+            Packet::ChatMessage(ref p) => Some((&p.from_user, p.msg_id)),
+            _ => None,
+        }
+    }
+
+    fn pong_response(&self) -> Option<Packet> {
+        match *self {
+            // 0 is an invalid ping id
+            Packet::PingRequest(ref p) if p.ping_id != 0 => {
+                Some(Packet::PongResponse(PongResponse { ping_id: p.ping_id }))
+            }
+            _ => None,
+        }
+    }
+
+    fn plugin_arg<'lua>(&self, lua: &'lua Lua) -> mlua::Result<Option<Value<'lua>>> {
         match *self {
-            Packet::PingRequest(ref p) => p.to_bytes(),
-            Packet::PongResponse(ref p) => p.to_bytes(),
-            Packet::ChatMessage(ref p) => p.to_bytes(),
+            Packet::ChatMessage(ref p) => p.clone().to_lua(lua).map(Some),
+            _ => Ok(None),
         }
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+
+    use crate::client::tests::{connected, protocol_client};
+    use crate::client::Client;
+    use crate::config::ClientConfig;
+    use crate::server::{tcp_run_connection, Server, ServerHandler};
+    use crate::stats::Stats;
+    use bytes::BufMut;
+    use futures::channel::mpsc;
+    use futures::future::BoxFuture;
+    use futures::{FutureExt, SinkExt};
+    use nom::{combinator::rest, do_parse, number::streaming::be_u64, tag};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::net::TcpListener;
+    use tokio::sync::RwLock;
+
+    /// Protocol defined outside of the crate.
+    #[derive(Debug, PartialEq, Clone)]
+    struct Echo {
+        id: u64,
+        body: Vec<u8>,
+    }
+
+    impl FromBytes for Echo {
+        named!(
+            from_bytes<Echo>,
+            do_parse!(
+                tag!("\x01")
+                    >> id: be_u64
+                    >> body: rest
+                    >> (Echo {
+                        id,
+                        body: body.to_vec()
+                    })
+            )
+        );
+    }
+
+    impl ToBytes for Echo {
+        fn to_bytes(&self, buf: &mut BytesMut) -> Result<(), PacketError> {
+            buf.put_u8(0x01);
+            buf.put_u64(self.id);
+            buf.extend_from_slice(&self.body);
+            Ok(())
+        }
+    }
+
+    impl Protocol for Echo {
+        fn is_known_kind(kind: u8) -> bool {
+            kind == 0x01
+        }
+
+        fn correlation_id(&self) -> Option<u64> {
+            Some(self.id)
+        }
+
+        fn set_correlation_id(&mut self, id: u64) {
+            self.id = id;
+        }
+
+        // the relay echoes requests back
+        fn is_response_to(&self, _request: &Echo) -> bool {
+            true
+        }
+    }
+
+    struct EchoHandler;
+
+    impl ServerHandler<Echo> for EchoHandler {
+        fn handle_packet(
+            &self,
+            packet: Echo,
+            mut tx: mpsc::Sender<Echo>,
+        ) -> BoxFuture<'static, Result<(), std::io::Error>> {
+            async move {
+                tx.send(packet)
+                    .await
+                    .map_err(|e| std::io::Error::other(e.to_string()))
+            }
+            .boxed()
+        }
+    }
+
     #[test]
     fn it_works() {
         assert_eq!(2 + 2, 4);
     }
+
+    #[tokio::test]
+    async fn custom_protocol() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = Server::with_handler(EchoHandler);
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            tcp_run_connection(&server, stream, Stats::new()).await
+        });
+
+        let (client, _incoming_rx): (Client<Echo>, _) =
+            protocol_client(addr, ClientConfig::default());
+        client.clone().spawn().await.unwrap();
+        connected(&client).await;
+
+        let request = Echo {
+            id: 0,
+            body: b"hello".to_vec(),
+        };
+        let response = client.send_for_response(request).await.unwrap();
+        assert_ne!(response.id, 0);
+        assert_eq!(response.body, b"hello".to_vec());
+    }
 }
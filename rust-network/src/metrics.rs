@@ -0,0 +1,368 @@
+/*! Prometheus metrics endpoint
+
+`Server` and `Connections` configured with `with_metrics` start a small HTTP
+listener next to their main loop. It answers `GET /metrics` with their `Stats`
+aggregated over all clients in the Prometheus text format, any other request
+gets `404`. Requests are served one at a time since they come from a scraper.
+*/
+
+use std::collections::BTreeMap;
+use std::fmt::{Display, Write as FmtWrite};
+use std::future::Future;
+use std::io::Error as IoError;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{debug, info, warn};
+
+use crate::stats::{StatsSnapshot, RTT_BUCKETS_MS};
+use crate::Protocol;
+
+/// Time given to a scraper to send its request and read the response.
+const METRICS_REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Maximum size of a request head, the rest is not read.
+const MAX_REQUEST_SIZE: usize = 8192;
+
+/// Pause after a failed `accept`, e.g. when out of file descriptors.
+const ACCEPT_ERROR_DELAY: Duration = Duration::from_millis(100);
+
+/// Values exported to Prometheus.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Metrics {
+    /// Counters aggregated over all clients
+    pub stats: StatsSnapshot,
+    /// Currently connected clients
+    pub connected_clients: usize,
+    /// Unsuccessful connection attempts of clients since they were connected
+    /// last time. Only `Connections` report it.
+    pub connection_attempts: Option<u64>,
+    /// Requests sent by `send_for_response` waiting for a response. Only
+    /// `Connections` report it.
+    pub pending_requests: Option<usize>,
+}
+
+impl Metrics {
+    /// Render metrics in the Prometheus text format. Packet kinds are labeled
+    /// with `P::kind_name`.
+    pub fn render<P: Protocol>(&self) -> String {
+        let mut out = String::new();
+        let stats = &self.stats;
+
+        gauge(
+            &mut out,
+            "connected_clients",
+            "Currently connected clients.",
+            self.connected_clients,
+        );
+        if let Some(connection_attempts) = self.connection_attempts {
+            gauge(
+                &mut out,
+                "connection_attempts",
+                "Unsuccessful connection attempts of clients since they were connected.",
+                connection_attempts,
+            );
+        }
+        if let Some(pending_requests) = self.pending_requests {
+            gauge(
+                &mut out,
+                "pending_requests",
+                "Requests waiting for a response.",
+                pending_requests,
+            );
+        }
+        counter(
+            &mut out,
+            "connects_total",
+            "Established connections.",
+            stats.connects,
+        );
+        counter(
+            &mut out,
+            "reconnects_total",
+            "Established connections of clients that were connected before.",
+            stats.reconnects,
+        );
+
+        header(
+            &mut out,
+            "packets_total",
+            "Packets by direction and kind.",
+            "counter",
+        );
+        per_kind::<P>(&mut out, "packets_total", "in", &stats.incoming_kinds);
+        per_kind::<P>(&mut out, "packets_total", "out", &stats.outgoing_kinds);
+        header(
+            &mut out,
+            "bytes_total",
+            "Size of frames including headers by direction and kind.",
+            "counter",
+        );
+        per_kind::<P>(&mut out, "bytes_total", "in", &stats.incoming_kind_bytes);
+        per_kind::<P>(&mut out, "bytes_total", "out", &stats.outgoing_kind_bytes);
+
+        counter(
+            &mut out,
+            "compression_raw_bytes_total",
+            "Size of packets passed through compression before compressing.",
+            stats.raw_bytes,
+        );
+        counter(
+            &mut out,
+            "compression_compressed_bytes_total",
+            "Size of packets passed through compression after compressing.",
+            stats.compressed_bytes,
+        );
+        counter(
+            &mut out,
+            "decode_errors_total",
+            "Frames that couldn't be decoded.",
+            stats.decode_errors,
+        );
+        counter(
+            &mut out,
+            "encode_errors_total",
+            "Packets that couldn't be encoded.",
+            stats.encode_errors,
+        );
+        counter(
+            &mut out,
+            "plugin_calls_total",
+            "Lua plugin calls.",
+            stats.plugin_calls,
+        );
+        counter(
+            &mut out,
+            "plugin_errors_total",
+            "Lua plugin calls that failed.",
+            stats.plugin_errors,
+        );
+        counter(
+            &mut out,
+            "duplicates_total",
+            "Received packets suppressed or flagged as repeats.",
+            stats.duplicates,
+        );
+
+        header(
+            &mut out,
+            "request_duration_seconds",
+            "Round-trip time of send_for_response.",
+            "histogram",
+        );
+        let mut cumulative = 0;
+        for (i, count) in stats.rtt.buckets.iter().enumerate() {
+            cumulative += count;
+            let le = match RTT_BUCKETS_MS.get(i) {
+                Some(&bound) => (bound as f64 / 1000.0).to_string(),
+                None => "+Inf".to_string(),
+            };
+            let _ = writeln!(
+                out,
+                "rust_network_request_duration_seconds_bucket{{le=\"{}\"}} {}",
+                le, cumulative
+            );
+        }
+        let _ = writeln!(
+            out,
+            "rust_network_request_duration_seconds_sum {}",
+            stats.rtt.sum.as_secs_f64()
+        );
+        let _ = writeln!(
+            out,
+            "rust_network_request_duration_seconds_count {}",
+            stats.rtt.count()
+        );
+
+        out
+    }
+}
+
+fn header(out: &mut String, name: &str, help: &str, kind: &str) {
+    let _ = writeln!(out, "# HELP rust_network_{} {}", name, help);
+    let _ = writeln!(out, "# TYPE rust_network_{} {}", name, kind);
+}
+
+fn gauge<V: Display>(out: &mut String, name: &str, help: &str, value: V) {
+    header(out, name, help, "gauge");
+    let _ = writeln!(out, "rust_network_{} {}", name, value);
+}
+
+fn counter<V: Display>(out: &mut String, name: &str, help: &str, value: V) {
+    header(out, name, help, "counter");
+    let _ = writeln!(out, "rust_network_{} {}", name, value);
+}
+
+fn per_kind<P: Protocol>(
+    out: &mut String,
+    name: &str,
+    direction: &str,
+    values: &BTreeMap<u8, u64>,
+) {
+    for (&kind, value) in values {
+        let kind = match P::kind_name(kind) {
+            Some(name) => name.to_string(),
+            None => format!("{:#04x}", kind),
+        };
+        let _ = writeln!(
+            out,
+            "rust_network_{}{{direction=\"{}\",kind=\"{}\"}} {}",
+            name, direction, kind, value
+        );
+    }
+}
+
+/// Serve metrics collected by `collect` on `addr`. Fails only if `addr`
+/// can't be bound, errors accepting a connection are logged and retried.
+pub async fn serve<P, F, Fut>(addr: SocketAddr, collect: F) -> Result<(), IoError>
+where
+    P: Protocol,
+    F: Fn() -> Fut,
+    Fut: Future<Output = Metrics>,
+{
+    let listener = TcpListener::bind(&addr).await?;
+    info!(%addr, "Metrics endpoint bound");
+
+    loop {
+        let (mut stream, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(error) => {
+                warn!(%error, "Metrics endpoint accept error");
+                tokio::time::sleep(ACCEPT_ERROR_DELAY).await;
+                continue;
+            }
+        };
+        let response = respond::<P, _, _>(&mut stream, &collect);
+        match tokio::time::timeout(METRICS_REQUEST_TIMEOUT, response).await {
+            Ok(Ok(())) => {}
+            Ok(Err(error)) => debug!(%peer, %error, "Metrics request error"),
+            Err(_) => debug!(%peer, "Metrics request timed out"),
+        }
+    }
+}
+
+async fn respond<P, F, Fut>(stream: &mut TcpStream, collect: &F) -> Result<(), IoError>
+where
+    P: Protocol,
+    F: Fn() -> Fut,
+    Fut: Future<Output = Metrics>,
+{
+    let mut request = Vec::new();
+    let mut buf = [0; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") && request.len() < MAX_REQUEST_SIZE {
+        let n = stream.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        request.extend_from_slice(&buf[..n]);
+    }
+
+    let request = String::from_utf8_lossy(&request);
+    let mut request_line = request.lines().next().unwrap_or("").split(' ');
+    let method = request_line.next().unwrap_or("");
+    let path = request_line
+        .next()
+        .unwrap_or("")
+        .split('?')
+        .next()
+        .unwrap_or("");
+
+    let (status, body) = if method == "GET" && path == "/metrics" {
+        ("200 OK", collect().await.render::<P>())
+    } else {
+        ("404 Not Found", "Not Found\n".to_string())
+    };
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::packet_kind;
+    use crate::stats::Stats;
+    use crate::Packet;
+
+    fn metrics() -> Metrics {
+        let stats = Stats::new();
+        stats.counters.add_incoming(packet_kind::CHAT_MESSAGE, 40);
+        stats.counters.add_outgoing(0x7f, 9);
+        stats.counters.increase_connects();
+        stats.counters.increase_plugin_calls();
+        stats.counters.increase_duplicates();
+        stats.counters.record_rtt(Duration::from_millis(3));
+        stats.counters.record_rtt(Duration::from_secs(10));
+
+        Metrics {
+            stats: stats.snapshot(),
+            connected_clients: 2,
+            connection_attempts: None,
+            pending_requests: Some(1),
+        }
+    }
+
+    #[test]
+    fn render() {
+        let text = metrics().render::<Packet>();
+
+        assert!(text.contains("# TYPE rust_network_connected_clients gauge\n"));
+        assert!(text.contains("rust_network_connected_clients 2\n"));
+        assert!(!text.contains("rust_network_connection_attempts"));
+        assert!(text.contains("rust_network_pending_requests 1\n"));
+        assert!(text.contains("rust_network_connects_total 1\n"));
+        assert!(
+            text.contains("rust_network_packets_total{direction=\"in\",kind=\"ChatMessage\"} 1\n")
+        );
+        assert!(
+            text.contains("rust_network_bytes_total{direction=\"in\",kind=\"ChatMessage\"} 40\n")
+        );
+        assert!(text.contains("rust_network_packets_total{direction=\"out\",kind=\"0x7f\"} 1\n"));
+        assert!(text.contains("rust_network_plugin_calls_total 1\n"));
+        assert!(text.contains("rust_network_duplicates_total 1\n"));
+        assert!(text.contains("rust_network_request_duration_seconds_bucket{le=\"0.002\"} 0\n"));
+        assert!(text.contains("rust_network_request_duration_seconds_bucket{le=\"0.005\"} 1\n"));
+        assert!(text.contains("rust_network_request_duration_seconds_bucket{le=\"+Inf\"} 2\n"));
+        assert!(text.contains("rust_network_request_duration_seconds_count 2\n"));
+    }
+
+    async fn get(addr: SocketAddr, path: &str) -> String {
+        let mut stream = loop {
+            match TcpStream::connect(&addr).await {
+                Ok(stream) => break stream,
+                Err(_) => tokio::time::sleep(Duration::from_millis(10)).await,
+            }
+        };
+        let request = format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path);
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn serve_http() {
+        // nobody listens on this port once the listener is dropped
+        let addr = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        tokio::spawn(serve::<Packet, _, _>(addr, || async { metrics() }));
+
+        let response = get(addr, "/metrics").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with(&metrics().render::<Packet>()));
+
+        let response = get(addr, "/").await;
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+    }
+}
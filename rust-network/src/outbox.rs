@@ -0,0 +1,190 @@
+/*! Outbound queue of a disconnected `Client`
+
+With an outbox configured `Client::send_packet` doesn't fail while the client
+is `Disconnected` or `Connecting`. Packets are queued instead and sent in
+order before any other packet once the connection is established. The queue
+is bounded, `OverflowPolicy` decides what happens to a packet sent when it's
+full. Packets may expire so that stale ones aren't sent after a long outage.
+
+The outbox lives in memory only, packets queued when the process exits are
+lost.
+*/
+
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use tracing::debug;
+
+/// What to do with a packet sent when the outbox is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Drop the oldest queued packet to make room for the new one.
+    DropOldest,
+    /// Silently drop the new packet.
+    DropNewest,
+    /// Fail `send_packet` with `SendPacketErrorKind::OutboxFull`.
+    Reject,
+}
+
+/// Settings of the outbox of a client.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OutboxConfig {
+    capacity: usize,
+    overflow: OverflowPolicy,
+    expiry: Option<Duration>,
+}
+
+impl OutboxConfig {
+    /// Queue up to `capacity` packets rejecting new ones when it's full.
+    /// Packets never expire.
+    pub fn new(capacity: usize) -> OutboxConfig {
+        OutboxConfig {
+            capacity,
+            overflow: OverflowPolicy::Reject,
+            expiry: None,
+        }
+    }
+
+    /// Handle packets sent when the outbox is full according to the given
+    /// policy.
+    pub fn with_overflow(mut self, overflow: OverflowPolicy) -> OutboxConfig {
+        self.overflow = overflow;
+        self
+    }
+
+    /// Drop packets queued for longer than `expiry` instead of sending them.
+    pub fn with_expiry(mut self, expiry: Duration) -> OutboxConfig {
+        self.expiry = Some(expiry);
+        self
+    }
+}
+
+/// Result of queuing a packet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Push {
+    /// The packet is queued.
+    Queued,
+    /// The packet is dropped according to `OverflowPolicy::DropNewest`.
+    Dropped,
+    /// The outbox is full and rejects packets.
+    Full,
+}
+
+/// Queue of packets waiting for a connection.
+#[derive(Debug)]
+pub(crate) struct Outbox<P> {
+    config: OutboxConfig,
+    packets: VecDeque<(P, Instant)>,
+}
+
+impl<P> Outbox<P> {
+    pub fn new(config: OutboxConfig) -> Outbox<P> {
+        Outbox {
+            config,
+            packets: VecDeque::new(),
+        }
+    }
+
+    /// Number of queued packets including expired ones not removed yet.
+    pub fn len(&self) -> usize {
+        self.packets.len()
+    }
+
+    /// Queue a packet sent at `now`.
+    pub fn push(&mut self, packet: P, now: Instant) -> Push {
+        self.remove_expired(now);
+        if self.packets.len() >= self.config.capacity {
+            match self.config.overflow {
+                OverflowPolicy::DropOldest if self.config.capacity > 0 => {
+                    debug!("Outbox is full, dropping the oldest packet");
+                    self.packets.pop_front();
+                }
+                OverflowPolicy::DropOldest | OverflowPolicy::DropNewest => {
+                    debug!("Outbox is full, dropping the packet");
+                    return Push::Dropped;
+                }
+                OverflowPolicy::Reject => return Push::Full,
+            }
+        }
+        self.packets.push_back((packet, now));
+        Push::Queued
+    }
+
+    /// Take all packets that didn't expire by `now` in the order they were
+    /// queued.
+    pub fn drain(&mut self, now: Instant) -> Vec<P> {
+        self.remove_expired(now);
+        self.packets.drain(..).map(|(packet, _)| packet).collect()
+    }
+
+    fn remove_expired(&mut self, now: Instant) {
+        let expiry = match self.config.expiry {
+            Some(expiry) => expiry,
+            None => return,
+        };
+        let before = self.packets.len();
+        self.packets
+            .retain(|&(_, queued)| now.saturating_duration_since(queued) < expiry);
+        if self.packets.len() < before {
+            debug!(
+                expired = before - self.packets.len(),
+                "Outbox packets expired"
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn outbox(overflow: OverflowPolicy) -> Outbox<u32> {
+        Outbox::new(OutboxConfig::new(2).with_overflow(overflow))
+    }
+
+    #[test]
+    fn drop_oldest() {
+        let now = Instant::now();
+        let mut outbox = outbox(OverflowPolicy::DropOldest);
+        for packet in 1..=3 {
+            assert_eq!(outbox.push(packet, now), Push::Queued);
+        }
+        assert_eq!(outbox.drain(now), vec![2, 3]);
+        assert_eq!(outbox.len(), 0);
+    }
+
+    #[test]
+    fn drop_newest() {
+        let now = Instant::now();
+        let mut outbox = outbox(OverflowPolicy::DropNewest);
+        assert_eq!(outbox.push(1, now), Push::Queued);
+        assert_eq!(outbox.push(2, now), Push::Queued);
+        assert_eq!(outbox.push(3, now), Push::Dropped);
+        assert_eq!(outbox.drain(now), vec![1, 2]);
+    }
+
+    #[test]
+    fn reject() {
+        let now = Instant::now();
+        let mut outbox = outbox(OverflowPolicy::Reject);
+        assert_eq!(outbox.push(1, now), Push::Queued);
+        assert_eq!(outbox.push(2, now), Push::Queued);
+        assert_eq!(outbox.push(3, now), Push::Full);
+        assert_eq!(outbox.drain(now), vec![1, 2]);
+    }
+
+    #[test]
+    fn expiry() {
+        let start = Instant::now();
+        let mut outbox = Outbox::new(OutboxConfig::new(2).with_expiry(Duration::from_secs(10)));
+        assert_eq!(outbox.push(1, start), Push::Queued);
+        assert_eq!(outbox.push(2, start + Duration::from_secs(5)), Push::Queued);
+        // the first packet expired so there is room for another one
+        let later = start + Duration::from_secs(12);
+        assert_eq!(outbox.push(3, later), Push::Queued);
+        assert_eq!(outbox.drain(start + Duration::from_secs(14)), vec![2, 3]);
+
+        assert_eq!(outbox.push(4, later), Push::Queued);
+        assert!(outbox.drain(later + Duration::from_secs(10)).is_empty());
+    }
+}
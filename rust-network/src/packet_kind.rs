@@ -0,0 +1,109 @@
+/*! Registry of packet kinds
+
+Every `Packet` variant is serialized with a unique leading byte, the packet
+kind. All kinds are reserved here so that two packets never share the same
+tag and parsing does not depend on the order of parsers in `Packet`.
+`ChatMessage` and `PongResponse` keep the kinds they were sent with before
+the registry, `PingRequest` shared its kind with `ChatMessage` and got a new
+one.
+*/
+
+/// Kind of [`Hello`](../hello/struct.Hello.html) handshake packet.
+pub const HELLO: u8 = 0x01;
+/// Kind of [`HelloAck`](../hello/struct.HelloAck.html) handshake packet.
+pub const HELLO_ACK: u8 = 0x02;
+/// Kind of [`PingRequest`](../ping_request/struct.PingRequest.html) packet.
+pub const PING_REQUEST: u8 = 0x05;
+/// Kind of [`PongResponse`](../pong_response/struct.PongResponse.html) packet.
+pub const PONG_RESPONSE: u8 = 0xbf;
+/// Kind of [`ChatMessage`](../chatmsg/struct.ChatMessage.html) packet.
+pub const CHAT_MESSAGE: u8 = 0x04;
+
+/// All reserved packet kinds with names of the packets using them.
+pub const PACKET_KINDS: &[(u8, &str)] = &[
+    (PING_REQUEST, "PingRequest"),
+    (PONG_RESPONSE, "PongResponse"),
+    (CHAT_MESSAGE, "ChatMessage"),
+];
+
+/// Kinds reserved by handshake packets. They are exchanged before any
+/// `Packet` and are never decoded by `Codec`.
+pub const HANDSHAKE_KINDS: &[(u8, &str)] = &[(HELLO, "Hello"), (HELLO_ACK, "HelloAck")];
+
+// Reject duplicate kinds at compile time.
+const _: () = assert!(!has_duplicates(PACKET_KINDS), "duplicate packet kind");
+const _: () = assert!(!has_duplicates(HANDSHAKE_KINDS), "duplicate packet kind");
+const _: () = assert!(
+    !has_common(PACKET_KINDS, HANDSHAKE_KINDS),
+    "duplicate packet kind"
+);
+
+/// Check if the same kind is reserved more than once.
+const fn has_duplicates(kinds: &[(u8, &str)]) -> bool {
+    let mut i = 0;
+    while i < kinds.len() {
+        let mut j = i + 1;
+        while j < kinds.len() {
+            if kinds[i].0 == kinds[j].0 {
+                return true;
+            }
+            j += 1;
+        }
+        i += 1;
+    }
+    false
+}
+
+/// Check if any kind is reserved in both lists.
+const fn has_common(a: &[(u8, &str)], b: &[(u8, &str)]) -> bool {
+    let mut i = 0;
+    while i < a.len() {
+        let mut j = 0;
+        while j < b.len() {
+            if a[i].0 == b[j].0 {
+                return true;
+            }
+            j += 1;
+        }
+        i += 1;
+    }
+    false
+}
+
+/// Check if the kind is reserved by one of the packets.
+pub fn is_known(kind: u8) -> bool {
+    PACKET_KINDS.iter().any(|&(k, _)| k == kind)
+}
+
+/// Name of the packet using the kind.
+pub fn name(kind: u8) -> Option<&'static str> {
+    PACKET_KINDS
+        .iter()
+        .find(|&&(k, _)| k == kind)
+        .map(|&(_, name)| name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn no_duplicates() {
+        assert!(!has_duplicates(PACKET_KINDS));
+        assert!(has_duplicates(&[(1, "A"), (2, "B"), (1, "C")]));
+        assert!(!has_duplicates(HANDSHAKE_KINDS));
+        assert!(!has_common(PACKET_KINDS, HANDSHAKE_KINDS));
+        assert!(has_common(&[(1, "A")], &[(2, "B"), (1, "C")]));
+    }
+
+    #[test]
+    fn known_kinds() {
+        assert!(is_known(PING_REQUEST));
+        assert!(is_known(PONG_RESPONSE));
+        assert!(is_known(CHAT_MESSAGE));
+        assert!(!is_known(0x7f));
+        assert!(!is_known(HELLO));
+        assert_eq!(name(CHAT_MESSAGE), Some("ChatMessage"));
+        assert_eq!(name(0x7f), None);
+    }
+}
@@ -1,10 +1,10 @@
 /*! PingRequest packet
 */
 
-use bytes::BufMut;
+use bytes::{BufMut, BytesMut};
 use crate::errors::PacketError;
 use nom::{do_parse, named, number::streaming::be_u64, tag};
-use crate::{FromBytes, ToBytes};
+use crate::{packet_kind, FromBytes, ToBytes};
 
 /** Sent by both client and server, both will respond.
 Ping packets are used to know if the other side of the connection is still
@@ -19,12 +19,9 @@ The server should respond immediately to ping packets with pong packets.
 Serialized form:
 Length | Content
 ------ | ------
-`1`    | `0x04`
+`1`    | `0x05`
 `8`    | ping_id in BigEndian
 */
-
-/// The trait provides method to deserialize struct from raw bytes
-
 #[derive(Debug, PartialEq, Clone)]
 pub struct PingRequest {
     /// The id of ping
@@ -34,16 +31,15 @@ pub struct PingRequest {
 impl FromBytes for PingRequest {
     named!(
         from_bytes<PingRequest>,
-        do_parse!(tag!("\x04") >> ping_id: be_u64 >> (PingRequest { ping_id }))
+        do_parse!(tag!(&[packet_kind::PING_REQUEST][..]) >> ping_id: be_u64 >> (PingRequest { ping_id }))
     );
     
 }
 
 impl ToBytes for PingRequest {
-    fn to_bytes(&self) -> Result<Vec<u8>, PacketError> {
-        let mut buf = Vec::<u8>::new();
-        buf.put_u8(0x4);
+    fn to_bytes(&self, buf: &mut BytesMut) -> Result<(), PacketError> {
+        buf.put_u8(packet_kind::PING_REQUEST);
         buf.put_u64(self.ping_id);
-        Ok(buf.to_vec())
+        Ok(())
     }
 }
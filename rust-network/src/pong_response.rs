@@ -1,8 +1,8 @@
 /*! PongResponse packet
 */
 
-use crate::{FromBytes, ToBytes};
-use bytes::BufMut;
+use crate::{packet_kind, FromBytes, ToBytes};
+use bytes::{BufMut, BytesMut};
 use crate::errors::PacketError;
 use nom::{do_parse, named, number::streaming::be_u64, tag};
 
@@ -13,7 +13,7 @@ the same `ping_id` as was in the ping, if not the pong packet must be ignored.
 Serialized form:
 Length | Content
 ------ | ------
-`1`    | `0x05`
+`1`    | `0xbf`
 `8`    | ping_id in BigEndian
 */
 #[derive(Debug, PartialEq, Clone)]
@@ -25,15 +25,14 @@ pub struct PongResponse {
 impl FromBytes for PongResponse {
     named!(
         from_bytes<PongResponse>,
-        do_parse!(tag!(b"\xbf") >> ping_id: be_u64 >> (PongResponse { ping_id }))
+        do_parse!(tag!(&[packet_kind::PONG_RESPONSE][..]) >> ping_id: be_u64 >> (PongResponse { ping_id }))
     );
 }
 
 impl ToBytes for PongResponse {
-    fn to_bytes(&self) -> Result<Vec<u8>, PacketError> {
-        let mut buf = Vec::<u8>::new();
-        buf.put_u8(0xbf);
-        buf.put_u64(123);
-        Ok(buf.to_vec())
+    fn to_bytes(&self, buf: &mut BytesMut) -> Result<(), PacketError> {
+        buf.put_u8(packet_kind::PONG_RESPONSE);
+        buf.put_u64(self.ping_id);
+        Ok(())
     }
 }
@@ -0,0 +1,219 @@
+/*! Reconnect policy of a `Client`
+
+After a failed connection attempt the client waits before `Connections`
+spawns it again. The delay is either fixed or grows exponentially up to a
+cap, optionally shortened by a random jitter so that many clients dropped by
+the same relay don't reconnect at the same moment. A client may give up after
+a number of failed attempts, then it stays disconnected until it's spawned
+explicitly.
+
+Whether clients that gave up are removed from `Connections` is decided
+separately by `EvictionPolicy`.
+*/
+
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+use rand_core::{OsRng, RngCore};
+
+/// Delay before the first reconnect of the default policy.
+pub const DEFAULT_INITIAL_DELAY: Duration = Duration::from_secs(1);
+
+/// Maximum delay between reconnects of the default policy.
+pub const DEFAULT_MAX_DELAY: Duration = Duration::from_secs(60);
+
+/// Jitter of the default policy.
+pub const DEFAULT_JITTER: f64 = 0.2;
+
+/// Callback called with the client id and the number of failed attempts when
+/// a client gives up reconnecting.
+pub type GiveUpCallback = Arc<dyn Fn(&str, u32) + Send + Sync>;
+
+/// How the delay between reconnects grows.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Backoff {
+    /// Wait the same time after every failed attempt.
+    Fixed(Duration),
+    /// Double the delay after every failed attempt starting with `initial`
+    /// until it reaches `max`.
+    Exponential {
+        /// Delay after the first failed attempt.
+        initial: Duration,
+        /// Maximum delay.
+        max: Duration,
+    },
+}
+
+/// Decides when a disconnected client reconnects and when it gives up.
+#[derive(Clone)]
+pub struct ReconnectPolicy {
+    backoff: Backoff,
+    /// Fraction of the delay that is randomly cut off, from 0 to 1.
+    jitter: f64,
+    /// Number of failed attempts in a row after which the client gives up.
+    /// The client never gives up when `None`.
+    max_attempts: Option<u32>,
+    on_give_up: Option<GiveUpCallback>,
+}
+
+impl Default for ReconnectPolicy {
+    /// Exponential backoff from 1 second up to 1 minute with 20% jitter
+    /// that never gives up.
+    fn default() -> Self {
+        ReconnectPolicy::exponential(DEFAULT_INITIAL_DELAY, DEFAULT_MAX_DELAY)
+            .with_jitter(DEFAULT_JITTER)
+    }
+}
+
+impl fmt::Debug for ReconnectPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ReconnectPolicy")
+            .field("backoff", &self.backoff)
+            .field("jitter", &self.jitter)
+            .field("max_attempts", &self.max_attempts)
+            .field("on_give_up", &self.on_give_up.is_some())
+            .finish()
+    }
+}
+
+impl ReconnectPolicy {
+    /// Reconnect after the same delay every time.
+    pub fn fixed(delay: Duration) -> ReconnectPolicy {
+        ReconnectPolicy::new(Backoff::Fixed(delay))
+    }
+
+    /// Reconnect after a delay doubled with every failed attempt up to
+    /// `max`.
+    pub fn exponential(initial: Duration, max: Duration) -> ReconnectPolicy {
+        ReconnectPolicy::new(Backoff::Exponential { initial, max })
+    }
+
+    fn new(backoff: Backoff) -> ReconnectPolicy {
+        ReconnectPolicy {
+            backoff,
+            jitter: 0.0,
+            max_attempts: None,
+            on_give_up: None,
+        }
+    }
+
+    /// Cut a random part of up to `jitter` of every delay, e.g. `0.2` waits
+    /// from 80% to 100% of the delay. The value is clamped to `0..=1`.
+    pub fn with_jitter(mut self, jitter: f64) -> ReconnectPolicy {
+        self.jitter = jitter.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Give up after the given number of failed attempts in a row.
+    pub fn with_max_attempts(mut self, max_attempts: u32) -> ReconnectPolicy {
+        self.max_attempts = Some(max_attempts);
+        self
+    }
+
+    /// Call `callback` with the client id and the number of failed attempts
+    /// when the client gives up.
+    pub fn with_give_up<F>(mut self, callback: F) -> ReconnectPolicy
+    where
+        F: Fn(&str, u32) + Send + Sync + 'static,
+    {
+        self.on_give_up = Some(Arc::new(callback));
+        self
+    }
+
+    /// Backoff of this policy.
+    pub fn backoff(&self) -> Backoff {
+        self.backoff
+    }
+
+    /// Check if the client should stop reconnecting after the given number
+    /// of failed attempts in a row.
+    pub fn gives_up(&self, attempts: u32) -> bool {
+        self.max_attempts.is_some_and(|max| attempts >= max)
+    }
+
+    /// Delay before the next attempt after the given number of failed
+    /// attempts in a row, including jitter.
+    pub fn delay(&self, attempts: u32) -> Duration {
+        let delay = self.base_delay(attempts);
+        if self.jitter > 0.0 {
+            let random = OsRng.next_u64() as f64 / u64::MAX as f64;
+            delay.mul_f64(1.0 - self.jitter * random)
+        } else {
+            delay
+        }
+    }
+
+    /// Delay without jitter.
+    fn base_delay(&self, attempts: u32) -> Duration {
+        match self.backoff {
+            Backoff::Fixed(delay) => delay,
+            Backoff::Exponential { initial, max } => {
+                let exponent = attempts.saturating_sub(1).min(31);
+                initial
+                    .checked_mul(1 << exponent)
+                    .map_or(max, |delay| delay.min(max))
+            }
+        }
+    }
+
+    /// Call the give up callback if there is one.
+    pub(crate) fn give_up(&self, client_id: &str, attempts: u32) {
+        if let Some(ref on_give_up) = self.on_give_up {
+            on_give_up(client_id, attempts);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Mutex;
+
+    #[test]
+    fn fixed() {
+        let policy = ReconnectPolicy::fixed(Duration::from_secs(3));
+        assert_eq!(policy.delay(1), Duration::from_secs(3));
+        assert_eq!(policy.delay(100), Duration::from_secs(3));
+        assert!(!policy.gives_up(u32::MAX));
+    }
+
+    #[test]
+    fn exponential_with_cap() {
+        let policy = ReconnectPolicy::exponential(Duration::from_secs(1), Duration::from_secs(10));
+        let delays: Vec<_> = (1..=6).map(|attempts| policy.delay(attempts)).collect();
+        let expected: Vec<_> = [1, 2, 4, 8, 10, 10]
+            .iter()
+            .map(|&secs| Duration::from_secs(secs))
+            .collect();
+        assert_eq!(delays, expected);
+        assert_eq!(policy.delay(u32::MAX), Duration::from_secs(10));
+    }
+
+    #[test]
+    fn jitter() {
+        let policy = ReconnectPolicy::fixed(Duration::from_secs(10)).with_jitter(0.5);
+        for _ in 0..100 {
+            let delay = policy.delay(1);
+            assert!(delay >= Duration::from_secs(5));
+            assert!(delay <= Duration::from_secs(10));
+        }
+    }
+
+    #[test]
+    fn max_attempts_and_give_up() {
+        let given_up = Arc::new(Mutex::new(Vec::new()));
+        let given_up_c = given_up.clone();
+        let policy = ReconnectPolicy::default()
+            .with_max_attempts(3)
+            .with_give_up(move |id, attempts| {
+                given_up_c.lock().unwrap().push((id.to_string(), attempts))
+            });
+        assert!(!policy.gives_up(2));
+        assert!(policy.gives_up(3));
+
+        policy.give_up("client", 3);
+        assert_eq!(*given_up.lock().unwrap(), vec![("client".to_string(), 3)]);
+    }
+}
@@ -0,0 +1,376 @@
+/*! Encrypted transport session
+
+When secure mode is enabled both sides perform a handshake right after the TCP
+connection is established and before any `Packet` is sent:
+
+Length | Content
+------ | ------
+`32`   | Long-term static public key of the sender
+`32`   | Ephemeral public key of the sender
+
+The client sends its keys first, then the server answers with its keys. Both
+sides compute three x25519 shared secrets (ephemeral-ephemeral,
+client ephemeral-server static and client static-server ephemeral) and derive
+one ChaCha20-Poly1305 key per direction from them. Only the owners of the
+announced static keys are able to derive the same session keys. Every frame
+after the handshake is encrypted and the nonce is a per-direction counter.
+*/
+
+use std::fmt;
+use std::io::Error as IoError;
+
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use failure::Fail;
+use rand_core::{OsRng, RngCore};
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::errors::*;
+
+pub use x25519_dalek::{PublicKey, StaticSecret};
+
+/// Size of serialized x25519 public key.
+pub const PUBLIC_KEY_SIZE: usize = 32;
+
+/// Size of the authentication tag appended to every encrypted frame.
+pub const TAG_SIZE: usize = 16;
+
+/// Label mixed into the derived session keys.
+const PROTOCOL_LABEL: &[u8] = b"rust-network secure session v1";
+
+/// Generate a random x25519 secret key.
+pub fn gen_secret_key() -> StaticSecret {
+    let mut bytes = [0; 32];
+    OsRng.fill_bytes(&mut bytes);
+    StaticSecret::from(bytes)
+}
+
+/// Keys used to establish a secure session.
+#[derive(Clone)]
+pub struct SecureConfig {
+    /// Long-term static secret key of this side.
+    secret_key: StaticSecret,
+    /// Public key of `secret_key`.
+    public_key: PublicKey,
+    /// Static public key the server must present. Only used by the client.
+    server_key: Option<PublicKey>,
+}
+
+impl SecureConfig {
+    /// Create a config with the given long-term static secret key.
+    pub fn new(secret_key: StaticSecret) -> SecureConfig {
+        let public_key = PublicKey::from(&secret_key);
+        SecureConfig {
+            secret_key,
+            public_key,
+            server_key: None,
+        }
+    }
+
+    /// Create a config with a random long-term static secret key.
+    pub fn generate() -> SecureConfig {
+        SecureConfig::new(gen_secret_key())
+    }
+
+    /// Pin the static public key of the server. The client will refuse to
+    /// talk to a server presenting any other key.
+    pub fn pin_server_key(mut self, server_key: PublicKey) -> SecureConfig {
+        self.server_key = Some(server_key);
+        self
+    }
+
+    /// Long-term static public key of this side.
+    pub fn public_key(&self) -> PublicKey {
+        self.public_key
+    }
+
+    /// Pinned static public key of the server.
+    pub fn server_key(&self) -> Option<PublicKey> {
+        self.server_key
+    }
+}
+
+impl fmt::Debug for SecureConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SecureConfig")
+            .field("public_key", &hex::encode(self.public_key.as_bytes()))
+            .field(
+                "server_key",
+                &self.server_key.map(|key| hex::encode(key.as_bytes())),
+            )
+            .finish()
+    }
+}
+
+/// Established secure session. Encrypts outgoing and decrypts incoming frames.
+pub struct Session {
+    /// Cipher for outgoing frames.
+    encrypt: ChaCha20Poly1305,
+    /// Cipher for incoming frames.
+    decrypt: ChaCha20Poly1305,
+    /// Nonce counter of outgoing frames.
+    send_nonce: u64,
+    /// Nonce counter of incoming frames.
+    recv_nonce: u64,
+    /// Static public key of the other side.
+    peer_key: PublicKey,
+}
+
+impl Session {
+    /// Static public key of the other side.
+    pub fn peer_key(&self) -> PublicKey {
+        self.peer_key
+    }
+
+    /// Encrypt outgoing frame.
+    pub fn encrypt(&mut self, plain: &[u8]) -> Option<Vec<u8>> {
+        let nonce = next_nonce(&mut self.send_nonce)?;
+        self.encrypt.encrypt(&nonce, plain).ok()
+    }
+
+    /// Decrypt incoming frame.
+    pub fn decrypt(&mut self, encrypted: &[u8]) -> Option<Vec<u8>> {
+        let nonce = next_nonce(&mut self.recv_nonce)?;
+        self.decrypt.decrypt(&nonce, encrypted).ok()
+    }
+}
+
+impl fmt::Debug for Session {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Session")
+            .field("peer_key", &hex::encode(self.peer_key.as_bytes()))
+            .field("send_nonce", &self.send_nonce)
+            .field("recv_nonce", &self.recv_nonce)
+            .finish()
+    }
+}
+
+/// Take the next nonce from the counter. Returns `None` when the counter is
+/// exhausted since a nonce must never be reused with the same key.
+fn next_nonce(counter: &mut u64) -> Option<Nonce> {
+    let value = *counter;
+    *counter = counter.checked_add(1)?;
+    let mut nonce = [0; 12];
+    nonce[4..].copy_from_slice(&value.to_be_bytes());
+    Some(*Nonce::from_slice(&nonce))
+}
+
+/// Keys announced by one side of the handshake.
+struct Hello {
+    static_key: PublicKey,
+    ephemeral_key: PublicKey,
+}
+
+async fn write_hello<S>(
+    stream: &mut S,
+    static_key: &PublicKey,
+    ephemeral_key: &PublicKey,
+) -> Result<(), IoError>
+where
+    S: AsyncWrite + Unpin,
+{
+    let mut buf = [0; PUBLIC_KEY_SIZE * 2];
+    buf[..PUBLIC_KEY_SIZE].copy_from_slice(static_key.as_bytes());
+    buf[PUBLIC_KEY_SIZE..].copy_from_slice(ephemeral_key.as_bytes());
+    stream.write_all(&buf).await?;
+    stream.flush().await
+}
+
+async fn read_hello<S>(stream: &mut S) -> Result<Hello, IoError>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut static_key = [0; PUBLIC_KEY_SIZE];
+    let mut ephemeral_key = [0; PUBLIC_KEY_SIZE];
+    stream.read_exact(&mut static_key).await?;
+    stream.read_exact(&mut ephemeral_key).await?;
+    Ok(Hello {
+        static_key: PublicKey::from(static_key),
+        ephemeral_key: PublicKey::from(ephemeral_key),
+    })
+}
+
+/// Derive the key of one direction from the handshake transcript.
+fn derive_key(transcript: &[u8], direction: &[u8]) -> Key {
+    let mut hasher = Sha256::new();
+    hasher.update(PROTOCOL_LABEL);
+    hasher.update(transcript);
+    hasher.update(direction);
+    hasher.finalize()
+}
+
+/// Build the session from the public keys of both sides and the shared
+/// secrets.
+fn new_session(client: &Hello, server: &Hello, shared: [[u8; 32]; 3], is_client: bool) -> Session {
+    let mut transcript = Vec::with_capacity(PUBLIC_KEY_SIZE * 7);
+    transcript.extend_from_slice(client.static_key.as_bytes());
+    transcript.extend_from_slice(client.ephemeral_key.as_bytes());
+    transcript.extend_from_slice(server.static_key.as_bytes());
+    transcript.extend_from_slice(server.ephemeral_key.as_bytes());
+    for secret in shared.iter() {
+        transcript.extend_from_slice(secret);
+    }
+
+    let to_server = ChaCha20Poly1305::new(&derive_key(&transcript, b"client to server"));
+    let to_client = ChaCha20Poly1305::new(&derive_key(&transcript, b"server to client"));
+    let (encrypt, decrypt, peer_key) = if is_client {
+        (to_server, to_client, server.static_key)
+    } else {
+        (to_client, to_server, client.static_key)
+    };
+
+    Session {
+        encrypt,
+        decrypt,
+        send_nonce: 0,
+        recv_nonce: 0,
+        peer_key,
+    }
+}
+
+/// Run the client side of the handshake and establish the session.
+pub async fn client_handshake<S>(
+    stream: &mut S,
+    config: &SecureConfig,
+) -> Result<Session, HandshakeError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let ephemeral_secret = gen_secret_key();
+    let client = Hello {
+        static_key: config.public_key,
+        ephemeral_key: PublicKey::from(&ephemeral_secret),
+    };
+    write_hello(stream, &client.static_key, &client.ephemeral_key)
+        .await
+        .map_err(|e| e.context(HandshakeErrorKind::Io))?;
+    let server = read_hello(stream)
+        .await
+        .map_err(|e| e.context(HandshakeErrorKind::Io))?;
+
+    if let Some(ref server_key) = config.server_key {
+        if server_key.as_bytes() != server.static_key.as_bytes() {
+            return Err(HandshakeErrorKind::ServerKeyMismatch.into());
+        }
+    }
+
+    let shared = [
+        ephemeral_secret
+            .diffie_hellman(&server.ephemeral_key)
+            .to_bytes(),
+        ephemeral_secret
+            .diffie_hellman(&server.static_key)
+            .to_bytes(),
+        config
+            .secret_key
+            .diffie_hellman(&server.ephemeral_key)
+            .to_bytes(),
+    ];
+    Ok(new_session(&client, &server, shared, true))
+}
+
+/// Run the server side of the handshake and establish the session.
+pub async fn server_handshake<S>(stream: &mut S, config: &SecureConfig) -> Result<Session, IoError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let client = read_hello(stream).await?;
+    let ephemeral_secret = gen_secret_key();
+    let server = Hello {
+        static_key: config.public_key,
+        ephemeral_key: PublicKey::from(&ephemeral_secret),
+    };
+    write_hello(stream, &server.static_key, &server.ephemeral_key).await?;
+
+    let shared = [
+        ephemeral_secret
+            .diffie_hellman(&client.ephemeral_key)
+            .to_bytes(),
+        config
+            .secret_key
+            .diffie_hellman(&client.ephemeral_key)
+            .to_bytes(),
+        ephemeral_secret
+            .diffie_hellman(&client.static_key)
+            .to_bytes(),
+    ];
+    Ok(new_session(&client, &server, shared, false))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn handshake(
+        client_config: SecureConfig,
+        server_config: SecureConfig,
+    ) -> (Result<Session, HandshakeError>, Result<Session, IoError>) {
+        let (mut client_stream, mut server_stream) = tokio::io::duplex(1024);
+        let server =
+            tokio::spawn(async move { server_handshake(&mut server_stream, &server_config).await });
+        let client = client_handshake(&mut client_stream, &client_config).await;
+        (client, server.await.unwrap())
+    }
+
+    #[tokio::test]
+    async fn encrypt_decrypt() {
+        let client_config = SecureConfig::generate();
+        let server_config = SecureConfig::generate();
+        let (client, server) = handshake(
+            client_config
+                .clone()
+                .pin_server_key(server_config.public_key()),
+            server_config.clone(),
+        )
+        .await;
+        let mut client = client.unwrap();
+        let mut server = server.unwrap();
+
+        assert_eq!(client.peer_key(), server_config.public_key());
+        assert_eq!(server.peer_key(), client_config.public_key());
+
+        for i in 0..3u8 {
+            let encrypted = client.encrypt(&[i; 10]).unwrap();
+            assert_eq!(encrypted.len(), 10 + TAG_SIZE);
+            assert_eq!(server.decrypt(&encrypted).unwrap(), vec![i; 10]);
+
+            let encrypted = server.encrypt(&[i; 5]).unwrap();
+            assert_eq!(client.decrypt(&encrypted).unwrap(), vec![i; 5]);
+        }
+    }
+
+    #[tokio::test]
+    async fn decrypt_tampered() {
+        let (client, server) = handshake(SecureConfig::generate(), SecureConfig::generate()).await;
+        let mut client = client.unwrap();
+        let mut server = server.unwrap();
+
+        let mut encrypted = client.encrypt(b"hello").unwrap();
+        encrypted[0] ^= 1;
+        assert!(server.decrypt(&encrypted).is_none());
+    }
+
+    #[tokio::test]
+    async fn decrypt_replayed() {
+        let (client, server) = handshake(SecureConfig::generate(), SecureConfig::generate()).await;
+        let mut client = client.unwrap();
+        let mut server = server.unwrap();
+
+        let encrypted = client.encrypt(b"hello").unwrap();
+        assert!(server.decrypt(&encrypted).is_some());
+        assert!(server.decrypt(&encrypted).is_none());
+    }
+
+    #[tokio::test]
+    async fn server_key_mismatch() {
+        let client_config =
+            SecureConfig::generate().pin_server_key(SecureConfig::generate().public_key());
+        let (client, _server) = handshake(client_config, SecureConfig::generate()).await;
+
+        assert_eq!(
+            *client.unwrap_err().kind(),
+            HandshakeErrorKind::ServerKeyMismatch
+        );
+    }
+}
@@ -1,15 +1,25 @@
-use crate::codec::Codec;
+use crate::codec::{Codec, MAX_FRAME_SIZE};
 use crate::connections::Connections;
+use crate::errors::HandshakeError;
+use crate::handshake::{self, Capabilities, Negotiated};
+use crate::hello::{CAPABILITY_COMPRESSION, CAPABILITY_ENCRYPTION};
 use crate::{
     chatmsg::ChatMessage,
+    compression::Compression,
     codec::{DecodeError, EncodeError},
+    metrics::{self, Metrics},
     ping_request::PingRequest,
     pong_response::PongResponse,
-    stats::Stats,
-    Packet,
+    secure::{self, SecureConfig, Session},
+    stats::{Stats, StatsSnapshot},
+    tls::TlsServer,
+    transport::{Acceptor, BoxedStream},
+    websocket::{WebSocketAcceptor, WebSocketConfig},
+    Packet, Protocol,
 };
 use failure::Fail;
 use futures::channel::mpsc::{self, Sender};
+use futures::future::BoxFuture;
 use futures::FutureExt;
 use futures::TryFutureExt;
 use futures::{SinkExt, StreamExt, TryStreamExt};
@@ -26,17 +36,22 @@ use std::{
     time::Duration,
 };
 use tokio::{
+    io::{AsyncRead, AsyncWrite},
     net::{TcpListener, TcpStream},
-    sync::RwLock,
-    time::error::Error as TimerError,
+    sync::{Mutex, RwLock},
+    time::{error::Error as TimerError, Instant},
 };
 use tokio_util::codec::Framed;
+use tracing::{debug, field, info, info_span, trace, warn, Instrument, Span};
 
 /// Interval of time for Tcp Ping sender
 const TCP_PING_INTERVAL: Duration = Duration::from_secs(5);
 
+/// Clients that didn't answer pings for this long are disconnected.
+const TCP_PING_TIMEOUT: Duration = Duration::from_secs(15);
+
 /// Interval of time for the TCP handshake.
-//const TCP_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+const TCP_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
 
 const SERVER_CHANNEL_SIZE: usize = 2;
 
@@ -63,6 +78,13 @@ pub enum ServerRunError {
         #[fail(cause)]
         error: IoError,
     },
+    /// Metrics endpoint IO error
+    #[fail(display = "Metrics endpoint IO error: {:?}", error)]
+    MetricsError {
+        /// IO error
+        #[fail(cause)]
+        error: IoError,
+    },
 }
 
 /// Error that can happen during TCP connection execution
@@ -101,6 +123,26 @@ pub enum ConnectionError {
         #[fail(cause)]
         error: IoError,
     },
+    /// TLS handshake error
+    #[fail(display = "TLS handshake error: {:?}", error)]
+    TlsHandshakeError {
+        /// TLS handshake error
+        #[fail(cause)]
+        error: IoError,
+    },
+    /// Client didn't answer pings in time
+    #[fail(display = "Client didn't answer pings for {:?}", timeout)]
+    PingTimeoutError {
+        /// Time since the last pong
+        timeout: Duration,
+    },
+    /// Client was rejected during the handshake
+    #[fail(display = "Server handshake error: {}", error)]
+    ServerHandshakeError {
+        /// Server handshake error
+        #[fail(cause)]
+        error: HandshakeError,
+    },
     /// Packet handling error
     #[fail(display = "Packet handling error: {:?}", error)]
     PacketHandlingError {
@@ -124,10 +166,49 @@ pub enum ConnectionError {
     },
 }
 
+/// Application logic of the `Server`.
+pub trait ServerHandler<P>: Send + Sync + 'static {
+    /// Handle packet received from a client. Packets sent to `tx` are sent
+    /// back to this client.
+    fn handle_packet(&self, packet: P, tx: Sender<P>) -> BoxFuture<'static, Result<(), Error>>;
+}
+
 #[derive(Clone)]
-pub struct Server {
-   
-    pub clients: Arc<RwLock<HashMap<String, Sender<Packet>>>>,
+pub struct Server<P = Packet> {
+    pub clients: Arc<RwLock<HashMap<String, Sender<P>>>>,
+    handler: Arc<dyn ServerHandler<P>>,
+    /// Keys of the secure session. Plaintext connections are accepted when
+    /// `None`.
+    secure: Option<Arc<SecureConfig>>,
+    /// Compression offered to clients. Frames are compressed only if the
+    /// client supports it too.
+    compression: Option<Compression>,
+    /// Counters of connected clients. Key is the same as in `clients`.
+    client_stats: Arc<RwLock<HashMap<String, Stats>>>,
+    /// Address of the Prometheus metrics endpoint started by `tcp_run`.
+    metrics_addr: Option<SocketAddr>,
+    /// TLS settings. Accepted streams are used as is when `None`.
+    tls: Option<TlsServer>,
+    /// Interval of time between pings sent to every client. Pings are not
+    /// sent when `None`.
+    ping_interval: Option<Duration>,
+    /// Clients that didn't answer pings for this long are disconnected.
+    ping_timeout: Duration,
+}
+
+/// Liveness of a connected client.
+#[derive(Debug, Clone, Copy)]
+struct Liveness {
+    /// Id of the last ping sent to the client if it wasn't answered yet.
+    ping_id: Option<u64>,
+    /// Time when the client last answered a ping or connected.
+    last_pong: Instant,
+}
+
+impl Default for Server {
+    fn default() -> Self {
+        Server::new()
+    }
 }
 
 #[derive(Default, Clone)]
@@ -140,88 +221,211 @@ impl Server {
     Create a new `Server` without onion
     */
     pub fn new() -> Server {
+        Server::with_handler(ChatHandler)
+    }
+}
+
+impl<P: Protocol> Server<P> {
+    /// Create a new `Server` handling packets with the given handler.
+    pub fn with_handler<H: ServerHandler<P>>(handler: H) -> Server<P> {
         Server {
             clients: Arc::new(RwLock::new(HashMap::new())),
+            handler: Arc::new(handler),
+            secure: None,
+            compression: None,
+            client_stats: Arc::new(RwLock::new(HashMap::new())),
+            metrics_addr: None,
+            tls: None,
+            ping_interval: Some(TCP_PING_INTERVAL),
+            ping_timeout: TCP_PING_TIMEOUT,
+        }
+    }
+
+    /// Require every client to establish a secure session using the given
+    /// keys.
+    pub fn with_secure(mut self, config: SecureConfig) -> Server<P> {
+        self.secure = Some(Arc::new(config));
+        self
+    }
+
+    /// Compress large packets sent to clients that support compression.
+    pub fn with_compression(mut self, compression: Compression) -> Server<P> {
+        self.compression = Some(compression);
+        self
+    }
+
+    /// Require every client to connect with TLS using the given certificate.
+    pub fn with_tls(mut self, tls: TlsServer) -> Server<P> {
+        self.tls = Some(tls);
+        self
+    }
+
+    /// Ping every client each `interval` and disconnect clients that
+    /// didn't answer for `timeout`. Pings are disabled with `None`.
+    pub fn with_heartbeat(mut self, interval: Option<Duration>, timeout: Duration) -> Server<P> {
+        self.ping_interval = interval;
+        self.ping_timeout = timeout;
+        self
+    }
+
+    /// Serve Prometheus metrics on the given address while `tcp_run` is
+    /// running.
+    pub fn with_metrics(mut self, addr: SocketAddr) -> Server<P> {
+        self.metrics_addr = Some(addr);
+        self
+    }
+
+    /// Metrics exported by the Prometheus endpoint. `stats` are the counters
+    /// passed to `tcp_run`.
+    pub async fn metrics(&self, stats: &Stats) -> Metrics {
+        Metrics {
+            stats: stats.snapshot(),
+            connected_clients: self.client_stats.read().await.len(),
+            connection_attempts: None,
+            pending_requests: None,
         }
     }
+
+    /// Snapshot of counters of every connected client. Counters aggregated
+    /// over all clients are kept by `Stats` passed to `tcp_run`.
+    pub async fn client_stats(&self) -> HashMap<String, StatsSnapshot> {
+        self.client_stats
+            .read()
+            .await
+            .iter()
+            .map(|(addr, stats)| (addr.clone(), stats.snapshot()))
+            .collect()
+    }
+
+    /// 解析接收的请求
+    pub async fn handle_packet(&self, packet: P, tx: Sender<P>) -> Result<(), Error> {
+        self.handler.handle_packet(packet, tx).await
+    }
+}
+
+/// Built-in `ServerHandler` for `Packet` that answers every `ChatMessage`.
+pub struct ChatHandler;
+
+impl ServerHandler<Packet> for ChatHandler {
+    fn handle_packet(
+        &self,
+        packet: Packet,
+        tx: Sender<Packet>,
+    ) -> BoxFuture<'static, Result<(), Error>> {
+        ChatHandler::handle_packet(packet, tx).boxed()
+    }
+}
+
+impl ChatHandler {
     /// 解析接收的请求
-    pub async fn handle_packet(&self, packet: Packet, mut tx: Sender<Packet>) -> Result<(), Error> {
+    async fn handle_packet(packet: Packet, mut tx: Sender<Packet>) -> Result<(), Error> {
         match packet {
-            Packet::PingRequest(packet) => self.handle_ping_request(&packet).await,
-            Packet::PongResponse(packet) => self.handle_pong_response(&packet).await,
+            Packet::PingRequest(packet) => ChatHandler::handle_ping_request(&packet).await,
+            Packet::PongResponse(packet) => ChatHandler::handle_pong_response(&packet).await,
             Packet::ChatMessage(mut p) => {
-                println!(
-                    "收到客户端消息 消息ID {} to {} from {} content {}",
-                    p.msg_id,
-                    &p.to_user,
-                    &p.from_user,
-                    std::str::from_utf8(&p.content).unwrap().to_string()
+                debug!(
+                    msg_id = p.msg_id,
+                    to_user = %p.to_user,
+                    from_user = %p.from_user,
+                    "chat message received"
                 );
+                trace!(content = %String::from_utf8_lossy(&p.content));
                 p.content = format!("{}{}", "来自服务端消息", Connections::gen_random_string(16))
-                    .as_bytes()
-                    .to_vec();
+                    .into();
+                // the reply goes back to the sender
+                std::mem::swap(&mut p.to_user, &mut p.from_user);
 
-                println!(
-                    "发送内容 {}",
-                    std::str::from_utf8(&p.content).unwrap().to_string()
-                );
+                trace!(content = %String::from_utf8_lossy(&p.content), "chat message reply");
                 tx.send(Packet::ChatMessage(p)).await;
                 Ok(())
             }
         }
     }
 
-    /// 解析ping
-    async fn handle_ping_request(&self, packet: &PingRequest) -> Result<(), Error> {
+    /// 解析ping. Valid pings are answered by the connection before they
+    /// reach the handler so only pings with id 0 end up here.
+    async fn handle_ping_request(packet: &PingRequest) -> Result<(), Error> {
         if packet.ping_id == 0 {
-            return Err(Error::new(ErrorKind::Other, "PingRequest.ping_id == 0"));
+            return Err(Error::other("PingRequest.ping_id == 0"));
         }
-        // let state = self.state.read().await;
-        // if let Some(client_a) = state.connected_clients.get(pk) {
-        //     client_a.send_pong_response(packet.ping_id).await
-        // } else {
-        //     Err(Error::new(ErrorKind::Other, "PingRequest: no such PK"))
-        // }
         Ok(())
     }
-    /// 解析pong
-    async fn handle_pong_response(&self, packet: &PongResponse) -> Result<(), Error> {
+    /// 解析pong. Pongs answering the last ping are consumed by the
+    /// connection, late ones are ignored.
+    async fn handle_pong_response(packet: &PongResponse) -> Result<(), Error> {
         if packet.ping_id == 0 {
-            return Err(Error::new(ErrorKind::Other, "PongResponse.ping_id == 0"));
+            return Err(Error::other("PongResponse.ping_id == 0"));
         }
-        //let mut state = self.state.write().await;
-        //     if let Some(client_a) = state.connected_clients.get_mut(pk) {
-        //         if packet.ping_id == client_a.ping_id() {
-        //             client_a.set_last_pong_resp(Instant::now());
-
-        //             Ok(())
-        //         } else {
-        //             Err(Error::new(ErrorKind::Other, "PongResponse.ping_id does not match"))
-        //         }
-        //     } else {
-        //         Err(Error::new(ErrorKind::Other, "PongResponse: no such PK"))
-        //     }
-        // }
+        trace!(ping_id = packet.ping_id, "Ignoring late pong");
         Ok(())
     }
 }
 /// Running TCP ping sender and incoming `TcpStream`. This function uses
 /// `tokio::spawn` inside so it should be executed via tokio to be able to
 /// get tokio default executor.
-pub async fn tcp_run(
-    server: &Server,
+pub async fn tcp_run<P: Protocol>(
+    server: &Server<P>,
     addr: SocketAddr,
     stats: Stats,
     connections_limit: usize,
+) -> Result<(), ServerRunError> {
+    let listener = TcpListener::bind(&addr)
+        .await
+        .map_err(|error| ServerRunError::IncomingError { error })?;
+
+    info!(%addr, "Tcp server bound");
+    run(server, listener, stats, connections_limit).await
+}
+
+/// Running ping sender and incoming WebSocket connections. Clients connect
+/// with `WebSocketTransport` using the same path.
+pub async fn ws_run<P: Protocol>(
+    server: &Server<P>,
+    addr: SocketAddr,
+    config: WebSocketConfig,
+    stats: Stats,
+    connections_limit: usize,
+) -> Result<(), ServerRunError> {
+    let listener = TcpListener::bind(&addr)
+        .await
+        .map_err(|error| ServerRunError::IncomingError { error })?;
+
+    info!(%addr, "WebSocket server bound");
+    let acceptor = WebSocketAcceptor::new(listener, config);
+    run(server, acceptor, stats, connections_limit).await
+}
+
+/// Running ping sender and incoming Unix socket connections on the given
+/// path. Use `run` with a `UnixAcceptor` from `bind_with_permissions` to set
+/// permissions of the socket file or restrict users.
+#[cfg(unix)]
+pub async fn unix_run<P: Protocol>(
+    server: &Server<P>,
+    path: &std::path::Path,
+    stats: Stats,
+    connections_limit: usize,
+) -> Result<(), ServerRunError> {
+    let acceptor = crate::unix::UnixAcceptor::bind(path)
+        .map_err(|error| ServerRunError::IncomingError { error })?;
+
+    info!(path = %path.display(), "Unix socket server bound");
+    run(server, acceptor, stats, connections_limit).await
+}
+
+/// Running ping sender and connections accepted by the acceptor. This
+/// function uses `tokio::spawn` inside so it should be executed via tokio to
+/// be able to get tokio default executor.
+pub async fn run<P: Protocol, A: Acceptor>(
+    server: &Server<P>,
+    acceptor: A,
+    stats: Stats,
+    connections_limit: usize,
 ) -> Result<(), ServerRunError> {
     let connections_count = Arc::new(AtomicUsize::new(0));
-    
-    let listener = TcpListener::bind(&addr).await.unwrap();
 
-    println!("Tcp server bind {} ", addr);
     let connections_future = async {
         loop {
-            let (stream, _) = listener
+            let (stream, peer) = acceptor
                 .accept()
                 .await
                 .map_err(|error| ServerRunError::IncomingError { error })?;
@@ -233,10 +437,13 @@ pub async fn tcp_run(
                 let server = server.clone();
 
                 tokio::spawn(async move {
-                    let res = tcp_run_connection(&server, stream, stats).await;
+                    let span = info_span!("connection", %peer, client_id = field::Empty);
+                    let res = run_connection(&server, stream, peer.clone(), stats)
+                        .instrument(span)
+                        .await;
 
                     if let Err(ref e) = res {
-                        println!("Error while running tcp connection: {:?}", e)
+                        warn!(%peer, error = %e, "Error while running tcp connection")
                     }
 
                     connections_count_c.fetch_sub(1, Ordering::SeqCst);
@@ -244,45 +451,80 @@ pub async fn tcp_run(
                     res
                 });
             } else {
-                // trace!(
-                //     "Tcp server has reached the limit of {} connections",
-                //     connections_limit
-                // );
+                trace!(
+                    "Tcp server has reached the limit of {} connections",
+                    connections_limit
+                );
             }
         }
     };
 
-    let mut wakeups = tokio::time::interval(TCP_PING_INTERVAL);
-    let ping_future = async {
-        loop {
-            wakeups.tick().await;
-
-            //trace!("Tcp server ping sender wake up");
-            // server.send_pings().await
-            //     .map_err(|error| ServerRunError::SendPingsError { error })?;
-
-            let msg = ChatMessage {
-                msg_id: 66778899,
-                to_user: "123".to_string(),
-                from_user: "789".to_string(),
-                content: Connections::gen_random_string(16).as_bytes().to_vec(),
-            };
-
-            for (_, client) in server.clients.write().await.iter_mut() {
-                client.send(Packet::ChatMessage(msg.clone())).await;
+    let metrics_future = async {
+        match server.metrics_addr {
+            Some(metrics_addr) => {
+                metrics::serve::<P, _, _>(metrics_addr, || server.metrics(&stats))
+                    .await
+                    .map_err(|error| ServerRunError::MetricsError { error })
             }
+            None => futures::future::pending().await,
         }
     };
 
     futures::select! {
         res = connections_future.fuse() => res,
-        res = ping_future.fuse() => res,
+        res = metrics_future.fuse() => res,
     }
 }
 
+/// Exchange `Hello` with the client and establish the secure session if it's
+/// enabled. Fails if it takes longer than `TCP_HANDSHAKE_TIMEOUT`.
+async fn handshake<P, S>(
+    server: &Server<P>,
+    stream: &mut S,
+) -> Result<(Negotiated, Option<Session>), ConnectionError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let handshake = async {
+        let encryption = if server.secure.is_some() {
+            CAPABILITY_ENCRYPTION
+        } else {
+            0
+        };
+        let compression = if server.compression.is_some() {
+            CAPABILITY_COMPRESSION
+        } else {
+            0
+        };
+        let capabilities = Capabilities {
+            supported: encryption | compression,
+            required: encryption,
+            max_frame_size: MAX_FRAME_SIZE,
+        };
+        let negotiated = handshake::server_hello(stream, capabilities)
+            .await
+            .map_err(|error| ConnectionError::ServerHandshakeError { error })?;
+
+        let session = match server.secure {
+            Some(ref config) => Some(
+                secure::server_handshake(stream, config)
+                    .await
+                    .map_err(|error| ConnectionError::ServerHandshakeIoError { error })?,
+            ),
+            None => None,
+        };
+
+        Ok((negotiated, session))
+    };
+
+    tokio::time::timeout(TCP_HANDSHAKE_TIMEOUT, handshake)
+        .await
+        .map_err(|error| ConnectionError::ServerHandshakeTimeoutError { error })?
+}
+
 /// Running TCP server on incoming `TcpStream`
-pub async fn tcp_run_connection(
-    server: &Server,
+pub async fn tcp_run_connection<P: Protocol>(
+    server: &Server<P>,
     stream: TcpStream,
     stats: Stats,
 ) -> Result<(), ConnectionError> {
@@ -291,30 +533,134 @@ pub async fn tcp_run_connection(
         Err(error) => return Err(ConnectionError::PeerAddrError { error }),
     };
 
-    let secure_socket = Framed::new(stream, Codec::new(stats));
+    let span = info_span!("connection", peer = %addr, client_id = field::Empty);
+    run_connection(server, stream, addr.to_string(), stats)
+        .instrument(span)
+        .await
+}
+
+async fn run_connection<P, S>(
+    server: &Server<P>,
+    stream: S,
+    peer: String,
+    stats: Stats,
+) -> Result<(), ConnectionError>
+where
+    P: Protocol,
+    S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    let mut stream: BoxedStream = match server.tls {
+        Some(ref tls) => {
+            let stream = tokio::time::timeout(TCP_HANDSHAKE_TIMEOUT, tls.accept(stream))
+                .await
+                .map_err(|error| ConnectionError::ServerHandshakeTimeoutError { error })?
+                .map_err(|error| ConnectionError::TlsHandshakeError { error })?;
+            Box::new(stream)
+        }
+        None => Box::new(stream),
+    };
+    let (negotiated, session) = handshake(server, &mut stream).await?;
+    Span::current().record("client_id", negotiated.client_id.as_str());
+    info!("Client connected");
+
+    let stats = stats.child();
+    stats.counters.increase_connects();
+    server
+        .client_stats
+        .write()
+        .await
+        .insert(peer.clone(), stats.clone());
+
+    let codec = Codec::<P>::new(stats)
+        .with_session(session)
+        .with_max_frame_size(negotiated.max_frame_size)
+        .with_compression(
+            server
+                .compression
+                .filter(|_| negotiated.has(CAPABILITY_COMPRESSION)),
+        );
+    let secure_socket = Framed::new(stream, codec);
     let (mut to_client, from_client) = secure_socket.split();
     let (to_client_tx, mut to_client_rx) = mpsc::channel(SERVER_CHANNEL_SIZE);
 
     if let hash_map::Entry::Vacant(vacant) =
-        server.clients.write().await.entry(addr.clone().to_string())
+        server.clients.write().await.entry(peer.clone())
     {
         vacant.insert(to_client_tx.clone());
     }
 
+    let liveness = Mutex::new(Liveness {
+        ping_id: None,
+        last_pong: Instant::now(),
+    });
+
     // processor = for each Packet from client process it
     let processor = from_client
         .map_err(|error| ConnectionError::DecodePacketError { error })
         .try_for_each(|packet| {
-            println!("Handle  => {:?}", packet);
-            let tx_clone = to_client_tx.clone();
-            server
-                .handle_packet(packet, tx_clone)
-                .map_err(|error| ConnectionError::PacketHandlingError { error })
+            let liveness = &liveness;
+            let mut tx_clone = to_client_tx.clone();
+            async move {
+                trace!(?packet, "Handle packet");
+                if let Some(pong_id) = packet.pong_id() {
+                    let mut liveness = liveness.lock().await;
+                    if liveness.ping_id == Some(pong_id) {
+                        liveness.ping_id = None;
+                        liveness.last_pong = Instant::now();
+                        return Ok(());
+                    }
+                }
+                if let Some(pong) = packet.pong_response() {
+                    // the writer is gone only when the connection is closing
+                    let _ = tx_clone.send(pong).await;
+                    return Ok(());
+                }
+                server
+                    .handle_packet(packet, tx_clone)
+                    .map_err(|error| ConnectionError::PacketHandlingError { error })
+                    .await
+            }
         });
 
+    let heartbeat = async {
+        let interval = match server.ping_interval {
+            Some(interval) => interval,
+            None => return futures::future::pending().await,
+        };
+        let mut wakeups = tokio::time::interval_at(Instant::now() + interval, interval);
+        let mut tx = to_client_tx.clone();
+        let mut ping_id = 0;
+
+        loop {
+            wakeups.tick().await;
+
+            let packet = {
+                let mut liveness = liveness.lock().await;
+                if liveness.last_pong.elapsed() >= server.ping_timeout {
+                    return Err(ConnectionError::PingTimeoutError {
+                        timeout: server.ping_timeout,
+                    });
+                }
+                ping_id += 1;
+                match P::ping_request(ping_id) {
+                    Some(packet) => {
+                        liveness.ping_id = Some(ping_id);
+                        packet
+                    }
+                    None => return futures::future::pending().await,
+                }
+            };
+
+            trace!(ping_id, "Sending ping");
+            if tx.send(packet).await.is_err() {
+                return Ok(());
+            }
+        }
+    };
+
     let writer = async {
         while let Some(packet) = to_client_rx.next().await {
-            println!("Sending TCP packet {:?} to ", &addr);
+            trace!(?packet, "Sending TCP packet");
             to_client
                 .send(packet)
                 .await
@@ -326,9 +672,202 @@ pub async fn tcp_run_connection(
 
     let r_processing = futures::select! {
         res = processor.fuse() => res,
-        res = writer.fuse() => res
+        res = writer.fuse() => res,
+        res = heartbeat.fuse() => res,
     };
 
-    println!("Client Disconnect {}", addr);
+    server.clients.write().await.remove(&peer);
+    server.client_stats.write().await.remove(&peer);
+    info!("Client disconnected");
     r_processing
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::errors::HandshakeErrorKind;
+    use crate::packet_kind;
+    use bytes::{Bytes, BytesMut};
+    use tokio::io::AsyncWriteExt;
+    use tokio_util::codec::Encoder;
+
+    const NO_CAPABILITIES: Capabilities = Capabilities {
+        supported: 0,
+        required: 0,
+        max_frame_size: MAX_FRAME_SIZE,
+    };
+
+    fn chat_message(msg_id: u64) -> Packet {
+        Packet::ChatMessage(ChatMessage {
+            msg_id,
+            to_user: "server".to_string(),
+            from_user: "client".to_string(),
+            content: Bytes::from_static(b"ping"),
+        })
+    }
+
+    #[tokio::test]
+    async fn run_connection_coalesced_and_torn_frames() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = Server::new();
+        let server_c = server.clone();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            tcp_run_connection(&server_c, stream, Stats::new()).await
+        });
+
+        let mut codec = Codec::<Packet>::new(Stats::new());
+        let mut coalesced = BytesMut::new();
+        codec.encode(chat_message(1), &mut coalesced).unwrap();
+        codec.encode(chat_message(2), &mut coalesced).unwrap();
+        let mut torn = BytesMut::new();
+        codec.encode(chat_message(3), &mut torn).unwrap();
+
+        let mut stream = TcpStream::connect(&addr).await.unwrap();
+        stream.set_nodelay(true).unwrap();
+        handshake::client_hello(&mut stream, "client".to_string(), NO_CAPABILITIES)
+            .await
+            .unwrap();
+        stream.write_all(&coalesced).await.unwrap();
+        for byte in torn.iter() {
+            stream.write_all(&[*byte]).await.unwrap();
+            tokio::task::yield_now().await;
+        }
+
+        let mut from_server = Framed::new(stream, Codec::<Packet>::new(Stats::new()));
+        for msg_id in 1..=3 {
+            match from_server.next().await.unwrap().unwrap() {
+                Packet::ChatMessage(p) => assert_eq!(p.msg_id, msg_id),
+                packet => panic!("unexpected packet {:?}", packet),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn run_connection_client_stats() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = Server::new();
+        let server_c = server.clone();
+        let stats = Stats::new();
+        let stats_c = stats.clone();
+        let connection = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            tcp_run_connection(&server_c, stream, stats_c).await
+        });
+
+        let mut stream = TcpStream::connect(&addr).await.unwrap();
+        handshake::client_hello(&mut stream, "client".to_string(), NO_CAPABILITIES)
+            .await
+            .unwrap();
+        let mut to_server = Framed::new(stream, Codec::<Packet>::new(Stats::new()));
+        to_server.send(chat_message(1)).await.unwrap();
+        to_server.next().await.unwrap().unwrap();
+
+        let client_stats = server.client_stats().await;
+        assert_eq!(client_stats.len(), 1);
+        let snapshot = client_stats.values().next().unwrap();
+        assert_eq!(snapshot.connects, 1);
+        assert_eq!(snapshot.incoming_kinds[&packet_kind::CHAT_MESSAGE], 1);
+        assert!(snapshot.incoming_bytes > 0);
+        assert_eq!(*snapshot, stats.snapshot());
+
+        drop(to_server);
+        let _ = connection.await;
+        assert!(server.client_stats().await.is_empty());
+        assert_eq!(stats.snapshot().connects, 1);
+    }
+
+    #[tokio::test]
+    async fn run_connection_answers_ping() {
+        let server = Server::new();
+        let (stream, mut client) = tokio::io::duplex(1024);
+        tokio::spawn(async move {
+            run_connection(&server, stream, "client".to_string(), Stats::new()).await
+        });
+
+        handshake::client_hello(&mut client, "client".to_string(), NO_CAPABILITIES)
+            .await
+            .unwrap();
+        let mut client = Framed::new(client, Codec::<Packet>::new(Stats::new()));
+        client
+            .send(Packet::PingRequest(PingRequest { ping_id: 42 }))
+            .await
+            .unwrap();
+        assert_eq!(
+            client.next().await.unwrap().unwrap(),
+            Packet::PongResponse(PongResponse { ping_id: 42 })
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn run_connection_evicts_silent_client() {
+        let server =
+            Server::new().with_heartbeat(Some(Duration::from_secs(1)), Duration::from_secs(3));
+        let server_c = server.clone();
+        let (stream, mut client) = tokio::io::duplex(1024);
+        let connection = tokio::spawn(async move {
+            run_connection(&server_c, stream, "client".to_string(), Stats::new()).await
+        });
+
+        handshake::client_hello(&mut client, "client".to_string(), NO_CAPABILITIES)
+            .await
+            .unwrap();
+        let mut client = Framed::new(client, Codec::<Packet>::new(Stats::new()));
+        // the client stays connected for longer than the timeout while it
+        // answers pings
+        for _ in 0..5 {
+            let pong = match client.next().await.unwrap().unwrap() {
+                Packet::PingRequest(p) => PongResponse { ping_id: p.ping_id },
+                packet => panic!("unexpected packet {:?}", packet),
+            };
+            client.send(Packet::PongResponse(pong)).await.unwrap();
+        }
+        assert!(server.clients.read().await.contains_key("client"));
+
+        let started = Instant::now();
+        assert!(matches!(
+            connection.await.unwrap(),
+            Err(ConnectionError::PingTimeoutError { .. })
+        ));
+        assert!(started.elapsed() >= Duration::from_secs(3));
+        assert!(server.clients.read().await.is_empty());
+        assert!(server.client_stats().await.is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn handshake_timeout() {
+        let server = Server::new();
+        // the client never sends Hello
+        let (mut stream, _client) = tokio::io::duplex(1024);
+
+        assert!(matches!(
+            handshake(&server, &mut stream).await,
+            Err(ConnectionError::ServerHandshakeTimeoutError { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn handshake_missing_encryption() {
+        let server = Server::new().with_secure(SecureConfig::generate());
+        let (mut stream, mut client) = tokio::io::duplex(1024);
+        let client = tokio::spawn(async move {
+            handshake::client_hello(&mut client, "client".to_string(), NO_CAPABILITIES).await
+        });
+
+        match handshake(&server, &mut stream).await {
+            Err(ConnectionError::ServerHandshakeError { error }) => {
+                assert_eq!(*error.kind(), HandshakeErrorKind::MissingCapability)
+            }
+            res => panic!(
+                "unexpected result {:?}",
+                res.map(|(negotiated, _)| negotiated)
+            ),
+        }
+        assert_eq!(
+            *client.await.unwrap().unwrap_err().kind(),
+            HandshakeErrorKind::MissingCapability
+        );
+    }
+}
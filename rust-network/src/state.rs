@@ -0,0 +1,80 @@
+/*! Connection state events
+
+`Client::subscribe` returns a receiver of `StateEvent`s reported on every
+change of the connection state of the client. `Connections::subscribe`
+aggregates events of all its clients, they are told apart by `client_id`.
+
+Events are delivered through a `tokio::sync::broadcast` channel so a
+subscriber that falls behind by more than `STATE_EVENTS_CAPACITY` events
+gets `RecvError::Lagged` and misses the oldest ones.
+*/
+
+use std::time::Instant;
+
+use failure::Fail;
+use tokio::sync::broadcast;
+
+use crate::endpoint::Peer;
+use crate::errors::{SpawnError, SpawnErrorKind};
+
+/// Number of events kept for subscribers that didn't receive them yet.
+pub const STATE_EVENTS_CAPACITY: usize = 64;
+
+/// Connection state of a `Client`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ClientState {
+    /// Establishing connection to the relay and making a handshake.
+    Connecting,
+    /// Connection to the relay is established.
+    Connected {
+        /// Address of the relay.
+        peer: Peer,
+    },
+    /// Connection is closed or couldn't be established.
+    Disconnected {
+        /// Error that dropped the connection. `None` if it was closed by
+        /// either side.
+        error: Option<ErrorCause>,
+    },
+    /// Connection is closed and the client isn't reconnected until it's
+    /// spawned explicitly.
+    Sleeping,
+}
+
+/// Error that dropped a connection.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ErrorCause {
+    /// Kind of the error.
+    pub kind: SpawnErrorKind,
+    /// Description of the error followed by its causes.
+    pub message: String,
+}
+
+impl From<&SpawnError> for ErrorCause {
+    fn from(error: &SpawnError) -> ErrorCause {
+        let message = <dyn Fail>::iter_chain(error)
+            .map(|fail| fail.to_string())
+            .collect::<Vec<_>>()
+            .join(": ");
+        ErrorCause {
+            kind: error.kind().clone(),
+            message,
+        }
+    }
+}
+
+/// Change of the connection state of a client.
+#[derive(Debug, Clone)]
+pub struct StateEvent {
+    /// Id of the client.
+    pub client_id: String,
+    /// New state.
+    pub state: ClientState,
+    /// Time of the change.
+    pub at: Instant,
+}
+
+/// Create a channel of state events.
+pub fn channel() -> broadcast::Sender<StateEvent> {
+    broadcast::channel(STATE_EVENTS_CAPACITY).0
+}
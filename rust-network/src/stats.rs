@@ -1,10 +1,22 @@
 /*!
 Statistics of incoming/outgoing packets
 This is used by both Udp codec and Tcp codec.
+
+Every `Client` and every server connection keeps its own `Stats` created with
+`Stats::child`. Counters of a child are added to its parent as well, so the
+`Stats` of `Connections` or the one passed to `tcp_run` aggregate all their
+clients.
 */
 
+use std::collections::BTreeMap;
+use std::fmt;
 use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
+
+/// Upper bounds of `send_for_response` round-trip time histogram buckets in
+/// milliseconds. The last bucket counts everything above the last bound.
+pub const RTT_BUCKETS_MS: &[u64] = &[1, 2, 5, 10, 25, 50, 100, 250, 500, 1000, 2500, 5000];
 
 /// Struct for various counters
 #[derive(Clone, Default)]
@@ -18,36 +30,335 @@ impl Stats {
     pub fn new() -> Self {
         Default::default()
     }
+
+    /// New Stats object that adds all its counters to this one as well.
+    pub fn child(&self) -> Self {
+        Stats {
+            counters: Arc::new(Counters {
+                parent: Some(self.counters.clone()),
+                ..Default::default()
+            }),
+        }
+    }
+
+    /// Current values of all counters
+    pub fn snapshot(&self) -> StatsSnapshot {
+        self.counters.snapshot()
+    }
+}
+
+impl fmt::Debug for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Stats")
+            .field("counters", &self.snapshot())
+            .finish()
+    }
 }
 
-#[derive(Default)]
 /// A struct for counting incoming and outgoing packets.
 pub struct Counters {
     /// Incoming packets count for Udp/Tcp
     incoming: AtomicU64,
     /// Outgoing packets count for Udp/Tcp
     outgoing: AtomicU64,
+    /// Size of incoming frames including headers
+    incoming_bytes: AtomicU64,
+    /// Size of outgoing frames including headers
+    outgoing_bytes: AtomicU64,
+    /// Size of packets passed through compression before compressing
+    raw_bytes: AtomicU64,
+    /// Size of packets passed through compression after compressing
+    compressed_bytes: AtomicU64,
+    /// Frames that couldn't be decoded
+    decode_errors: AtomicU64,
+    /// Packets that couldn't be encoded
+    encode_errors: AtomicU64,
+    /// Established connections
+    connects: AtomicU64,
+    /// Established connections of clients that were connected before
+    reconnects: AtomicU64,
+    /// Incoming packets count indexed by packet kind
+    incoming_kinds: Box<[AtomicU64]>,
+    /// Outgoing packets count indexed by packet kind
+    outgoing_kinds: Box<[AtomicU64]>,
+    /// Size of incoming frames indexed by packet kind
+    incoming_kind_bytes: Box<[AtomicU64]>,
+    /// Size of outgoing frames indexed by packet kind
+    outgoing_kind_bytes: Box<[AtomicU64]>,
+    /// Lua plugin calls
+    plugin_calls: AtomicU64,
+    /// Lua plugin calls that failed
+    plugin_errors: AtomicU64,
+    /// Received packets suppressed or flagged as repeats
+    duplicates: AtomicU64,
+    /// `send_for_response` round-trip times
+    rtt: Histogram,
+    /// Counters that aggregate these ones
+    parent: Option<Arc<Counters>>,
+}
+
+impl Default for Counters {
+    fn default() -> Self {
+        Counters {
+            incoming: AtomicU64::new(0),
+            outgoing: AtomicU64::new(0),
+            incoming_bytes: AtomicU64::new(0),
+            outgoing_bytes: AtomicU64::new(0),
+            raw_bytes: AtomicU64::new(0),
+            compressed_bytes: AtomicU64::new(0),
+            decode_errors: AtomicU64::new(0),
+            encode_errors: AtomicU64::new(0),
+            connects: AtomicU64::new(0),
+            reconnects: AtomicU64::new(0),
+            incoming_kinds: new_atomics(256),
+            outgoing_kinds: new_atomics(256),
+            incoming_kind_bytes: new_atomics(256),
+            outgoing_kind_bytes: new_atomics(256),
+            plugin_calls: AtomicU64::new(0),
+            plugin_errors: AtomicU64::new(0),
+            duplicates: AtomicU64::new(0),
+            rtt: Histogram::default(),
+            parent: None,
+        }
+    }
+}
+
+fn new_atomics(len: usize) -> Box<[AtomicU64]> {
+    (0..len).map(|_| AtomicU64::new(0)).collect()
+}
+
+fn load(counter: &AtomicU64) -> u64 {
+    counter.load(Ordering::Relaxed)
 }
 
 impl Counters {
+    /// Add `value` to the counter of these counters and of all parents
+    fn add<F>(&self, counter: F, value: u64)
+    where
+        F: Fn(&Counters) -> &AtomicU64,
+    {
+        let mut counters = Some(self);
+        while let Some(c) = counters {
+            counter(c).fetch_add(value, Ordering::Relaxed);
+            counters = c.parent.as_deref();
+        }
+    }
+
     /// Add 1 to incoming counter
     pub fn increase_incoming(&self) {
-        self.incoming.fetch_add(1, Ordering::Relaxed);
+        self.add(|c| &c.incoming, 1);
     }
 
     /// Add 1 to outgoing counter
     pub fn increase_outgoing(&self) {
-        self.outgoing.fetch_add(1, Ordering::Relaxed);
+        self.add(|c| &c.outgoing, 1);
     }
 
     /// Get incoming counter
     pub fn incoming(&self) -> u64 {
-        self.incoming.load(Ordering::Relaxed)
+        load(&self.incoming)
     }
 
     /// Get outgoing counter
     pub fn outgoing(&self) -> u64 {
-        self.outgoing.load(Ordering::Relaxed)
+        load(&self.outgoing)
+    }
+
+    /// Count a received packet of the given kind and frame size
+    pub fn add_incoming(&self, kind: u8, size: usize) {
+        self.add(|c| &c.incoming_kinds[kind as usize], 1);
+        self.add(|c| &c.incoming_kind_bytes[kind as usize], size as u64);
+        self.add(|c| &c.incoming_bytes, size as u64);
+    }
+
+    /// Count a sent packet of the given kind and frame size
+    pub fn add_outgoing(&self, kind: u8, size: usize) {
+        self.add(|c| &c.outgoing_kinds[kind as usize], 1);
+        self.add(|c| &c.outgoing_kind_bytes[kind as usize], size as u64);
+        self.add(|c| &c.outgoing_bytes, size as u64);
+    }
+
+    /// Add the size of a packet before and after compression
+    pub fn add_compressed(&self, raw: usize, compressed: usize) {
+        self.add(|c| &c.raw_bytes, raw as u64);
+        self.add(|c| &c.compressed_bytes, compressed as u64);
+    }
+
+    /// Get total size of packets before compression
+    pub fn raw_bytes(&self) -> u64 {
+        load(&self.raw_bytes)
+    }
+
+    /// Get total size of packets after compression
+    pub fn compressed_bytes(&self) -> u64 {
+        load(&self.compressed_bytes)
+    }
+
+    /// Add 1 to decode errors counter
+    pub fn increase_decode_errors(&self) {
+        self.add(|c| &c.decode_errors, 1);
+    }
+
+    /// Add 1 to encode errors counter
+    pub fn increase_encode_errors(&self) {
+        self.add(|c| &c.encode_errors, 1);
+    }
+
+    /// Count an established connection. It's counted as reconnect as well if
+    /// these counters have seen a connection before.
+    pub fn increase_connects(&self) {
+        if load(&self.connects) > 0 {
+            self.add(|c| &c.reconnects, 1);
+        }
+        self.add(|c| &c.connects, 1);
+    }
+
+    /// Count a Lua plugin call
+    pub fn increase_plugin_calls(&self) {
+        self.add(|c| &c.plugin_calls, 1);
+    }
+
+    /// Count a Lua plugin call that failed
+    pub fn increase_plugin_errors(&self) {
+        self.add(|c| &c.plugin_errors, 1);
+    }
+
+    /// Count a received packet suppressed or flagged as a repeat
+    pub fn increase_duplicates(&self) {
+        self.add(|c| &c.duplicates, 1);
+    }
+
+    /// Add a `send_for_response` round-trip time to the histogram
+    pub fn record_rtt(&self, rtt: Duration) {
+        let bucket = self.rtt.bucket(rtt);
+        self.add(|c| &c.rtt.buckets[bucket], 1);
+        self.add(|c| &c.rtt.sum_micros, rtt.as_micros() as u64);
+    }
+
+    /// Current values of all counters
+    pub fn snapshot(&self) -> StatsSnapshot {
+        let kinds = |counters: &[AtomicU64]| {
+            counters
+                .iter()
+                .enumerate()
+                .map(|(kind, count)| (kind as u8, load(count)))
+                .filter(|&(_, count)| count > 0)
+                .collect()
+        };
+
+        StatsSnapshot {
+            incoming: load(&self.incoming),
+            outgoing: load(&self.outgoing),
+            incoming_bytes: load(&self.incoming_bytes),
+            outgoing_bytes: load(&self.outgoing_bytes),
+            raw_bytes: load(&self.raw_bytes),
+            compressed_bytes: load(&self.compressed_bytes),
+            decode_errors: load(&self.decode_errors),
+            encode_errors: load(&self.encode_errors),
+            connects: load(&self.connects),
+            reconnects: load(&self.reconnects),
+            incoming_kinds: kinds(&self.incoming_kinds),
+            outgoing_kinds: kinds(&self.outgoing_kinds),
+            incoming_kind_bytes: kinds(&self.incoming_kind_bytes),
+            outgoing_kind_bytes: kinds(&self.outgoing_kind_bytes),
+            plugin_calls: load(&self.plugin_calls),
+            plugin_errors: load(&self.plugin_errors),
+            duplicates: load(&self.duplicates),
+            rtt: self.rtt.snapshot(),
+        }
+    }
+}
+
+/// Histogram with buckets bounded by `RTT_BUCKETS_MS`.
+struct Histogram {
+    /// Count of values in each bucket
+    buckets: Box<[AtomicU64]>,
+    /// Sum of all values in microseconds
+    sum_micros: AtomicU64,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Histogram {
+            buckets: new_atomics(RTT_BUCKETS_MS.len() + 1),
+            sum_micros: AtomicU64::new(0),
+        }
+    }
+}
+
+impl Histogram {
+    fn bucket(&self, value: Duration) -> usize {
+        RTT_BUCKETS_MS
+            .iter()
+            .position(|&bound| value <= Duration::from_millis(bound))
+            .unwrap_or(RTT_BUCKETS_MS.len())
+    }
+
+    fn snapshot(&self) -> HistogramSnapshot {
+        HistogramSnapshot {
+            buckets: self.buckets.iter().map(load).collect(),
+            sum: Duration::from_micros(load(&self.sum_micros)),
+        }
+    }
+}
+
+/// Values of `Counters` at some moment.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct StatsSnapshot {
+    /// Incoming packets count
+    pub incoming: u64,
+    /// Outgoing packets count
+    pub outgoing: u64,
+    /// Size of incoming frames including headers
+    pub incoming_bytes: u64,
+    /// Size of outgoing frames including headers
+    pub outgoing_bytes: u64,
+    /// Size of packets passed through compression before compressing
+    pub raw_bytes: u64,
+    /// Size of packets passed through compression after compressing
+    pub compressed_bytes: u64,
+    /// Frames that couldn't be decoded
+    pub decode_errors: u64,
+    /// Packets that couldn't be encoded
+    pub encode_errors: u64,
+    /// Established connections
+    pub connects: u64,
+    /// Established connections of clients that were connected before
+    pub reconnects: u64,
+    /// Incoming packets count by packet kind. Kinds without packets are
+    /// omitted.
+    pub incoming_kinds: BTreeMap<u8, u64>,
+    /// Outgoing packets count by packet kind. Kinds without packets are
+    /// omitted.
+    pub outgoing_kinds: BTreeMap<u8, u64>,
+    /// Size of incoming frames by packet kind
+    pub incoming_kind_bytes: BTreeMap<u8, u64>,
+    /// Size of outgoing frames by packet kind
+    pub outgoing_kind_bytes: BTreeMap<u8, u64>,
+    /// Lua plugin calls
+    pub plugin_calls: u64,
+    /// Lua plugin calls that failed
+    pub plugin_errors: u64,
+    /// Received packets suppressed or flagged as repeats
+    pub duplicates: u64,
+    /// `send_for_response` round-trip times
+    pub rtt: HistogramSnapshot,
+}
+
+/// Values of a histogram at some moment.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct HistogramSnapshot {
+    /// Count of values in each bucket. Bucket `i` counts values up to
+    /// `RTT_BUCKETS_MS[i]`, the last one counts values above all bounds.
+    pub buckets: Vec<u64>,
+    /// Sum of all values
+    pub sum: Duration,
+}
+
+impl HistogramSnapshot {
+    /// Count of all values
+    pub fn count(&self) -> u64 {
+        self.buckets.iter().sum()
     }
 }
 
@@ -71,4 +382,63 @@ mod tests {
         stats.counters.increase_outgoing();
         assert_eq!(2, stats.counters.outgoing());
     }
+
+    #[test]
+    fn compressed() {
+        let stats = Stats::new();
+        stats.counters.add_compressed(100, 40);
+        stats.counters.add_compressed(10, 11);
+        assert_eq!(110, stats.counters.raw_bytes());
+        assert_eq!(51, stats.counters.compressed_bytes());
+    }
+
+    #[test]
+    fn kinds() {
+        let stats = Stats::new();
+        stats.counters.add_incoming(0x06, 30);
+        stats.counters.add_incoming(0x06, 20);
+        stats.counters.add_outgoing(0x04, 13);
+        let snapshot = stats.snapshot();
+
+        assert_eq!(snapshot.incoming_bytes, 50);
+        assert_eq!(snapshot.outgoing_bytes, 13);
+        assert_eq!(snapshot.incoming_kinds, vec![(0x06, 2)].into_iter().collect());
+        assert_eq!(snapshot.outgoing_kinds, vec![(0x04, 1)].into_iter().collect());
+        assert_eq!(snapshot.incoming_kind_bytes, vec![(0x06, 50)].into_iter().collect());
+    }
+
+    #[test]
+    fn rtt() {
+        let stats = Stats::new();
+        stats.counters.record_rtt(Duration::from_micros(500));
+        stats.counters.record_rtt(Duration::from_millis(1));
+        stats.counters.record_rtt(Duration::from_millis(30));
+        stats.counters.record_rtt(Duration::from_secs(60));
+        let rtt = stats.snapshot().rtt;
+
+        assert_eq!(rtt.count(), 4);
+        assert_eq!(rtt.buckets[0], 2);
+        assert_eq!(rtt.buckets[5], 1);
+        assert_eq!(rtt.buckets[RTT_BUCKETS_MS.len()], 1);
+        assert_eq!(rtt.sum, Duration::from_micros(60_031_500));
+    }
+
+    #[test]
+    fn child() {
+        let stats = Stats::new();
+        let a = stats.child();
+        let b = stats.child();
+        a.counters.increase_connects();
+        a.counters.increase_connects();
+        b.counters.increase_connects();
+        b.counters.increase_decode_errors();
+
+        assert_eq!(a.snapshot().connects, 2);
+        assert_eq!(a.snapshot().reconnects, 1);
+        assert_eq!(b.snapshot().reconnects, 0);
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.connects, 3);
+        assert_eq!(snapshot.reconnects, 1);
+        assert_eq!(snapshot.decode_errors, 1);
+    }
 }
@@ -0,0 +1,472 @@
+/*! TLS for connections between clients and the server
+
+Relays that require TLS are reached by wrapping the stream opened by the
+`Transport` with rustls before the `Hello` handshake. The server does the same
+with accepted streams when it's configured with a certificate. TLS doesn't
+replace the `secure` session, both can be enabled at once.
+
+The relay certificate is verified against root certificates given to
+`TlsClientConfig`. Pinned certificates are matched by SHA-256 fingerprint of
+their DER encoding. When only pins are configured the relay may use a
+self-signed certificate.
+*/
+
+use std::convert::TryFrom;
+use std::fmt;
+use std::io::Error as IoError;
+use std::sync::Arc;
+
+use failure::Fail;
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::client::WebPkiServerVerifier;
+use rustls::crypto::{self, CryptoProvider};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{ClientConfig, DigitallySignedStruct, RootCertStore, ServerConfig, SignatureScheme};
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_rustls::{client, server, TlsAcceptor, TlsConnector};
+
+use crate::errors::*;
+
+/// SHA-256 fingerprint of a DER encoded certificate used for pinning.
+pub fn fingerprint(cert: &[u8]) -> [u8; 32] {
+    Sha256::digest(cert).into()
+}
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(crypto::ring::default_provider())
+}
+
+fn parse_certificates(pem: &[u8]) -> Result<Vec<CertificateDer<'static>>, TlsError> {
+    let certs = CertificateDer::pem_slice_iter(pem)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| e.context(TlsErrorKind::Certificate))?;
+    if certs.is_empty() {
+        return Err(TlsErrorKind::Certificate.into());
+    }
+    Ok(certs)
+}
+
+fn parse_root_certificates(pem: &[u8]) -> Result<RootCertStore, TlsError> {
+    let mut roots = RootCertStore::empty();
+    for cert in parse_certificates(pem)? {
+        roots
+            .add(cert)
+            .map_err(|e| e.context(TlsErrorKind::Certificate))?;
+    }
+    Ok(roots)
+}
+
+fn parse_private_key(pem: &[u8]) -> Result<PrivateKeyDer<'static>, TlsError> {
+    PrivateKeyDer::from_pem_slice(pem).map_err(|e| e.context(TlsErrorKind::PrivateKey).into())
+}
+
+/// TLS settings of a client, turned into `TlsClient` by `build`.
+#[derive(Debug)]
+pub struct TlsClientConfig {
+    /// Name sent as SNI and verified against the relay certificate
+    server_name: ServerName<'static>,
+    /// Trusted root certificates
+    roots: RootCertStore,
+    /// Certificate chain and key authenticating the client
+    client_certificate: Option<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>)>,
+    /// Fingerprints of accepted relay certificates
+    pins: Vec<[u8; 32]>,
+}
+
+impl TlsClientConfig {
+    /// Connect to the relay with the given DNS name or IP address.
+    pub fn new(server_name: &str) -> Result<TlsClientConfig, TlsError> {
+        let server_name = ServerName::try_from(server_name.to_string())
+            .map_err(|e| e.context(TlsErrorKind::ServerName))?;
+        Ok(TlsClientConfig {
+            server_name,
+            roots: RootCertStore::empty(),
+            client_certificate: None,
+            pins: Vec::new(),
+        })
+    }
+
+    /// Trust root certificates from PEM data.
+    pub fn with_root_certificates(mut self, pem: &[u8]) -> Result<TlsClientConfig, TlsError> {
+        self.roots.roots.extend(parse_root_certificates(pem)?.roots);
+        Ok(self)
+    }
+
+    /// Authenticate to the relay with a PEM certificate chain and its private
+    /// key.
+    pub fn with_client_certificate(
+        mut self,
+        cert_pem: &[u8],
+        key_pem: &[u8],
+    ) -> Result<TlsClientConfig, TlsError> {
+        self.client_certificate =
+            Some((parse_certificates(cert_pem)?, parse_private_key(key_pem)?));
+        Ok(self)
+    }
+
+    /// Accept only relay certificates with one of the pinned fingerprints.
+    pub fn with_pinned_certificate(mut self, fingerprint: [u8; 32]) -> TlsClientConfig {
+        self.pins.push(fingerprint);
+        self
+    }
+
+    /// Check the settings and build the rustls configuration.
+    pub fn build(self) -> Result<TlsClient, TlsError> {
+        let provider = provider();
+        let verifier = if self.roots.is_empty() {
+            if self.pins.is_empty() {
+                return Err(TlsErrorKind::NoTrustedCertificates.into());
+            }
+            None
+        } else {
+            let verifier =
+                WebPkiServerVerifier::builder_with_provider(Arc::new(self.roots), provider.clone())
+                    .build()
+                    .map_err(|e| e.context(TlsErrorKind::Config))?;
+            Some(verifier)
+        };
+        let verifier = PinningVerifier {
+            inner: verifier,
+            pins: self.pins,
+            provider: provider.clone(),
+        };
+
+        let builder = ClientConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .map_err(|e| e.context(TlsErrorKind::Config))?
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(verifier));
+        let config = match self.client_certificate {
+            Some((certs, key)) => builder
+                .with_client_auth_cert(certs, key)
+                .map_err(|e| e.context(TlsErrorKind::Config))?,
+            None => builder.with_no_client_auth(),
+        };
+
+        Ok(TlsClient {
+            connector: TlsConnector::from(Arc::new(config)),
+            server_name: self.server_name,
+        })
+    }
+}
+
+/// Verifies the relay certificate with root certificates if there are any and
+/// checks that it's pinned if there are pins.
+#[derive(Debug)]
+struct PinningVerifier {
+    inner: Option<Arc<WebPkiServerVerifier>>,
+    pins: Vec<[u8; 32]>,
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for PinningVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if !self.pins.is_empty() && !self.pins.contains(&fingerprint(end_entity)) {
+            return Err(rustls::Error::General(
+                "Certificate doesn't match pinned fingerprints".to_string(),
+            ));
+        }
+        match self.inner {
+            Some(ref inner) => {
+                inner.verify_server_cert(end_entity, intermediates, server_name, ocsp_response, now)
+            }
+            None => Ok(ServerCertVerified::assertion()),
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+/// TLS client side of connections to a relay.
+#[derive(Clone)]
+pub struct TlsClient {
+    connector: TlsConnector,
+    server_name: ServerName<'static>,
+}
+
+impl fmt::Debug for TlsClient {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("TlsClient")
+            .field("server_name", &self.server_name)
+            .finish()
+    }
+}
+
+impl TlsClient {
+    /// Perform the TLS handshake over a connected stream.
+    pub async fn connect<S>(&self, stream: S) -> Result<client::TlsStream<S>, IoError>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        self.connector
+            .connect(self.server_name.clone(), stream)
+            .await
+    }
+}
+
+/// TLS settings of the server, turned into `TlsServer` by `build`.
+#[derive(Debug)]
+pub struct TlsServerConfig {
+    /// Certificate chain presented to clients
+    certificate: Vec<CertificateDer<'static>>,
+    /// Key of the certificate
+    key: PrivateKeyDer<'static>,
+    /// Roots of required client certificates
+    client_roots: Option<RootCertStore>,
+}
+
+impl TlsServerConfig {
+    /// Present the PEM certificate chain with its private key to clients.
+    pub fn new(cert_pem: &[u8], key_pem: &[u8]) -> Result<TlsServerConfig, TlsError> {
+        Ok(TlsServerConfig {
+            certificate: parse_certificates(cert_pem)?,
+            key: parse_private_key(key_pem)?,
+            client_roots: None,
+        })
+    }
+
+    /// Require clients to present a certificate issued by one of the PEM root
+    /// certificates.
+    pub fn with_client_roots(mut self, pem: &[u8]) -> Result<TlsServerConfig, TlsError> {
+        self.client_roots = Some(parse_root_certificates(pem)?);
+        Ok(self)
+    }
+
+    /// Check the settings and build the rustls configuration.
+    pub fn build(self) -> Result<TlsServer, TlsError> {
+        let provider = provider();
+        let builder = ServerConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()
+            .map_err(|e| e.context(TlsErrorKind::Config))?;
+        let builder = match self.client_roots {
+            Some(roots) => {
+                let verifier =
+                    WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider)
+                        .build()
+                        .map_err(|e| e.context(TlsErrorKind::Config))?;
+                builder.with_client_cert_verifier(verifier)
+            }
+            None => builder.with_no_client_auth(),
+        };
+        let config = builder
+            .with_single_cert(self.certificate, self.key)
+            .map_err(|e| e.context(TlsErrorKind::Config))?;
+
+        Ok(TlsServer {
+            acceptor: TlsAcceptor::from(Arc::new(config)),
+        })
+    }
+}
+
+/// TLS server side of connections accepted by the server.
+#[derive(Clone)]
+pub struct TlsServer {
+    acceptor: TlsAcceptor,
+}
+
+impl fmt::Debug for TlsServer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("TlsServer").finish()
+    }
+}
+
+impl TlsServer {
+    /// Perform the TLS handshake over an accepted stream.
+    pub async fn accept<S>(&self, stream: S) -> Result<server::TlsStream<S>, IoError>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        self.acceptor.accept(stream).await
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    use rcgen::{BasicConstraints, CertificateParams, CertifiedKey, IsCa, KeyPair};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    /// Certificate authority issuing certificates for tests.
+    pub struct TestCa {
+        pub cert: rcgen::Certificate,
+        key: KeyPair,
+    }
+
+    impl TestCa {
+        pub fn new() -> TestCa {
+            let mut params = CertificateParams::new(Vec::new()).unwrap();
+            params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+            let key = KeyPair::generate().unwrap();
+            let cert = params.self_signed(&key).unwrap();
+            TestCa { cert, key }
+        }
+
+        pub fn issue(&self, name: &str) -> CertifiedKey {
+            let params = CertificateParams::new(vec![name.to_string()]).unwrap();
+            let key_pair = KeyPair::generate().unwrap();
+            let cert = params.signed_by(&key_pair, &self.cert, &self.key).unwrap();
+            CertifiedKey { cert, key_pair }
+        }
+    }
+
+    fn server_config(cert: &CertifiedKey) -> TlsServerConfig {
+        TlsServerConfig::new(
+            cert.cert.pem().as_bytes(),
+            cert.key_pair.serialize_pem().as_bytes(),
+        )
+        .unwrap()
+    }
+
+    async fn handshake(client: TlsClient, server: TlsServer) -> Result<(), IoError> {
+        let (client_stream, server_stream) = tokio::io::duplex(16 * 1024);
+        let server = async move {
+            let mut stream = server.accept(server_stream).await?;
+            let mut buf = [0; 4];
+            stream.read_exact(&mut buf).await?;
+            stream.write_all(&buf).await?;
+            stream.flush().await
+        };
+        tokio::spawn(server);
+
+        let mut stream = client.connect(client_stream).await?;
+        stream.write_all(b"ping").await?;
+        stream.flush().await?;
+        let mut buf = [0; 4];
+        stream.read_exact(&mut buf).await?;
+        assert_eq!(&buf, b"ping");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn root_certificate() {
+        let ca = TestCa::new();
+        let client = TlsClientConfig::new("localhost")
+            .unwrap()
+            .with_root_certificates(ca.cert.pem().as_bytes())
+            .unwrap()
+            .build()
+            .unwrap();
+
+        let server = server_config(&ca.issue("localhost")).build().unwrap();
+        handshake(client.clone(), server).await.unwrap();
+
+        let server = server_config(&ca.issue("example.com")).build().unwrap();
+        assert!(handshake(client.clone(), server).await.is_err());
+
+        let server = server_config(&TestCa::new().issue("localhost"))
+            .build()
+            .unwrap();
+        assert!(handshake(client, server).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn pinned_self_signed_certificate() {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let client = TlsClientConfig::new("localhost")
+            .unwrap()
+            .with_pinned_certificate(fingerprint(cert.cert.der()))
+            .build()
+            .unwrap();
+        handshake(client, server_config(&cert).build().unwrap())
+            .await
+            .unwrap();
+
+        let other = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let client = TlsClientConfig::new("localhost")
+            .unwrap()
+            .with_pinned_certificate(fingerprint(other.cert.der()))
+            .build()
+            .unwrap();
+        assert!(handshake(client, server_config(&cert).build().unwrap())
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn client_certificate() {
+        let ca = TestCa::new();
+        let server = server_config(&ca.issue("localhost"))
+            .with_client_roots(ca.cert.pem().as_bytes())
+            .unwrap()
+            .build()
+            .unwrap();
+        let config = || {
+            TlsClientConfig::new("localhost")
+                .unwrap()
+                .with_root_certificates(ca.cert.pem().as_bytes())
+                .unwrap()
+        };
+
+        let client_cert = ca.issue("client");
+        let client = config()
+            .with_client_certificate(
+                client_cert.cert.pem().as_bytes(),
+                client_cert.key_pair.serialize_pem().as_bytes(),
+            )
+            .unwrap()
+            .build()
+            .unwrap();
+        handshake(client, server.clone()).await.unwrap();
+
+        let client = config().build().unwrap();
+        assert!(handshake(client, server).await.is_err());
+    }
+
+    #[test]
+    fn invalid_config() {
+        let error = TlsClientConfig::new("localhost")
+            .unwrap()
+            .build()
+            .unwrap_err();
+        assert_eq!(*error.kind(), TlsErrorKind::NoTrustedCertificates);
+
+        let error = TlsClientConfig::new("not a name").unwrap_err();
+        assert_eq!(*error.kind(), TlsErrorKind::ServerName);
+
+        let error = TlsServerConfig::new(b"", b"").unwrap_err();
+        assert_eq!(*error.kind(), TlsErrorKind::Certificate);
+    }
+}
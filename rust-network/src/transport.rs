@@ -0,0 +1,215 @@
+/*! Transports carrying frames between clients and the server
+
+`Client` opens its connections with a `Transport` and the server accept loop
+`server::run` takes them from an `Acceptor`. Both yield a `BoxedStream` so the
+handshake, `Codec`, reconnect logic and Lua plugins work the same over any
+stream. TCP is used by default, Unix sockets are available on Unix in
+`unix`.
+*/
+
+use std::fmt::Debug;
+use std::io::{Error as IoError, ErrorKind};
+use std::time::Duration;
+
+use futures::channel::mpsc;
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::endpoint::Peer;
+
+/// Bidirectional byte stream of a single connection.
+pub trait AsyncStream: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<S: AsyncRead + AsyncWrite + Send + Unpin> AsyncStream for S {}
+
+/// Stream returned by transports and acceptors.
+pub type BoxedStream = Box<dyn AsyncStream>;
+
+/// Opens connections to relays for `Client`.
+pub trait Transport: Debug + Send + Sync + 'static {
+    /// Connect to the relay at the given peer. Fails with `InvalidInput` for
+    /// peers the transport can't reach, e.g. a Unix socket path over TCP.
+    fn connect(&self, peer: &Peer) -> BoxFuture<'static, Result<BoxedStream, IoError>>;
+
+    /// Connect like `connect` and send the round-trip time of every
+    /// keepalive ping the transport exchanges on its own, e.g. a WebSocket
+    /// ping, to `rtt_tx`. `Client` counts them like answers to its heartbeat.
+    /// Transports without keepalive pings just connect.
+    fn connect_with_rtt(
+        &self,
+        peer: &Peer,
+        rtt_tx: RttSender,
+    ) -> BoxFuture<'static, Result<BoxedStream, IoError>> {
+        drop(rtt_tx);
+        self.connect(peer)
+    }
+}
+
+/// Receives the round-trip times of the keepalive pings of a connection.
+pub type RttSender = mpsc::UnboundedSender<Duration>;
+
+/// Accepts connections of clients for `server::run`.
+pub trait Acceptor: Send + Sync + 'static {
+    /// Wait for the next connection. Returns the stream together with a
+    /// peer name that is unique among connected clients.
+    fn accept(&self) -> BoxFuture<'_, Result<(BoxedStream, String), IoError>>;
+}
+
+/// Plain TCP transport used by default.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TcpTransport;
+
+impl Transport for TcpTransport {
+    fn connect(&self, peer: &Peer) -> BoxFuture<'static, Result<BoxedStream, IoError>> {
+        let addr = peer.addr();
+        let peer = peer.to_string();
+        async move {
+            let addr = addr.ok_or_else(|| unsupported_peer("TCP", &peer))?;
+            let stream = TcpStream::connect(&addr).await?;
+            Ok(Box::new(stream) as BoxedStream)
+        }
+        .boxed()
+    }
+}
+
+/// Error of a transport asked to connect to a peer it can't reach.
+pub(crate) fn unsupported_peer(transport: &str, peer: &str) -> IoError {
+    IoError::new(
+        ErrorKind::InvalidInput,
+        format!("{} transport can't connect to {}", transport, peer),
+    )
+}
+
+impl Acceptor for TcpListener {
+    fn accept(&self) -> BoxFuture<'_, Result<(BoxedStream, String), IoError>> {
+        async move {
+            let (stream, peer) = TcpListener::accept(self).await?;
+            Ok((Box::new(stream) as BoxedStream, peer.to_string()))
+        }
+        .boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::ErrorKind;
+    use std::net::SocketAddr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use bytes::Bytes;
+    use futures::channel::mpsc;
+    use futures::StreamExt;
+    use tokio::io::DuplexStream;
+    use tokio::sync::Mutex;
+
+    use crate::chatmsg::ChatMessage;
+    use crate::client::tests::{connected, test_client};
+    use crate::config::ClientConfig;
+    use crate::connections::Connections;
+    use crate::server::{self, Server};
+    use crate::stats::Stats;
+    use crate::Packet;
+
+    /// In-memory transport handing the other end of every stream to
+    /// `MemoryAcceptor`.
+    #[derive(Debug)]
+    struct MemoryTransport {
+        tx: mpsc::UnboundedSender<DuplexStream>,
+    }
+
+    struct MemoryAcceptor {
+        rx: Mutex<mpsc::UnboundedReceiver<DuplexStream>>,
+        peers: AtomicUsize,
+    }
+
+    fn memory() -> (MemoryTransport, MemoryAcceptor) {
+        let (tx, rx) = mpsc::unbounded();
+        let acceptor = MemoryAcceptor {
+            rx: Mutex::new(rx),
+            peers: Default::default(),
+        };
+        (MemoryTransport { tx }, acceptor)
+    }
+
+    impl Transport for MemoryTransport {
+        fn connect(&self, _peer: &Peer) -> BoxFuture<'static, Result<BoxedStream, IoError>> {
+            let (client, server) = tokio::io::duplex(64 * 1024);
+            let result = self
+                .tx
+                .unbounded_send(server)
+                .map(|()| Box::new(client) as BoxedStream)
+                .map_err(|_| IoError::from(ErrorKind::ConnectionRefused));
+            futures::future::ready(result).boxed()
+        }
+    }
+
+    impl Acceptor for MemoryAcceptor {
+        fn accept(&self) -> BoxFuture<'_, Result<(BoxedStream, String), IoError>> {
+            async move {
+                let stream = self
+                    .rx
+                    .lock()
+                    .await
+                    .next()
+                    .await
+                    .ok_or_else(|| IoError::from(ErrorKind::ConnectionAborted))?;
+                let peer = self.peers.fetch_add(1, Ordering::SeqCst);
+                Ok((Box::new(stream) as BoxedStream, format!("memory:{}", peer)))
+            }
+            .boxed()
+        }
+    }
+
+    fn chat_message() -> Packet {
+        Packet::ChatMessage(ChatMessage {
+            msg_id: 0,
+            to_user: "server".to_string(),
+            from_user: "client".to_string(),
+            content: Bytes::from_static(b"ping"),
+        })
+    }
+
+    #[tokio::test]
+    async fn client_over_memory() {
+        let (transport, acceptor) = memory();
+        let server = Server::new();
+        let server_c = server.clone();
+        tokio::spawn(async move { server::run(&server_c, acceptor, Stats::new(), 8).await });
+
+        let addr: SocketAddr = "127.0.0.1:0".parse().unwrap();
+        let config = ClientConfig::default().with_transport(Arc::new(transport));
+        let (client, _incoming_rx) = test_client(addr, config);
+        client.clone().spawn().await.unwrap();
+        connected(&client).await;
+
+        match client.send_for_response(chat_message()).await.unwrap() {
+            Packet::ChatMessage(p) => assert!(p.msg_id > 0),
+            packet => panic!("unexpected packet {:?}", packet),
+        }
+        assert!(server.client_stats().await.contains_key("memory:0"));
+    }
+
+    #[tokio::test]
+    async fn connections_over_memory() {
+        let (transport, acceptor) = memory();
+        let server = Server::new();
+        tokio::spawn(async move { server::run(&server, acceptor, Stats::new(), 8).await });
+
+        let (incoming_tx, _incoming_rx) = mpsc::unbounded();
+        let connections =
+            Connections::<Packet>::new(incoming_tx).with_transport(Arc::new(transport));
+        connections
+            .add_client("relay".to_string(), "127.0.0.1:0".parse().unwrap())
+            .await
+            .unwrap();
+
+        let client = connections.clients.read().await["relay"].clone();
+        connected(&client).await;
+        assert_eq!(connections.stats().snapshot().connects, 1);
+    }
+}
@@ -0,0 +1,317 @@
+/*! Unix domain socket transport for same-host deployments
+
+`UnixTransport` connects a `Client` to a relay listening on a filesystem path
+given by `Endpoint::Path` and `UnixAcceptor` accepts connections for
+`server::run`, see
+`server::unix_run`. Frames use the same `Codec` as over TCP.
+
+A socket file left behind by a crashed server is removed when binding, while
+a socket with a live server behind it is an `AddrInUse` error. The file is
+removed again when the acceptor is dropped. Credentials of connecting
+processes are looked up so the acceptor can admit only given users.
+*/
+
+use std::fs::{self, DirBuilder, Permissions};
+use std::io::{Error as IoError, ErrorKind};
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use tokio::net::{UnixListener, UnixStream};
+use tracing::debug;
+
+use crate::endpoint::Peer;
+use crate::transport::{unsupported_peer, Acceptor, BoxedStream, Transport};
+
+/// Transport connecting to Unix socket paths.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct UnixTransport;
+
+impl Transport for UnixTransport {
+    fn connect(&self, peer: &Peer) -> BoxFuture<'static, Result<BoxedStream, IoError>> {
+        let path = peer.path().map(Path::to_path_buf);
+        let peer = peer.to_string();
+        async move {
+            let path = path.ok_or_else(|| unsupported_peer("Unix socket", &peer))?;
+            let stream = UnixStream::connect(path).await?;
+            Ok(Box::new(stream) as BoxedStream)
+        }
+        .boxed()
+    }
+}
+
+/// Acceptor of a Unix socket. Peers usually have no address so they are
+/// named by the order of connection together with their uid and pid.
+#[derive(Debug)]
+pub struct UnixAcceptor {
+    listener: UnixListener,
+    /// Socket file removed on drop. `None` if the listener was bound
+    /// elsewhere.
+    path: Option<PathBuf>,
+    /// Users allowed to connect. Everybody with access to the file is
+    /// allowed when `None`.
+    allowed_uids: Option<Vec<u32>>,
+    next_peer: AtomicU64,
+}
+
+impl UnixAcceptor {
+    /// Accept connections on the given listener. Its socket file is left as
+    /// is.
+    pub fn new(listener: UnixListener) -> UnixAcceptor {
+        UnixAcceptor {
+            listener,
+            path: None,
+            allowed_uids: None,
+            next_peer: AtomicU64::new(0),
+        }
+    }
+
+    /// Listen on the given path replacing a stale socket file.
+    pub fn bind<T: AsRef<Path>>(path: T) -> Result<UnixAcceptor, IoError> {
+        let path = path.as_ref();
+        remove_stale_socket(path)?;
+        let listener = UnixListener::bind(path)?;
+        Ok(UnixAcceptor::with_path(listener, path))
+    }
+
+    /// Listen on the given path with the given permissions of the socket
+    /// file, e.g. `0o660` to let only the owner and the group connect. The
+    /// socket is bound in a private directory next to `path` and moved in
+    /// place once its mode is set so nobody can connect in between.
+    pub fn bind_with_permissions<T: AsRef<Path>>(
+        path: T,
+        mode: u32,
+    ) -> Result<UnixAcceptor, IoError> {
+        let path = path.as_ref();
+        remove_stale_socket(path)?;
+        let name = path
+            .file_name()
+            .ok_or_else(|| IoError::new(ErrorKind::InvalidInput, "Socket path has no file name"))?;
+        let mut private = path.as_os_str().to_owned();
+        private.push(format!(".{}.tmp", std::process::id()));
+        let private = PathBuf::from(private);
+        DirBuilder::new().mode(0o700).create(&private)?;
+        let result = bind_private(&private.join(name), path, mode);
+        let _ = fs::remove_dir_all(&private);
+        Ok(UnixAcceptor::with_path(result?, path))
+    }
+
+    fn with_path(listener: UnixListener, path: &Path) -> UnixAcceptor {
+        let mut acceptor = UnixAcceptor::new(listener);
+        acceptor.path = Some(path.to_path_buf());
+        acceptor
+    }
+
+    /// Accept connections only from processes running as the given user.
+    /// Can be called several times to allow several users.
+    pub fn with_allowed_uid(mut self, uid: u32) -> UnixAcceptor {
+        self.allowed_uids.get_or_insert_with(Vec::new).push(uid);
+        self
+    }
+}
+
+/// Bind a socket at `private` inside a directory only the current user can
+/// enter, set its mode and move it to `path`.
+fn bind_private(private: &Path, path: &Path, mode: u32) -> Result<UnixListener, IoError> {
+    let listener = UnixListener::bind(private)?;
+    fs::set_permissions(private, Permissions::from_mode(mode))?;
+    fs::rename(private, path)?;
+    Ok(listener)
+}
+
+/// Remove the socket file at `path` unless a server is listening on it.
+fn remove_stale_socket(path: &Path) -> Result<(), IoError> {
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(ref e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    if !metadata.file_type().is_socket() {
+        return Err(IoError::new(
+            ErrorKind::AlreadyExists,
+            "Path is not a socket",
+        ));
+    }
+    match std::os::unix::net::UnixStream::connect(path) {
+        Ok(_) => Err(IoError::new(ErrorKind::AddrInUse, "Socket is in use")),
+        Err(ref e) if e.kind() == ErrorKind::ConnectionRefused => fs::remove_file(path),
+        Err(e) => Err(e),
+    }
+}
+
+impl Drop for UnixAcceptor {
+    fn drop(&mut self) {
+        if let Some(ref path) = self.path {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+impl Acceptor for UnixAcceptor {
+    fn accept(&self) -> BoxFuture<'_, Result<(BoxedStream, String), IoError>> {
+        async move {
+            loop {
+                let (stream, _) = self.listener.accept().await?;
+                let n = self.next_peer.fetch_add(1, Ordering::SeqCst);
+                let cred = match stream.peer_cred() {
+                    Ok(cred) => cred,
+                    Err(error) => {
+                        debug!(peer = n, %error, "Failed to get Unix peer credentials");
+                        continue;
+                    }
+                };
+                if let Some(ref allowed_uids) = self.allowed_uids {
+                    if !allowed_uids.contains(&cred.uid()) {
+                        debug!(peer = n, uid = cred.uid(), "Unix peer is not allowed");
+                        continue;
+                    }
+                }
+
+                let peer = match cred.pid() {
+                    Some(pid) => format!("unix:{} uid={} pid={}", n, cred.uid(), pid),
+                    None => format!("unix:{} uid={}", n, cred.uid()),
+                };
+                return Ok((Box::new(stream) as BoxedStream, peer));
+            }
+        }
+        .boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::os::unix::fs::MetadataExt;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use bytes::Bytes;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use crate::chatmsg::ChatMessage;
+    use crate::client::tests::{connected, test_client};
+    use crate::config::ClientConfig;
+    use crate::endpoint::Endpoint;
+    use crate::server::{self, Server};
+    use crate::stats::Stats;
+    use crate::transport::TcpTransport;
+    use crate::Packet;
+
+    fn socket_path(name: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("rust-network-{}-{}.sock", name, std::process::id()));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    #[tokio::test]
+    async fn connect_and_accept() {
+        let path = socket_path("accept");
+        let acceptor = UnixAcceptor::bind(&path).unwrap();
+        let mut client = UnixTransport
+            .connect(&Peer::Path(path.clone()))
+            .await
+            .unwrap();
+        let (mut server, peer) = acceptor.accept().await.unwrap();
+        let uid = fs::metadata(&path).unwrap().uid();
+        let expected = format!("unix:0 uid={} pid={}", uid, std::process::id());
+        assert_eq!(peer, expected);
+
+        client.write_all(b"ping").await.unwrap();
+        let mut buf = [0; 4];
+        server.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+
+        drop(acceptor);
+        assert!(!path.exists());
+    }
+
+    #[tokio::test]
+    async fn wrong_peer() {
+        let peer = Peer::Addr("127.0.0.1:33445".parse().unwrap());
+        let error = UnixTransport.connect(&peer).await.err().unwrap();
+        assert_eq!(error.kind(), ErrorKind::InvalidInput);
+        let path = socket_path("tcp");
+        let error = TcpTransport.connect(&Peer::Path(path)).await.err().unwrap();
+        assert_eq!(error.kind(), ErrorKind::InvalidInput);
+    }
+
+    #[tokio::test]
+    async fn stale_socket() {
+        let path = socket_path("stale");
+        let listener = UnixListener::bind(&path).unwrap();
+
+        let error = UnixAcceptor::bind(&path).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::AddrInUse);
+
+        // the file stays after the listener is gone as after a crash
+        drop(listener);
+        assert!(path.exists());
+        let acceptor = UnixAcceptor::bind(&path).unwrap();
+        UnixTransport
+            .connect(&Peer::Path(path.clone()))
+            .await
+            .unwrap();
+        acceptor.accept().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn not_a_socket() {
+        let path = socket_path("file");
+        fs::write(&path, b"data").unwrap();
+
+        let error = UnixAcceptor::bind(&path).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::AlreadyExists);
+        assert_eq!(fs::read(&path).unwrap(), b"data");
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn permissions_and_uids() {
+        let path = socket_path("uids");
+        let acceptor = UnixAcceptor::bind_with_permissions(&path, 0o600).unwrap();
+        let uid = fs::metadata(&path).unwrap().uid();
+        let acceptor = acceptor.with_allowed_uid(uid.wrapping_add(1));
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        let private = format!("{}.{}.tmp", path.display(), std::process::id());
+        assert!(!Path::new(&private).exists());
+
+        let mut rejected = UnixTransport
+            .connect(&Peer::Path(path.clone()))
+            .await
+            .unwrap();
+        let accepted = tokio::time::timeout(Duration::from_millis(100), acceptor.accept()).await;
+        assert!(accepted.is_err());
+        let mut buf = [0; 1];
+        assert_eq!(rejected.read(&mut buf).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn client_over_unix_socket() {
+        let path = socket_path("client");
+        let acceptor = UnixAcceptor::bind(&path).unwrap();
+        let server = Server::new();
+        let server_c = server.clone();
+        tokio::spawn(async move { server::run(&server_c, acceptor, Stats::new(), 8).await });
+
+        let config = ClientConfig::default().with_transport(Arc::new(UnixTransport));
+        let (client, _incoming_rx) = test_client(Endpoint::path(&path), config);
+        client.clone().spawn().await.unwrap();
+        connected(&client).await;
+        assert_eq!(client.endpoint().await, Some(Peer::Path(path.clone())));
+
+        let packet = Packet::ChatMessage(ChatMessage {
+            msg_id: 0,
+            to_user: "server".to_string(),
+            from_user: "client".to_string(),
+            content: Bytes::from_static(b"ping"),
+        });
+        client.send_for_response(packet).await.unwrap();
+        let peers = server.client_stats().await;
+        assert!(peers.keys().all(|peer| peer.starts_with("unix:0 uid=")));
+    }
+}
@@ -0,0 +1,682 @@
+/*! WebSocket transport for browsers and gateways
+
+`WebSocketTransport` connects a `Client` to a relay over WebSocket and
+`WebSocketAcceptor` upgrades connections accepted by another `Acceptor`, see
+`server::ws_run`. Bytes written by the `Codec` travel as binary messages,
+every message carries one or more whole frames with their length headers.
+Text messages are rejected.
+
+Both sides send a WebSocket ping every `ping_interval` and close the
+connection when nothing, including a pong, was received for `idle_timeout`,
+so a dead peer ends the connection like a TCP error does. The round-trip times
+of the client's pings are reported to `Client`, see
+`Transport::connect_with_rtt`.
+*/
+
+use std::io::{Error as IoError, ErrorKind};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use futures::channel::mpsc;
+use futures::future::BoxFuture;
+use futures::{ready, FutureExt, Sink, SinkExt, StreamExt};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::sync::Mutex;
+use tokio::task::JoinHandle;
+use tokio::time::{Instant, Interval, Sleep};
+use tokio_tungstenite::tungstenite::handshake::server::{
+    Callback, ErrorResponse, Request, Response,
+};
+use tokio_tungstenite::tungstenite::http::{header, HeaderValue, StatusCode};
+use tokio_tungstenite::tungstenite::{client::IntoClientRequest, Error as WsError, Message};
+use tokio_tungstenite::WebSocketStream;
+use tracing::{debug, warn};
+
+use crate::codec::MAX_FRAME_SIZE;
+use crate::endpoint::Peer;
+use crate::transport::{Acceptor, BoxedStream, RttSender, TcpTransport, Transport};
+
+/// Interval between WebSocket pings by default.
+pub const DEFAULT_PING_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Time without any message after which the connection is closed by default.
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Time given to the peer to complete the WebSocket handshake.
+const WS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Upgraded connections waiting for `WebSocketAcceptor::accept`.
+const ACCEPT_QUEUE_SIZE: usize = 16;
+
+/// Pause after a failed `accept`, e.g. when out of file descriptors.
+const ACCEPT_ERROR_DELAY: Duration = Duration::from_millis(100);
+
+/// WebSocket settings shared by the client and the server.
+#[derive(Debug, Clone)]
+pub struct WebSocketConfig {
+    /// Path of the handshake request
+    path: String,
+    /// `Origin` header sent by the client
+    origin: Option<String>,
+    /// Origins accepted by the server. Any origin is accepted when empty.
+    allowed_origins: Vec<String>,
+    /// Interval between pings, pings are not sent when `None`
+    ping_interval: Option<Duration>,
+    /// Time without messages after which the connection is closed
+    idle_timeout: Option<Duration>,
+}
+
+impl Default for WebSocketConfig {
+    fn default() -> Self {
+        WebSocketConfig::new("/")
+    }
+}
+
+impl WebSocketConfig {
+    /// Perform the handshake on the given path.
+    pub fn new<T: Into<String>>(path: T) -> WebSocketConfig {
+        WebSocketConfig {
+            path: path.into(),
+            origin: None,
+            allowed_origins: Vec::new(),
+            ping_interval: Some(DEFAULT_PING_INTERVAL),
+            idle_timeout: Some(DEFAULT_IDLE_TIMEOUT),
+        }
+    }
+
+    /// Send the given `Origin` header in the client handshake.
+    pub fn with_origin<T: Into<String>>(mut self, origin: T) -> WebSocketConfig {
+        self.origin = Some(origin.into());
+        self
+    }
+
+    /// Accept handshakes from the given origin. Once an origin is allowed
+    /// handshakes from other origins or without `Origin` are rejected.
+    pub fn with_allowed_origin<T: Into<String>>(mut self, origin: T) -> WebSocketConfig {
+        self.allowed_origins.push(origin.into());
+        self
+    }
+
+    /// Send pings every `ping_interval` and close the connection when nothing
+    /// was received for `idle_timeout`. `None` disables either of them.
+    pub fn with_keepalive(
+        mut self,
+        ping_interval: Option<Duration>,
+        idle_timeout: Option<Duration>,
+    ) -> WebSocketConfig {
+        self.ping_interval = ping_interval;
+        self.idle_timeout = idle_timeout;
+        self
+    }
+
+    /// Check the handshake request of a client. Returns the status of the
+    /// rejecting response.
+    fn check_request(&self, request: &Request) -> Result<(), StatusCode> {
+        if request.uri().path() != self.path {
+            return Err(StatusCode::NOT_FOUND);
+        }
+        if !self.allowed_origins.is_empty() {
+            let origin = request
+                .headers()
+                .get(header::ORIGIN)
+                .and_then(|origin| origin.to_str().ok());
+            if !origin.is_some_and(|origin| self.allowed_origins.iter().any(|o| o == origin)) {
+                return Err(StatusCode::FORBIDDEN);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Handshake callback applying `WebSocketConfig::check_request`.
+struct CheckRequest<'a>(&'a WebSocketConfig);
+
+impl Callback for CheckRequest<'_> {
+    fn on_request(self, request: &Request, response: Response) -> Result<Response, ErrorResponse> {
+        match self.0.check_request(request) {
+            Ok(()) => Ok(response),
+            Err(status) => {
+                let mut response = ErrorResponse::new(None);
+                *response.status_mut() = status;
+                Err(response)
+            }
+        }
+    }
+}
+
+fn ws_error(error: WsError) -> IoError {
+    match error {
+        WsError::Io(error) => error,
+        error => IoError::other(error),
+    }
+}
+
+/// Byte stream over a WebSocket connection.
+pub struct MessageStream<S> {
+    inner: WebSocketStream<S>,
+    /// Payload of the last binary message not read yet
+    read_buf: Vec<u8>,
+    read_pos: usize,
+    /// Bytes written since the last flush, sent as one message on flush
+    write_buf: Vec<u8>,
+    ping: Option<Interval>,
+    ping_pending: bool,
+    /// Time the oldest unanswered ping was sent
+    ping_sent: Option<Instant>,
+    rtt_tx: Option<RttSender>,
+    idle: Option<(Duration, Pin<Box<Sleep>>)>,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> MessageStream<S> {
+    fn new(
+        inner: WebSocketStream<S>,
+        config: &WebSocketConfig,
+        rtt_tx: Option<RttSender>,
+    ) -> MessageStream<S> {
+        let ping = config
+            .ping_interval
+            .map(|interval| tokio::time::interval_at(Instant::now() + interval, interval));
+        let idle = config
+            .idle_timeout
+            .map(|timeout| (timeout, Box::pin(tokio::time::sleep(timeout))));
+        MessageStream {
+            inner,
+            read_buf: Vec::new(),
+            read_pos: 0,
+            write_buf: Vec::new(),
+            ping,
+            ping_pending: false,
+            ping_sent: None,
+            rtt_tx,
+            idle,
+        }
+    }
+
+    /// Send due pings and fail if the peer was silent for too long.
+    fn poll_keepalive(&mut self, cx: &mut Context) -> Result<(), IoError> {
+        if let Some((_, ref mut sleep)) = self.idle {
+            if sleep.poll_unpin(cx).is_ready() {
+                return Err(IoError::new(
+                    ErrorKind::TimedOut,
+                    "WebSocket peer is silent",
+                ));
+            }
+        }
+        if let Some(ref mut ping) = self.ping {
+            while ping.poll_tick(cx).is_ready() {
+                self.ping_pending = true;
+            }
+        }
+        if self.ping_pending {
+            if let Poll::Ready(result) = self.inner.poll_ready_unpin(cx) {
+                result.map_err(ws_error)?;
+                self.inner
+                    .start_send_unpin(Message::Ping(Vec::new()))
+                    .map_err(ws_error)?;
+                self.ping_pending = false;
+                self.ping_sent.get_or_insert_with(Instant::now);
+            }
+        }
+        if let Poll::Ready(Err(error)) = self.inner.poll_flush_unpin(cx) {
+            return Err(ws_error(error));
+        }
+        Ok(())
+    }
+
+    fn poll_send(&mut self, cx: &mut Context) -> Poll<Result<(), IoError>> {
+        if !self.write_buf.is_empty() {
+            ready!(self.inner.poll_ready_unpin(cx)).map_err(ws_error)?;
+            let message = Message::Binary(std::mem::take(&mut self.write_buf));
+            self.inner.start_send_unpin(message).map_err(ws_error)?;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for MessageStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &mut ReadBuf,
+    ) -> Poll<Result<(), IoError>> {
+        let this = self.get_mut();
+        loop {
+            if this.read_pos < this.read_buf.len() {
+                let n = buf.remaining().min(this.read_buf.len() - this.read_pos);
+                buf.put_slice(&this.read_buf[this.read_pos..this.read_pos + n]);
+                this.read_pos += n;
+                return Poll::Ready(Ok(()));
+            }
+
+            this.poll_keepalive(cx)?;
+            let message = match ready!(this.inner.poll_next_unpin(cx)) {
+                Some(Ok(message)) => message,
+                Some(Err(WsError::ConnectionClosed)) | None => return Poll::Ready(Ok(())),
+                Some(Err(error)) => return Poll::Ready(Err(ws_error(error))),
+            };
+            if let Some((timeout, ref mut sleep)) = this.idle {
+                sleep.as_mut().reset(Instant::now() + timeout);
+            }
+            match message {
+                Message::Binary(data) => {
+                    this.read_buf = data;
+                    this.read_pos = 0;
+                }
+                Message::Text(_) => {
+                    return Poll::Ready(Err(IoError::new(
+                        ErrorKind::InvalidData,
+                        "Unexpected WebSocket text message",
+                    )))
+                }
+                Message::Close(_) => return Poll::Ready(Ok(())),
+                Message::Pong(_) => {
+                    if let (Some(sent), Some(ref rtt_tx)) = (this.ping_sent.take(), &this.rtt_tx) {
+                        let _ = rtt_tx.unbounded_send(sent.elapsed());
+                    }
+                }
+                Message::Ping(_) | Message::Frame(_) => {}
+            }
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for MessageStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &[u8],
+    ) -> Poll<Result<usize, IoError>> {
+        let this = self.get_mut();
+        if this.write_buf.len() >= MAX_FRAME_SIZE {
+            ready!(this.poll_send(cx))?;
+        }
+        this.write_buf.extend_from_slice(buf);
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), IoError>> {
+        let this = self.get_mut();
+        ready!(this.poll_send(cx))?;
+        this.inner.poll_flush_unpin(cx).map_err(ws_error)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), IoError>> {
+        let this = self.get_mut();
+        ready!(this.poll_send(cx))?;
+        match ready!(Pin::new(&mut this.inner).poll_close(cx)) {
+            Ok(()) | Err(WsError::ConnectionClosed) => Poll::Ready(Ok(())),
+            Err(error) => Poll::Ready(Err(ws_error(error))),
+        }
+    }
+}
+
+/// Transport connecting to relays over WebSocket. The handshake is performed
+/// over a stream opened by another transport, TCP by default.
+#[derive(Debug, Clone)]
+pub struct WebSocketTransport {
+    inner: Arc<dyn Transport>,
+    config: WebSocketConfig,
+}
+
+impl WebSocketTransport {
+    /// Connect over TCP with the given settings.
+    pub fn new(config: WebSocketConfig) -> WebSocketTransport {
+        WebSocketTransport {
+            inner: Arc::new(TcpTransport),
+            config,
+        }
+    }
+
+    /// Perform the handshake over streams opened by the given transport.
+    pub fn with_inner(mut self, inner: Arc<dyn Transport>) -> WebSocketTransport {
+        self.inner = inner;
+        self
+    }
+
+    /// Perform the handshake and report the round-trip times of pings to
+    /// `rtt_tx` if it's given.
+    fn open(
+        &self,
+        peer: &Peer,
+        rtt_tx: Option<RttSender>,
+    ) -> BoxFuture<'static, Result<BoxedStream, IoError>> {
+        let connecting = self.inner.connect(peer);
+        // Unix sockets have no authority of their own
+        let authority = match (peer.host(), peer.addr()) {
+            (Some(host), Some(addr)) => format!("{}:{}", host, addr.port()),
+            (None, Some(addr)) => addr.to_string(),
+            _ => "localhost".to_string(),
+        };
+        let config = self.config.clone();
+        async move {
+            let stream = connecting.await?;
+
+            let mut request = format!("ws://{}{}", authority, config.path)
+                .into_client_request()
+                .map_err(ws_error)?;
+            if let Some(ref origin) = config.origin {
+                let origin = HeaderValue::from_str(origin)
+                    .map_err(|e| IoError::new(ErrorKind::InvalidInput, e))?;
+                request.headers_mut().insert(header::ORIGIN, origin);
+            }
+            let handshake = tokio_tungstenite::client_async(request, stream);
+            let (stream, _) = tokio::time::timeout(WS_HANDSHAKE_TIMEOUT, handshake)
+                .await
+                .map_err(|e| IoError::new(ErrorKind::TimedOut, e))?
+                .map_err(ws_error)?;
+
+            Ok(Box::new(MessageStream::new(stream, &config, rtt_tx)) as BoxedStream)
+        }
+        .boxed()
+    }
+}
+
+impl Transport for WebSocketTransport {
+    fn connect(&self, peer: &Peer) -> BoxFuture<'static, Result<BoxedStream, IoError>> {
+        self.open(peer, None)
+    }
+
+    fn connect_with_rtt(
+        &self,
+        peer: &Peer,
+        rtt_tx: RttSender,
+    ) -> BoxFuture<'static, Result<BoxedStream, IoError>> {
+        self.open(peer, Some(rtt_tx))
+    }
+}
+
+/// Acceptor upgrading connections accepted by another acceptor to WebSocket.
+/// Handshakes run in background tasks so a slow client doesn't hold up the
+/// others. Connections failing the handshake are dropped, errors of the
+/// other acceptor are logged and it's asked again after a pause.
+pub struct WebSocketAcceptor {
+    upgraded: Mutex<mpsc::Receiver<(BoxedStream, String)>>,
+    task: JoinHandle<()>,
+}
+
+impl WebSocketAcceptor {
+    /// Upgrade connections accepted by `acceptor` with the given settings.
+    pub fn new<A: Acceptor>(acceptor: A, config: WebSocketConfig) -> WebSocketAcceptor {
+        let (tx, rx) = mpsc::channel(ACCEPT_QUEUE_SIZE);
+        let config = Arc::new(config);
+        let task = tokio::spawn(async move {
+            loop {
+                let (stream, peer) = match acceptor.accept().await {
+                    Ok(accepted) => accepted,
+                    Err(error) => {
+                        warn!(%error, "WebSocket acceptor accept error");
+                        tokio::time::sleep(ACCEPT_ERROR_DELAY).await;
+                        continue;
+                    }
+                };
+                let mut tx = tx.clone();
+                let config = config.clone();
+                tokio::spawn(async move {
+                    let handshake =
+                        tokio_tungstenite::accept_hdr_async(stream, CheckRequest(&config));
+                    match tokio::time::timeout(WS_HANDSHAKE_TIMEOUT, handshake).await {
+                        Ok(Ok(stream)) => {
+                            let stream = Box::new(MessageStream::new(stream, &config, None));
+                            let _ = tx.send((stream as BoxedStream, peer)).await;
+                        }
+                        Ok(Err(error)) => debug!(%peer, %error, "WebSocket handshake error"),
+                        Err(_) => debug!(%peer, "WebSocket handshake timed out"),
+                    }
+                });
+            }
+        });
+
+        WebSocketAcceptor {
+            upgraded: Mutex::new(rx),
+            task,
+        }
+    }
+}
+
+impl Drop for WebSocketAcceptor {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+impl Acceptor for WebSocketAcceptor {
+    fn accept(&self) -> BoxFuture<'_, Result<(BoxedStream, String), IoError>> {
+        async move {
+            match self.upgraded.lock().await.next().await {
+                Some(accepted) => Ok(accepted),
+                None => Err(IoError::from(ErrorKind::ConnectionAborted)),
+            }
+        }
+        .boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use bytes::Bytes;
+    use std::net::SocketAddr;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    use crate::chatmsg::ChatMessage;
+    use crate::client::tests::{connected, test_client};
+    use crate::config::ClientConfig;
+    use crate::handshake::{self, Capabilities};
+    use crate::server::{self, Server};
+    use crate::stats::Stats;
+    use crate::Packet;
+
+    async fn listen(config: WebSocketConfig) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let acceptor = WebSocketAcceptor::new(listener, config);
+        tokio::spawn(async move { server::run(&Server::new(), acceptor, Stats::new(), 8).await });
+        addr
+    }
+
+    /// Fails the first `accept` like a listener out of file descriptors.
+    struct FlakyAcceptor {
+        listener: TcpListener,
+        failed: AtomicBool,
+    }
+
+    impl Acceptor for FlakyAcceptor {
+        fn accept(&self) -> BoxFuture<'_, Result<(BoxedStream, String), IoError>> {
+            if !self.failed.swap(true, Ordering::SeqCst) {
+                let error = IoError::other("Too many open files");
+                return futures::future::ready(Err(error)).boxed();
+            }
+            Acceptor::accept(&self.listener)
+        }
+    }
+
+    #[tokio::test]
+    async fn acceptor_survives_accept_error() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let flaky = FlakyAcceptor {
+            listener,
+            failed: AtomicBool::new(false),
+        };
+        let acceptor = WebSocketAcceptor::new(flaky, WebSocketConfig::default());
+
+        let client = WebSocketTransport::new(WebSocketConfig::default()).connect(&addr.into());
+        let (client, accepted) = tokio::join!(client, acceptor.accept());
+        assert!(client.is_ok());
+        assert!(accepted.is_ok());
+    }
+
+    #[tokio::test]
+    async fn client_over_websocket() {
+        let addr = listen(WebSocketConfig::new("/relay")).await;
+
+        let transport = WebSocketTransport::new(WebSocketConfig::new("/relay"));
+        let config = ClientConfig::default().with_transport(Arc::new(transport));
+        let (client, _incoming_rx) = test_client(addr, config);
+        client.clone().spawn().await.unwrap();
+        connected(&client).await;
+
+        let packet = Packet::ChatMessage(ChatMessage {
+            msg_id: 0,
+            to_user: "server".to_string(),
+            from_user: "client".to_string(),
+            content: Bytes::from_static(b"ping"),
+        });
+        match client.send_for_response(packet).await.unwrap() {
+            Packet::ChatMessage(p) => assert!(p.msg_id > 0),
+            packet => panic!("unexpected packet {:?}", packet),
+        }
+    }
+
+    #[tokio::test]
+    async fn handshake_checks() {
+        let config = WebSocketConfig::new("/relay").with_allowed_origin("https://dashboard");
+        let addr = listen(config).await;
+
+        let peer = Peer::Addr(addr);
+        let connect = |config: WebSocketConfig| WebSocketTransport::new(config).connect(&peer);
+        assert!(
+            connect(WebSocketConfig::new("/relay").with_origin("https://dashboard"))
+                .await
+                .is_ok()
+        );
+        assert!(
+            connect(WebSocketConfig::new("/other").with_origin("https://dashboard"))
+                .await
+                .is_err()
+        );
+        assert!(
+            connect(WebSocketConfig::new("/relay").with_origin("https://evil"))
+                .await
+                .is_err()
+        );
+        assert!(connect(WebSocketConfig::new("/relay")).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn handshake_host() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let relay = async {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            while !request.ends_with(b"\r\n\r\n") {
+                let mut buf = [0; 1];
+                stream.read_exact(&mut buf).await.unwrap();
+                request.push(buf[0]);
+            }
+            String::from_utf8(request).unwrap().to_lowercase()
+        };
+
+        let peer = Peer::Host {
+            host: "relay.example.com".to_string(),
+            addr,
+        };
+        let transport = WebSocketTransport::new(WebSocketConfig::default());
+        let (_, request) = tokio::join!(transport.connect(&peer), relay);
+        let host = format!("host: relay.example.com:{}\r\n", addr.port());
+        assert!(request.contains(&host), "{}", request);
+    }
+
+    #[tokio::test]
+    async fn websocket_pongs_answer_heartbeat() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let ping_interval = Some(Duration::from_millis(10));
+        let keepalive = WebSocketConfig::default().with_keepalive(ping_interval, None);
+        let acceptor = WebSocketAcceptor::new(listener, keepalive.clone());
+        // the relay answers WebSocket pings but never protocol pings
+        let relay = async {
+            let (mut stream, _) = acceptor.accept().await.unwrap();
+            let capabilities = Capabilities {
+                supported: 0,
+                required: 0,
+                max_frame_size: MAX_FRAME_SIZE,
+            };
+            handshake::server_hello(&mut stream, capabilities)
+                .await
+                .unwrap();
+            let mut buf = [0; 64];
+            while stream.read(&mut buf).await.unwrap_or(0) > 0 {}
+            futures::future::pending::<()>().await
+        };
+
+        let config = ClientConfig::default()
+            .with_transport(Arc::new(WebSocketTransport::new(keepalive)))
+            .with_heartbeat(Some(Duration::from_millis(20)), 2);
+        let (client, _incoming_rx) = test_client(addr, config);
+        client.clone().spawn().await.unwrap();
+        // WebSocket pongs answer the heartbeat for many missed protocol pings
+        let alive = async {
+            connected(&client).await;
+            tokio::time::sleep(Duration::from_millis(200)).await;
+        };
+        futures::select! {
+            () = alive.fuse() => {}
+            _ = relay.fuse() => unreachable!(),
+        }
+
+        assert!(client.is_connected().await);
+        assert!(client.rtt().await.is_some());
+        assert!(client.stats().snapshot().rtt.count() > 0);
+    }
+
+    async fn pair(
+        client_config: WebSocketConfig,
+        server_config: WebSocketConfig,
+    ) -> (BoxedStream, BoxedStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let acceptor = WebSocketAcceptor::new(listener, server_config);
+        let client = WebSocketTransport::new(client_config)
+            .connect(&addr.into())
+            .await
+            .unwrap();
+        let (server, _) = acceptor.accept().await.unwrap();
+        (client, server)
+    }
+
+    #[tokio::test]
+    async fn bytes_as_messages() {
+        let config = WebSocketConfig::default();
+        let (mut client, mut server) = pair(config.clone(), config).await;
+
+        client.write_all(b"\x00\x00\x00\x02hi").await.unwrap();
+        client.flush().await.unwrap();
+        let mut buf = [0; 6];
+        server.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"\x00\x00\x00\x02hi");
+
+        client.shutdown().await.unwrap();
+        assert_eq!(server.read(&mut buf).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn keepalive() {
+        let ping_interval = Duration::from_millis(20);
+        let idle_timeout = Duration::from_millis(100);
+        let pinging = WebSocketConfig::default().with_keepalive(Some(ping_interval), None);
+        let silent = WebSocketConfig::default().with_keepalive(None, None);
+        let strict = WebSocketConfig::default().with_keepalive(None, Some(idle_timeout));
+        let mut buf = [0; 1];
+
+        // pings of the client keep the connection alive while it's read
+        let (mut client, mut server) = pair(pinging, strict.clone()).await;
+        let client_reader = tokio::spawn(async move {
+            let mut buf = [0; 1];
+            client.read(&mut buf).await
+        });
+        let read = tokio::time::timeout(idle_timeout * 3, server.read(&mut buf)).await;
+        assert!(read.is_err());
+        client_reader.abort();
+
+        let (_client, mut server) = pair(silent, strict).await;
+        let error = server.read(&mut buf).await.unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::TimedOut);
+    }
+}